@@ -1,13 +1,25 @@
 use anyhow::{anyhow, Context, Result};
-use log::info;
+use log::{info, warn};
 
-use std::{collections::HashMap, fs, path::Path};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt, fs,
+    io::{BufRead, Write},
+    ops::RangeInclusive,
+    path::Path,
+    str::FromStr,
+};
 
-use parser::parse;
+use parser::{filter_guard, parse_groups, parse_line_entry, parse_numbered_line, FilterGroups};
+pub use parser::{parse, parse_first_error, parse_partial, parse_report, ParseError};
 
 mod parser;
 
+/// config.txtの1行に対応する設定
+/// 対応する設定の種類を増やせるように、crateの外でmatchする場合は`_`の分岐が必要
 #[derive(Debug, PartialEq, Clone)]
+#[non_exhaustive]
 pub enum ConfigEntry {
     Comment(String),
     Command(Config),
@@ -15,6 +27,26 @@ pub enum ConfigEntry {
     DTparam(DTparam),
     ConditionFilter(String),
     GpuMem(GpuMem),
+    /// hdmi_timingsの空白区切りの数値
+    HdmiTimings(Vec<u32>),
+    /// hdmi_cvtのカスタム解像度
+    HdmiCvt(HdmiCvt),
+    /// 無効にされた設定
+    /// u-bootには変換されず、config.txtにはコメントアウトした行として出力する
+    Disabled(Box<ConfigEntry>),
+}
+
+/// hdmi_cvt=<width> <height> <framerate> [<aspect> <margins> <interlace> <rb>]
+/// 省略できる値は後ろから省略されるので、書かれていたものだけを保持する
+#[derive(Debug, PartialEq, Clone)]
+pub struct HdmiCvt {
+    width: u32,
+    height: u32,
+    framerate: u32,
+    aspect: Option<u32>,
+    margins: Option<u32>,
+    interlace: Option<u32>,
+    reduced_blanking: Option<u32>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -41,674 +73,7003 @@ pub struct DTparam {
     configs: Vec<Config>,
 }
 
+#[derive(Debug, Clone)]
 pub struct RPiConfig {
-    configs: HashMap<String, Vec<ConfigEntry>>,
+    configs: HashMap<String, Vec<Entry>>,
+    /// config.txtに[all]が明示的に書かれていたか
+    /// 書かれていない場合、allの設定はfilterの無い先頭部分として出力する
+    explicit_all: bool,
 }
 
-impl DTparam {
-    /// TODO: U-Bootのconfigを現在は;で結合しているが、||や&&でも結合できるよう、戻り値をVec<String>から適切なものに変更する
-    fn generate_uboot_config(&self) -> Result<Vec<String>> {
-        let mut commands = Vec::new();
-
-        fn dtparam_error(key: &str, value: &str) -> Result<String> {
-            Err(anyhow!("Unsupported dtparam option: {}={}", key, value))
-        }
+/// config.txtの1つの設定と、その設定が書かれていたconfig.txtの行番号
+/// 行番号は行単位で読み込んだ場合のみ記録される
+#[derive(Debug, Clone)]
+pub(crate) struct Entry {
+    config: ConfigEntry,
+    line: Option<usize>,
+    /// 設定が書かれた順番
+    /// 行番号と違い、filterごとにまとめた設定やmergeした設定でも記録され、後のファイルの設定ほど大きくなる
+    order: usize,
+}
 
-        for (key, value) in self
-            .configs
-            .iter()
-            .map(|Config { key, value }| (key.as_ref(), value.as_ref()))
-        {
-            let fdt_command: String = match key {
-                "act_led_trigger" => match value {
-                    "default-on" => {
-                        Ok("fdt set /leds/act linux,default-trigger default-on".to_string())
-                    }
-                    _ => dtparam_error(&key, &value),
-                },
-                "audio" => match value {
-                    "on" => Ok("fdt set /soc/audio status okay".to_string()),
-                    _ => dtparam_error(&key, &value),
-                },
-                "i2c_arm" => match value {
-                    "on" => Ok("fdt set i2c_arm status okay".to_string()),
-                    _ => dtparam_error(&key, &value),
-                },
-                "i2s" => match value {
-                    "on" => Ok("fdt set i2s status okay".to_string()),
-                    _ => dtparam_error(&key, &value),
-                },
-                "pwr_led_activelow" => match value {
-                    // https://patchwork.ozlabs.org/project/uboot/patch/1496149544-32348-1-git-send-email-hannes.schmelzer@br-automation.com/
-                    "off" => Ok("fdt set /leds/pwr gpios < ? ? 0x00 >".to_string()),
-                    "on" => Ok("fdt set /leds/pwr gpios < ? ? 0x01 >".to_string()),
-                    _ => dtparam_error(&key, &value),
-                },
-                "pwr_led_trigger" => match value {
-                    "none" => Ok("fdt set /leds/pwr linux,default-trigger none".to_string()),
-                    _ => dtparam_error(&key, &value),
-                },
-                "spi" => match value {
-                    "on" => Ok("fdt set spi0 status okay".to_string()),
-                    _ => dtparam_error(&key, &value),
-                },
-                "watchdog" => match value {
-                    "on" => Ok("fdt set watchdog status okay".to_string()),
-                    _ => dtparam_error(&key, &value),
-                },
-                "i2c_arm_baudrate" => {
-                    let baudrate: u32 = value
-                        .parse()
-                        .map_err(|err| anyhow!("Invalid i2c clock-frequency: {}", err))?;
-                    Ok(format!("fdt set i2c clock-frequency < {:#x} >", baudrate))
-                }
-                _ => Err(anyhow!("Unsupported dtparam key: {}", key)),
-            }?;
-            commands.push(fdt_command);
+impl Entry {
+    pub(crate) fn new(config: ConfigEntry, line: Option<usize>, order: usize) -> Self {
+        Entry {
+            config,
+            line,
+            order,
         }
-
-        Ok(commands)
     }
 }
 
-impl DTOverlay {
-    fn generate_uboot_config(&self) -> Result<Vec<String>> {
-        let overlay = &self.overlay;
-        let configs = &self.configs;
-        let mut commands: Vec<String> = Vec::new();
+/// 行番号と順番は比較せず、設定の内容のみを比較する
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.config == other.config
+    }
+}
 
-        // TODO: 5.x系に上げる際に読み替えるコードを追加する
-        // 例: pi3-disable-bt.dtbo -> disable-bt.dtbo
+/// board_nameでは判定できず、u-bootの環境変数で判定するfilter
+#[derive(Debug, PartialEq, Clone)]
+enum FilterGuard {
+    /// [0x12345678]
+    Serial(u64),
+    /// [board-type=0x11]
+    BoardType(u32),
+}
 
-        // i2sなど特殊対応のものに対応する
-        if overlay == "i2smaster" {
-            commands.push("fdt set i2s status okay".to_string());
-            return Ok(commands);
+impl FilterGuard {
+    /// u-bootのifで使う条件
+    /// 環境変数はu-bootのboard/raspberrypi/rpi/rpi.cで設定される書式に合わせる
+    fn condition(&self) -> String {
+        match self {
+            FilterGuard::Serial(serial) => format!("test \"${{serial#}}\" = \"{:016x}\"", serial),
+            // board_revはrevisionのtypeを%dで書いたもの
+            FilterGuard::BoardType(board_type) => {
+                format!("test \"${{board_rev}}\" = \"{}\"", board_type)
+            }
         }
+    }
+}
 
-        // TODO: ロード元のアドレスを編集できるようにする
-        commands.push(format!("load ${{devtype}} ${{devnum}}:${{devpart}} ${{fdt_ovaddr}} ${{fdtdir}}/overlays/{}.dtbo", overlay));
-        commands.push("fdt apply ${fdt_ovaddr}".to_string());
+/// u-boot向けのconfigを出力する際のオプション
+#[derive(Debug, Clone)]
+pub struct UbootOptions {
+    /// 変換元のconfig.txtの設定を、生成したコマンドの前にechoする
+    pub annotate: bool,
+    /// gpu_mem_*をモデルごとに振り分ける際に使う、u-bootのモデル名と搭載メモリ量(MB)の対応
+    pub model_ramsize: HashMap<String, usize>,
+    /// overlayの読み込みに失敗した場合にfdt applyを行わず、エラーを表示する
+    pub check_errors: bool,
+    /// 最後に/systemノードを作り、linux,revisionを設定する
+    pub emit_revision_node: bool,
+    /// `setenv KEY VALUE` でu-bootの環境変数として引き継ぐconfig.txtのkey
+    /// 既定ではbootcode_delayのみ
+    /// kernelやcmdlineはu-boot自身を起動するために書かれていることが多く、
+    /// 引き継ぐとu-bootの環境変数を意図せず書き換えてしまうので、必要な場合のみ追加する
+    pub forward_env: HashSet<String>,
+    /// gpu_memのメモリの値を0x%08xの固定幅で出力する
+    pub fixed_width_hex: bool,
+    /// fdt resizeで確保する大きさの最小値
+    /// 生成したコマンドから見積もった大きさの方が大きい場合はそちらを使う
+    pub fdt_resize_floor: usize,
+    /// gpu_memが指定されていないモデルに設定するgpu_mem(MB)
+    /// model_ramsizeに搭載メモリ量が書かれているモデルのみが対象になる
+    pub default_gpu_mem: Option<usize>,
+    /// fdtのコマンドでノードを指定する方法
+    pub node_path_style: NodePathStyle,
+    /// fdt mknodeの前にノードの有無を調べ、既にある場合は作らないようにする
+    /// 起動のリトライなどでコマンドが2回実行されても失敗しないようにするため
+    pub idempotent: bool,
+    /// uEnv.txtの出力の最後にCRLFを付ける
+    /// 指定しない場合は、これまでどおり改行を付けない
+    /// Windowsで編集されるboot partitionに置く場合に使う
+    /// u-bootで読み込む際は、`env import -t -r`で行末の\rを取り除く必要がある
+    /// fw_setenvのスクリプトや.envには使わない
+    pub crlf: bool,
+    /// fdt resizeの後、変換したコマンドの前にそのまま追加するコマンド
+    /// ;や改行を含むコマンドはエラーになる
+    pub prologue: Vec<String>,
+    /// 変換したコマンドの最後にそのまま追加するコマンド
+    /// ;や改行を含むコマンドはエラーになる
+    pub epilogue: Vec<String>,
+    /// dtparamのkeyと、変換後のコマンドのテンプレートの対応
+    /// 組み込みの変換より優先され、テンプレート中の`{value}`はdtparamの値に置き換えられる
+    pub dtparam_translations: HashMap<String, Vec<String>>,
+    /// pi3-disable-btのような古いoverlayの名前を、現在のfirmwareの名前に読み替えてloadする
+    /// 5.x以降のkernelではpi3-*.dtboが同梱されていないため
+    pub rename_legacy_overlays: bool,
+}
 
-        if !configs.is_empty() {
-            // TODO: パラメータを修正するコードを入れる
-            for c in configs {
-                let command = match &**overlay {
-                    "dwc2" => format!("fdt set usb {} {}", c.key, c.value),
-                    _ => unimplemented!("not supported overlay"),
-                };
-                commands.push(command);
-            }
+impl UbootOptions {
+    /// uEnv.txtの行末
+    /// crlfでない場合は改行を付けないので、空文字列になる
+    pub fn line_ending(&self) -> &'static str {
+        match self.crlf {
+            true => "\r\n",
+            false => "",
         }
-        Ok(commands)
     }
 }
 
-impl GpuMem {
-    fn generate_uboot_config(&self) -> Result<Vec<String>> {
-        // TODO: total_ramsizeが0の場合（gpu_mem=*）に対応する
-        let total_ramsize = self
-            .total_ramsize
-            .ok_or(anyhow!("Unsupported total_ramsize"))?
-            * 1024
-            * 1024;
-        let gpu_ramsize = self.gpu_ramsize * 1024 * 1024;
-        let cpu_ramsize = total_ramsize
-            .checked_sub(gpu_ramsize)
-            .ok_or(anyhow!("gpu_ramsize must be smaller than total_ramsize"))?;
+/// fdtのコマンドでノードを指定する方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NodePathStyle {
+    /// i2c_armのような/aliasesのエイリアスを使う
+    #[default]
+    Alias,
+    /// /soc/i2c@7e804000のようなフルパスを使う
+    /// SoCによってパスが異なるノードはエイリアスのままにする
+    FullPath,
+}
 
-        match &self.model {
-            Some(model) => match model.as_ref() {
-                "4 Model B" | "400" | "Compute Module 4" => Ok(vec![
-                    format!(
-                        "fdt set / memreserve < {:#x} {:#x} >",
-                        cpu_ramsize, gpu_ramsize,
-                    ),
-                    format!(
-                        "fdt set /memory@0 reg < 0x00 0x00 {:#x} 0x00 0x40000000 0xbc000000 >",
-                        cpu_ramsize
-                    ),
-                ]),
-                "3 Model B" | "3 Model B+" | "3 Model A+" | "Compute Module 3"
-                | "Compute Module 3+" => Ok(vec![
-                    format!(
-                        "fdt set / memreserve < {:#x} {:#x} >",
-                        cpu_ramsize, gpu_ramsize,
-                    ),
-                    format!("fdt set /memory@0 reg < 0x00 {:#x} >", cpu_ramsize,),
-                ]),
-                // "Zero" | "Zero W" => todo!(),
-                _ => Err(anyhow!(
-                    "Unsupported platform: {:?}, command: gpu_mem",
-                    model
-                )),
-            },
-            None => Err(anyhow!("gpu_mem.model is None")),
+/// u-bootのモデル名と搭載メモリ量(MB)の既定の対応
+fn default_model_ramsize() -> HashMap<String, usize> {
+    // https://www.raspberrypi.com/documentation/computers/raspberry-pi.html#old-style-revision-codes
+    let model_ramsize = [
+        (PiModel::Zero, 512),
+        (PiModel::ZeroW, 512),
+        (PiModel::Zero2W, 512),
+        (PiModel::Pi3APlus, 512),
+        (PiModel::Pi3B, 1024),
+        (PiModel::Pi3BPlus, 1024),
+        (PiModel::CM3, 1024),
+        (PiModel::CM3Plus, 1024),
+        (PiModel::Pi4B, 1024),
+        (PiModel::Pi400, 1024),
+        (PiModel::CM4, 1024),
+    ];
+    model_ramsize
+        .iter()
+        .map(|(model, ramsize)| (model.as_board_name().to_string(), *ramsize))
+        .collect()
+}
+
+impl Default for UbootOptions {
+    fn default() -> Self {
+        UbootOptions {
+            annotate: false,
+            model_ramsize: default_model_ramsize(),
+            check_errors: false,
+            emit_revision_node: true,
+            forward_env: HashSet::from(["bootcode_delay".to_string()]),
+            fixed_width_hex: false,
+            fdt_resize_floor: 0x2000,
+            default_gpu_mem: None,
+            node_path_style: NodePathStyle::Alias,
+            idempotent: false,
+            crlf: false,
+            prologue: Vec::new(),
+            epilogue: Vec::new(),
+            dtparam_translations: HashMap::new(),
+            rename_legacy_overlays: false,
         }
     }
 }
 
-/// config.txtを読み込んで作ったconfigをuboot向けにより細分化された状態にする関数
-/// 例: confitional filterのpi3はpi3 AとB両方を指すので、両方に設定が入るように分類する
-fn arrange_for_uboot(
-    piconfigs: &HashMap<String, Vec<ConfigEntry>>,
-) -> HashMap<String, Vec<ConfigEntry>> {
-    let mut ubootconfigs: HashMap<String, Vec<ConfigEntry>> = HashMap::new();
+/// dtparamの変換表をTOMLから読み込む
+///
+/// ```toml
+/// [dtparam]
+/// my_led = "fdt set /leds/my status {value}"
+/// spi = ["fdt set spi0 status okay", "fdt set spi1 status okay"]
+/// ```
+#[cfg(feature = "toml")]
+pub fn parse_dtparam_translations(toml: &str) -> Result<HashMap<String, Vec<String>>> {
+    let table: toml::Table =
+        toml::from_str(toml).context("Failed to parse dtparam translations")?;
+    let dtparam = match table.get("dtparam") {
+        Some(toml::Value::Table(dtparam)) => dtparam,
+        Some(_) => return Err(anyhow!("dtparam must be a table")),
+        None => return Ok(HashMap::new()),
+    };
+    dtparam
+        .iter()
+        .map(|(key, value)| {
+            let templates = match value {
+                toml::Value::String(template) => vec![template.clone()],
+                toml::Value::Array(templates) => templates
+                    .iter()
+                    .map(|template| match template {
+                        toml::Value::String(template) => Ok(template.clone()),
+                        _ => Err(anyhow!(
+                            "Invalid dtparam translation for {}: {}",
+                            key,
+                            template
+                        )),
+                    })
+                    .collect::<Result<_>>()?,
+                _ => {
+                    return Err(anyhow!(
+                        "Invalid dtparam translation for {}: {}",
+                        key,
+                        value
+                    ))
+                }
+            };
+            Ok((key.clone(), templates))
+        })
+        .collect()
+}
 
-    for (platform, configs) in piconfigs {
-        // raspi bootloaderの荒い分類をu-bootのもう少し細かい分類に分け直す
-        // raspi model: https://www.raspberrypi.com/documentation/computers/config_txt.html#model-filters
-        // uboot model: https://github.com/u-boot/u-boot/blob/master/board/raspberrypi/rpi/rpi.c#L89
-        match &**platform {
-            "all" => {
-                ubootconfigs.insert("all".to_string(), configs.clone());
-            }
-            "pi3" => {
-                ubootconfigs.insert("3 Model B".to_string(), configs.clone());
-                ubootconfigs.insert("3 Model B+".to_string(), configs.clone());
-                ubootconfigs.insert("3 Model A+".to_string(), configs.clone());
-                ubootconfigs.insert("Compute Module 3".to_string(), configs.clone());
-                ubootconfigs.insert("Compute Module 3+".to_string(), configs.clone());
-            }
-            "pi3+" => {
-                ubootconfigs.insert("3 Model B+".to_string(), configs.clone());
-                ubootconfigs.insert("3 Model A+".to_string(), configs.clone());
-            }
-            "pi4" => {
-                ubootconfigs.insert("4 Model B".to_string(), configs.clone());
-                ubootconfigs.insert("400".to_string(), configs.clone());
-                ubootconfigs.insert("Compute Module 4".to_string(), configs.clone());
-            }
-            "pi0" => {
-                ubootconfigs.insert("Zero".to_string(), configs.clone());
-                ubootconfigs.insert("Zero W".to_string(), configs.clone());
-                ubootconfigs.insert("Zero 2 W".to_string(), configs.clone());
-            }
-            "pi0w" => {
-                ubootconfigs.insert("Zero W".to_string(), configs.clone());
-                ubootconfigs.insert("Zero 2 W".to_string(), configs.clone());
-            }
-            _ => {
-                // TODO: 必要ならErrを出す？
-                info!("Unsupported platform: {}", platform);
-            }
+/// config.txtを検証する際のオプション
+#[derive(Debug, Clone)]
+pub struct ValidationOptions {
+    /// firmwareが解釈しないkeyと、変換できないdtparamのkeyをエラーにする
+    /// firmwareは解釈するがu-bootの設定に変換されないkeyは警告にする
+    pub strict_unknown_keys: bool,
+    /// 対象とするfirmwareのリリース日(YYYYMMDD)
+    /// firmware_levelsでこれより新しいfirmwareで追加されたとされるkeyを警告にする
+    pub firmware_level: Option<u32>,
+    /// config.txtのkeyと、そのkeyに対応したfirmwareのリリース日(YYYYMMDD)の対応
+    /// 既定の対応はまだ空なので、firmware_levelで警告させるには対応を指定する必要がある
+    pub firmware_levels: HashMap<String, u32>,
+    /// UbootOptions::dtparam_translationsと同じく、変換表で変換するdtparamのkey
+    /// ここにあるkeyはstrict_unknown_keysでもエラーにしない
+    pub dtparam_translations: HashMap<String, Vec<String>>,
+    /// UbootOptions::model_ramsizeと同じく、u-bootのモデル名と搭載メモリ量(MB)の対応
+    /// 変換と同じ値を指定しないと、gpu_memの振り分けや搭載メモリ量の検証が変換と食い違う
+    pub model_ramsize: HashMap<String, usize>,
+}
+
+/// 変換と同じmodel_ramsizeとdtparam_translationsで検証する
+impl From<&UbootOptions> for ValidationOptions {
+    fn from(options: &UbootOptions) -> Self {
+        ValidationOptions {
+            dtparam_translations: options.dtparam_translations.clone(),
+            model_ramsize: options.model_ramsize.clone(),
+            ..Default::default()
         }
     }
+}
 
-    // all以下にgpu_mem_*の設定があったら適切なmodel宛に再分類する
-    // u-bootでメモリ量に応じた条件分岐ができ無さそうなので、代わりにモデルで分類するため
-    // TODO: all以外に対応する
-    for all_config in piconfigs.get("all").unwrap_or(&Vec::new()) {
-        match all_config {
-            ConfigEntry::GpuMem(gpumem) => {
-                gpumem.total_ramsize.map(|total_memsize| {
-                    match total_memsize {
-                        // https://www.raspberrypi.com/documentation/computers/raspberry-pi.html#old-style-revision-codes
-                        256 => {
-                            // unsupported
-                            ()
-                        }
-                        512 => {
-                            let platforms = ["Zero", "Zero W", "3 Model A+"];
-                            for platform in platforms {
-                                ubootconfigs.get_mut(platform).map(|x| {
-                                    x.push(ConfigEntry::GpuMem(GpuMem {
-                                        total_ramsize: Some(total_memsize),
-                                        gpu_ramsize: gpumem.gpu_ramsize,
-                                        model: Some(platform.to_string()),
-                                    }))
-                                });
-                            }
-                        }
-                        1024 => {
-                            let platforms = [
-                                "3 Model B",
-                                "3 Model B+",
-                                "Compute Module 3",
-                                "Compute Module 3+",
-                                "4 Model B",
-                                "400",
-                                "Compute Module 4",
-                            ];
-                            for platform in platforms {
-                                let entry = ConfigEntry::GpuMem(GpuMem {
-                                    total_ramsize: Some(total_memsize),
-                                    gpu_ramsize: gpumem.gpu_ramsize,
-                                    model: Some(platform.to_string()),
-                                });
-                                match ubootconfigs.get_mut(platform) {
-                                    Some(x) => x.push(entry),
-                                    None => {
-                                        ubootconfigs.insert(platform.to_string(), vec![entry]);
-                                    }
-                                }
-                            }
-                        }
-                        _ => (),
-                    }
-                });
-                // allからは設定を削除する
-                ubootconfigs
-                    .get_mut("all")
-                    .map(|x| x.retain(|y| y != all_config));
-            }
-            _ => (),
+impl Default for ValidationOptions {
+    fn default() -> Self {
+        ValidationOptions {
+            strict_unknown_keys: false,
+            firmware_level: None,
+            firmware_levels: default_firmware_levels(),
+            dtparam_translations: HashMap::new(),
+            model_ramsize: default_model_ramsize(),
         }
     }
+}
 
-    ubootconfigs
+/// config.txtを正規化する際のオプション
+#[derive(Debug, Default, Clone)]
+pub struct NormalizeOptions {
+    /// dtoverlayのパラメータをkeyの順に並べ替える
+    /// 並べ替えると意味が変わるdtoverlayは並べ替えない
+    pub sort_overlay_params: bool,
 }
 
-impl RPiConfig {
-    pub fn new() -> Self {
-        RPiConfig {
-            configs: HashMap::new(),
+/// config.txtの検証結果
+/// 変換はできるが、そのまま使うと危険な設定などを警告として列挙する
+/// 同時に使えないoverlayなど、壊れたdevice treeになる設定はエラーとして列挙する
+/// 検証項目の種類を増やせるように、crateの外では構造体リテラルで作れないようにしている
+#[derive(Debug, Default, PartialEq, Clone)]
+#[non_exhaustive]
+pub struct ValidationReport {
+    pub warnings: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+/// 設定の種類ごとの数
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct EntryCounts {
+    pub overlays: usize,
+    pub dtparams: usize,
+    pub gpu_mem: usize,
+    /// hdmi_timingsなど、dtoverlay、dtparam、gpu_mem以外の設定
+    pub commands: usize,
+    pub comments: usize,
+    pub disabled: usize,
+}
+
+impl EntryCounts {
+    fn add(&mut self, entry: &ConfigEntry) {
+        match entry {
+            ConfigEntry::DTOverlay(_) => self.overlays += 1,
+            ConfigEntry::DTparam(_) => self.dtparams += 1,
+            ConfigEntry::GpuMem(_) => self.gpu_mem += 1,
+            ConfigEntry::Command(_) | ConfigEntry::HdmiTimings(_) | ConfigEntry::HdmiCvt(_) => {
+                self.commands += 1
+            }
+            ConfigEntry::Comment(_) => self.comments += 1,
+            ConfigEntry::Disabled(_) => self.disabled += 1,
+            // filterの行はfilterごとにまとめる際に取り除かれる
+            ConfigEntry::ConditionFilter(_) => {}
         }
     }
 
-    /// /boot/config.txt から RasPiの設定を読み込む
-    pub fn load_from_config(src: &Path) -> Result<Self> {
-        let config = fs::read_to_string(src)
-            .with_context(|| format!("Failed to read config.txt from {}", src.display()))?;
-        // TODO: restに余りがあったらエラーにする
-        let (_, configs) = parse(&config)
-            .map_err(|err| anyhow::anyhow!("Failed to parse config.txt: {:?}", err))?;
-        Ok(Self { configs })
+    fn merge(&mut self, other: &EntryCounts) {
+        self.overlays += other.overlays;
+        self.dtparams += other.dtparams;
+        self.gpu_mem += other.gpu_mem;
+        self.commands += other.commands;
+        self.comments += other.comments;
+        self.disabled += other.disabled;
     }
+}
 
-    /// configsの中身を読んで u-boot 向けのconfigを出力する
-    pub fn convert_to_uboot_config(&self, envval_name: &str) -> Result<Option<String>> {
-        if self.configs.is_empty() {
-            return Ok(None);
-        }
+/// RPiConfigの設定の種類ごとの数をfilterごとに集計したもの
+/// 設定が1つも無いfilterは含まない
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct ConfigSummary {
+    pub filters: BTreeMap<String, EntryCounts>,
+}
 
-        let configs = arrange_for_uboot(&self.configs);
+impl ConfigSummary {
+    /// 全てのfilterの合計
+    pub fn total(&self) -> EntryCounts {
+        let mut total = EntryCounts::default();
+        for counts in self.filters.values() {
+            total.merge(counts);
+        }
+        total
+    }
+}
 
-        let mut commands: Vec<String> = Vec::new();
+/// e.g. 3 overlays, 5 dtparams, 2 gpu_mem settings across 2 sections.
+impl fmt::Display for ConfigSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn plural(count: usize, singular: &str) -> String {
+            match count {
+                1 => format!("{} {}", count, singular),
+                _ => format!("{} {}s", count, singular),
+            }
+        }
+        let total = self.total();
+        write!(
+            f,
+            "{}, {}, {} across {}.",
+            plural(total.overlays, "overlay"),
+            plural(total.dtparams, "dtparam"),
+            plural(total.gpu_mem, "gpu_mem setting"),
+            plural(self.filters.len(), "section")
+        )
+    }
+}
 
-        // 項目追加時に必要なので、fdtのアドレスを伸長する
-        commands.push("setexpr fdt_ovaddr ${fdt_addr} + 0x40000".to_string());
-        commands.push("fdt addr ${fdt_addr}".to_string());
-        commands.push("fdt resize 0x2000".to_string());
-        // dtoverlay or dtparamの設定を抜き出す
-        // 全ボード向けのdtoverlay or dtparam を設定する
-        // 順番が大切な部分もあるので、必ずallが最初に来るようにすること
-        let supported_platforms = [
-            "all",
-            "Zero",
-            "Zero W",
-            "3 Model A+",
-            "3 Model B",
-            "3 Model B+",
-            "Compute Module 3",
-            "Compute Module 3+",
-            "4 Model B",
-            "400",
-            "Compute Module 4",
-        ];
-        for platform in supported_platforms {
-            let platform_configs = match configs.get(platform) {
-                None => continue,
-                Some(x) => x,
-            };
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // フラグ形式のパラメータは値を持たない
+        if self.value.is_empty() {
+            write!(f, "{}", self.key)
+        } else {
+            write!(f, "{}={}", self.key, self.value)
+        }
+    }
+}
 
-            let mut tmp_commands: Vec<String> = Vec::new();
+fn join_configs(configs: &[Config]) -> String {
+    configs
+        .iter()
+        .map(|c| c.to_string())
+        .collect::<Vec<String>>()
+        .join(",")
+}
 
-            for config in platform_configs {
-                // U-Bootで設定が必要な部分を取り出して変換する
-                match config {
-                    ConfigEntry::DTOverlay(x) => {
-                        tmp_commands.append(&mut x.generate_uboot_config()?)
-                    }
-                    ConfigEntry::DTparam(x) => tmp_commands.append(&mut x.generate_uboot_config()?),
-                    ConfigEntry::GpuMem(x) => tmp_commands.append(&mut x.generate_uboot_config()?),
-                    _ => (),
+/// config.txtでの表記で出力する
+impl fmt::Display for ConfigEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigEntry::Comment(comment) => write!(f, "#{}", comment),
+            ConfigEntry::Disabled(entry) => write!(f, "#{}", entry),
+            ConfigEntry::Command(config) => write!(f, "{}", config),
+            ConfigEntry::DTOverlay(DTOverlay { overlay, configs }) => {
+                if configs.is_empty() {
+                    write!(f, "dtoverlay={}", overlay)
+                } else {
+                    write!(f, "dtoverlay={},{}", overlay, join_configs(configs))
                 }
             }
-            if !tmp_commands.is_empty() {
-                if platform == "all" {
-                    commands.append(&mut tmp_commands);
-                } else {
-                    commands.push(format!("if test \"${{board_name}}\" = \"{}\"", platform));
-                    commands.push("then".to_string());
-                    commands.append(&mut tmp_commands);
-                    commands.push("fi".to_string());
+            ConfigEntry::DTparam(DTparam { configs }) => {
+                write!(f, "dtparam={}", join_configs(configs))
+            }
+            ConfigEntry::ConditionFilter(filter) => write!(f, "[{}]", filter),
+            ConfigEntry::GpuMem(GpuMem {
+                total_ramsize,
+                gpu_ramsize,
+                ..
+            }) => match total_ramsize {
+                Some(total_ramsize) => write!(f, "gpu_mem_{}={}", total_ramsize, gpu_ramsize),
+                None => write!(f, "gpu_mem={}", gpu_ramsize),
+            },
+            ConfigEntry::HdmiTimings(timings) => write!(
+                f,
+                "hdmi_timings={}",
+                timings
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
+            ConfigEntry::HdmiCvt(cvt) => {
+                write!(f, "hdmi_cvt={} {} {}", cvt.width, cvt.height, cvt.framerate)?;
+                let optional = [cvt.aspect, cvt.margins, cvt.interlace, cvt.reduced_blanking];
+                for value in optional.iter().map_while(|x| *x) {
+                    write!(f, " {}", value)?;
                 }
+                Ok(())
             }
         }
-        // TODO: VC memoryの設定を行う
-        // シリアル番号の設定を行う
-        commands.push("fdt mknode / system".to_string());
-        commands.push("fdt set /system linux,revision < ${board_revision} >".to_string());
+    }
+}
 
-        Ok(match commands.is_empty() {
-            true => None,
-            false => Some(format!("{}={}", envval_name, commands.join(";"))),
-        })
+/// config.txtの1行を1つの設定として読み込む
+/// filterは以降の行に対する指定なので、単独の設定としてはエラーにする
+pub fn parse_line(line: &str) -> Result<ConfigEntry> {
+    let (rest, entry) =
+        parse_line_entry(line).map_err(|err| anyhow!("Failed to parse {:?}: {:?}", line, err))?;
+    if !rest.trim().is_empty() {
+        return Err(anyhow!("Failed to parse {:?}: unexpected {:?}", line, rest));
+    }
+    match entry {
+        Some(ConfigEntry::ConditionFilter(filter)) => Err(anyhow!(
+            "[{}] is a condition filter, not a single config entry",
+            filter
+        )),
+        Some(entry) => Ok(entry),
+        None => Err(anyhow!("Empty line has no config entry")),
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl FromStr for ConfigEntry {
+    type Err = anyhow::Error;
 
-    #[test]
-    fn test_dtoverlay_uboot() {
-        let expected: Vec<(DTOverlay, Vec<String>)> = vec![
-            (
-                DTOverlay {
-                    overlay: "pi3-disable-bt".to_string(),
-                    configs: vec![],
-                },
-                vec!["load ${devtype} ${devnum}:${devpart} ${fdt_ovaddr} ${fdtdir}/overlays/pi3-disable-bt.dtbo",
-                "fdt apply ${fdt_ovaddr}",
-                ].iter_mut().map(|x| x.to_string()).collect(),
-            ),
-            (
-                DTOverlay {
-                    overlay: "pi3-disable-wifi".to_string(),
-                    configs: vec![],
-                },
-                vec!["load ${devtype} ${devnum}:${devpart} ${fdt_ovaddr} ${fdtdir}/overlays/pi3-disable-wifi.dtbo",
-                "fdt apply ${fdt_ovaddr}",
-                ].iter_mut().map(|x| x.to_string()).collect(),
-            ),
-            (
-                DTOverlay {
-                    overlay: "disable-bt".to_string(),
-                    configs: vec![],
-                },
-                vec!["load ${devtype} ${devnum}:${devpart} ${fdt_ovaddr} ${fdtdir}/overlays/disable-bt.dtbo",
-                "fdt apply ${fdt_ovaddr}",
-                ].iter_mut().map(|x| x.to_string()).collect(),
-            ),
-            (
-                DTOverlay {
-                    overlay: "dwc2".to_string(),
-                    configs: vec![Config {
-                        key: "dr_mode".to_string(),
-                        value: "host".to_string(),
-                    }],
-                },
-                vec!["load ${devtype} ${devnum}:${devpart} ${fdt_ovaddr} ${fdtdir}/overlays/dwc2.dtbo",
-                "fdt apply ${fdt_ovaddr}",
-                "fdt set usb dr_mode host",
-                ].iter_mut().map(|x| x.to_string()).collect(),
-            ),
-            (
-                DTOverlay {
-                    overlay: "i2smaster".to_string(),
-                    configs: vec![],
-                },
-                vec!["fdt set i2s status okay"].iter_mut().map(|x| x.to_string()).collect(),
-            ),
-            (
-                DTOverlay {
-                    overlay: "vc4-fkms-v3d".to_string(),
-                    configs: vec![],
-                },
-                vec!["load ${devtype} ${devnum}:${devpart} ${fdt_ovaddr} ${fdtdir}/overlays/vc4-fkms-v3d.dtbo",
-                "fdt apply ${fdt_ovaddr}",
-                ].iter_mut().map(|x| x.to_string()).collect(),
-            ),
-        ];
+    fn from_str(s: &str) -> Result<Self> {
+        parse_line(s)
+    }
+}
 
-        for tmp in expected {
-            let dtbo = tmp.0;
-            let expected = tmp.1;
+/// destと同じディレクトリの一時ファイルに書き込んでからrenameする
+/// 書き込み中に電源が落ちても、destは古い内容か新しい内容のどちらかになる
+pub fn write_atomically(dest: &Path, contents: &[u8]) -> Result<()> {
+    let file_name = dest
+        .file_name()
+        .ok_or(anyhow!("Invalid destination: {}", dest.display()))?;
+    let tmp = dest.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
 
-            let result = dtbo.generate_uboot_config().unwrap();
-            assert_eq!(expected, result);
+    let result = write_and_rename(&tmp, dest, contents);
+    if result.is_err() {
+        // 失敗した場合は一時ファイルを残さない
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+fn write_and_rename(tmp: &Path, dest: &Path, contents: &[u8]) -> Result<()> {
+    let mut file =
+        fs::File::create(tmp).with_context(|| format!("Failed to create {}", tmp.display()))?;
+    file.write_all(contents)
+        .and_then(|_| file.sync_all())
+        .with_context(|| format!("Failed to write {}", tmp.display()))?;
+    drop(file);
+
+    // 一時ファイルはdestと同じディレクトリに作るので、renameが別のファイルシステムをまたぐことは無い
+    fs::rename(tmp, dest).with_context(|| format!("Failed to replace {}", dest.display()))?;
+
+    // renameしたことをディレクトリに書き込まないと、電源が落ちた時に古い内容に戻ることがある
+    let dir = match dest.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("Failed to sync {}", dir.display()))
+}
+
+/// u-bootがboard_nameとして設定するRaspberry Piのモデル
+/// uboot model: https://github.com/u-boot/u-boot/blob/master/board/raspberrypi/rpi/rpi.c#L89
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PiModel {
+    Zero,
+    ZeroW,
+    Zero2W,
+    Pi3APlus,
+    Pi3B,
+    Pi3BPlus,
+    CM3,
+    CM3Plus,
+    Pi4B,
+    Pi400,
+    CM4,
+    Pi5B,
+    Pi500,
+    CM5,
+}
+
+impl PiModel {
+    /// 全モデル
+    /// u-bootの設定はallの後にこの順で出力されるので、並び順を変えないこと
+    pub const ALL: [PiModel; 14] = [
+        PiModel::Zero,
+        PiModel::ZeroW,
+        PiModel::Zero2W,
+        PiModel::Pi3APlus,
+        PiModel::Pi3B,
+        PiModel::Pi3BPlus,
+        PiModel::CM3,
+        PiModel::CM3Plus,
+        PiModel::Pi4B,
+        PiModel::Pi400,
+        PiModel::CM4,
+        PiModel::Pi5B,
+        PiModel::Pi500,
+        PiModel::CM5,
+    ];
+
+    /// u-bootのboard_nameを返す
+    pub fn as_board_name(&self) -> &'static str {
+        match self {
+            PiModel::Zero => "Zero",
+            PiModel::ZeroW => "Zero W",
+            PiModel::Zero2W => "Zero 2 W",
+            PiModel::Pi3APlus => "3 Model A+",
+            PiModel::Pi3B => "3 Model B",
+            PiModel::Pi3BPlus => "3 Model B+",
+            PiModel::CM3 => "Compute Module 3",
+            PiModel::CM3Plus => "Compute Module 3+",
+            PiModel::Pi4B => "4 Model B",
+            PiModel::Pi400 => "400",
+            PiModel::CM4 => "Compute Module 4",
+            PiModel::Pi5B => "5 Model B",
+            PiModel::Pi500 => "500",
+            PiModel::CM5 => "Compute Module 5",
         }
     }
 
-    #[test]
-    fn test_dtparam_uboot() {
-        let expected: Vec<(DTparam, Vec<String>)> = vec![
-            (
-                DTparam {
-                    configs: vec![Config {
-                        key: "act_led_trigger".to_string(),
-                        value: "default-on".to_string(),
-                    }],
-                },
-                vec!["fdt set /leds/act linux,default-trigger default-on"]
-                    .iter_mut()
-                    .map(|x| x.to_string())
-                    .collect(),
-            ),
-            (
-                DTparam {
-                    configs: vec![Config {
-                        key: "audio".to_string(),
-                        value: "on".to_string(),
-                    }],
-                },
-                vec!["fdt set /soc/audio status okay"]
-                    .iter_mut()
-                    .map(|x| x.to_string())
-                    .collect(),
-            ),
-            (
-                DTparam {
-                    configs: vec![Config {
-                        key: "i2c_arm".to_string(),
-                        value: "on".to_string(),
-                    }],
-                },
-                vec!["fdt set i2c_arm status okay"]
-                    .iter_mut()
-                    .map(|x| x.to_string())
-                    .collect(),
-            ),
-            (
-                DTparam {
-                    configs: vec![Config {
-                        key: "i2s".to_string(),
-                        value: "on".to_string(),
-                    }],
-                },
-                vec!["fdt set i2s status okay"]
-                    .iter_mut()
-                    .map(|x| x.to_string())
-                    .collect(),
+    /// u-bootのboard_nameからモデルを求める
+    pub fn from_board_name(name: &str) -> Option<Self> {
+        PiModel::ALL
+            .into_iter()
+            .find(|model| model.as_board_name() == name)
+    }
+}
+
+/// PiModel::ALLの順に並べた、u-bootの設定を出力する対象のモデルのboard_name
+fn board_names() -> [&'static str; 14] {
+    PiModel::ALL.map(|model| model.as_board_name())
+}
+
+/// Pi 5系のモデルに振り分けられたdtoverlayが変換できるか調べる
+/// TODO: Pi 5は周辺機器がRP1にあり、ノードの配置が異なるので、対応するまではノードを直接書き換えるものをエラーにする
+/// overlayを読み込むだけのものは、firmwareと同じくoverlay側がノードを選ぶのでそのまま変換する
+fn check_pi5_overlays(configs: &HashMap<String, Vec<Entry>>) -> Result<()> {
+    for model in [PiModel::Pi5B, PiModel::Pi500, PiModel::CM5] {
+        let model = model.as_board_name();
+        for Entry { config, line, .. } in configs.get(model).into_iter().flatten() {
+            match config {
+                ConfigEntry::DTOverlay(overlay) if overlay.edits_nodes() => {
+                    let err = anyhow!(
+                        "dtoverlay is not yet supported on this model: {:?} (from {})",
+                        model,
+                        config
+                    );
+                    return Err(match line {
+                        Some(line) => anyhow!("line {}: {}", line, err),
+                        None => err,
+                    });
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// filterを並べる順番
+/// allを先頭にして、残りは名前順にする
+fn filter_order(filter: &str) -> (bool, &str) {
+    (filter != "all", filter)
+}
+
+/// board_nameがmodelsのいずれかであるかの条件
+fn board_name_condition(models: &[PiModel]) -> String {
+    models
+        .iter()
+        .map(|model| format!("test \"${{board_name}}\" = \"{}\"", model.as_board_name()))
+        .collect::<Vec<_>>()
+        .join(" || ")
+}
+
+/// u-bootのモデルに振り分けられないCompute Moduleのfilterと、その理由
+/// 黙って捨てると設定が反映されないことに気付けないので、変換時にエラーにする
+const UNSUPPORTED_CM_FILTERS: [(&str, &str); 2] = [
+    ("cm1", "pibootcfg does not support BCM2835 boards"),
+    // CM4SはCM3と同じ形のCM4だが、u-bootのrpi.cにはboard_nameが無いので区別できない
+    ("cm4s", "u-boot has no board_name for CM4S"),
+];
+
+/// filterがu-bootで扱えないCompute Module向けであれば、その理由を返す
+fn unsupported_cm_filter(filter: &str) -> Option<&'static str> {
+    UNSUPPORTED_CM_FILTERS
+        .iter()
+        .find(|(cm, _)| *cm == filter)
+        .map(|(_, reason)| *reason)
+}
+
+/// i2c_arm_baudrateとして受け付ける値の範囲(Hz)
+const I2C_BAUDRATE_RANGE: RangeInclusive<u32> = 1_000..=1_000_000;
+/// dtoverlay=gpioで指定できるGPIOの番号の範囲(BCM2711は58本)
+const GPIO_RANGE: RangeInclusive<u32> = 0..=57;
+const GPIO_ACTIVE_LOW: u32 = 0x1;
+const GPIO_PULL_UP: u32 = 0x10;
+const GPIO_PULL_DOWN: u32 = 0x20;
+/// sd_overclockとして受け付ける値の範囲(MHz)
+/// 50MHzを要求された際に代わりに使うクロックなので、50MHzより下げることはできない
+const SD_OVERCLOCK_RANGE: RangeInclusive<u32> = 50..=100;
+/// rtc_bbat_vchgとして受け付ける充電電圧の範囲(μV)
+/// 0の場合はバックアップ電池を充電しない
+const RTC_BBAT_VCHG_RANGE: RangeInclusive<u32> = 0..=4_400_000;
+/// Pi 5のRTCのノード
+const RPI_RTC_NODE: &str = "/soc@107c000000/rpi_rtc";
+/// poe_fan_temp*として受け付ける温度の範囲(ミリ℃)
+const POE_FAN_TEMP_RANGE: RangeInclusive<u32> = 0..=100_000;
+/// poe_fan_temp*_hystとして受け付けるヒステリシスの範囲(ミリ℃)
+const POE_FAN_HYST_RANGE: RangeInclusive<u32> = 0..=20_000;
+
+/// config.txtのkeyと、そのkeyに対応したfirmwareのリリース日(YYYYMMDD)の既定の対応
+/// 日付はkeyを追加したfirmwareのcommitかリリースから取り、行ごとにその出典をコメントで書く
+/// 出典を確認できていないものは見積もりで埋めずに載せない
+/// https://github.com/raspberrypi/firmware/commits/master
+/// TODO: camera_auto_detect、hdmi_enable_4kp60、arm_boostなどを、追加したcommitを確認して載せる
+const FIRMWARE_LEVELS: [(&str, u32); 0] = [];
+
+/// config.txtのkeyと対応したfirmwareのリリース日の既定の対応
+fn default_firmware_levels() -> HashMap<String, u32> {
+    FIRMWARE_LEVELS
+        .iter()
+        .map(|(key, level)| (key.to_string(), *level))
+        .collect()
+}
+
+/// パラメータの順番で意味が変わるdtoverlay
+/// cma-*やaudio/noaudioのように異なるパラメータが同じpropertyを書き換え、後に書かれた方が優先される
+const ORDER_SENSITIVE_OVERLAYS: [&str; 3] = ["vc4-fkms-v3d", "vc4-kms-v3d", "vc4-kms-v3d-pi4"];
+
+/// 生成するコマンドで使うエイリアスと、BCM2835からBCM2711まで共通のフルパス
+/// mmc0はSoCによってsdhostとemmc2のどちらを指すか異なるので含めない
+const NODE_ALIAS_PATHS: [(&str, &str); 9] = [
+    ("bluetooth", "/soc/serial@7e201000/bluetooth"),
+    ("csi1", "/soc/csi@7e801000"),
+    ("gpio", "/soc/gpio@7e200000"),
+    ("i2c", "/soc/i2c@7e804000"),
+    ("i2c_arm", "/soc/i2c@7e804000"),
+    ("i2s", "/soc/i2s@7e203000"),
+    ("spi0", "/soc/spi@7e204000"),
+    ("usb", "/soc/usb@7e980000"),
+    ("watchdog", "/soc/watchdog@7e100000"),
+];
+
+/// fdtのコマンドのノードの指定を、styleに合わせて書き換える
+/// gpio/gpio17-hogのようにエイリアスから始まるパスも書き換える
+fn apply_node_path_style(command: String, style: NodePathStyle) -> String {
+    if style == NodePathStyle::Alias {
+        return command;
+    }
+    let mut words = command.splitn(4, ' ');
+    let (fdt, subcommand, node) = match (words.next(), words.next(), words.next()) {
+        (Some(fdt @ "fdt"), Some(subcommand @ ("set" | "mknode" | "rm")), Some(node)) => {
+            (fdt, subcommand, node)
+        }
+        _ => return command,
+    };
+    let (alias, rest) = match node.split_once('/') {
+        Some((alias, rest)) => (alias, Some(rest)),
+        None => (node, None),
+    };
+    let path = match NODE_ALIAS_PATHS.iter().find(|(x, _)| *x == alias) {
+        Some((_, path)) => path,
+        None => return command,
+    };
+    let mut resolved = format!("{} {} {}", fdt, subcommand, path);
+    if let Some(rest) = rest {
+        resolved.push('/');
+        resolved.push_str(rest);
+    }
+    if let Some(args) = words.next() {
+        resolved.push(' ');
+        resolved.push_str(args);
+    }
+    resolved
+}
+
+/// fdt mknodeを、ノードが無い場合だけ実行するように書き換える
+/// fdt get sizeはノードが無いと失敗するので、存在の確認に使う
+fn guard_mknode(command: String, idempotent: bool) -> String {
+    if !idempotent {
+        return command;
+    }
+    let (parent, name) = match command
+        .strip_prefix("fdt mknode ")
+        .and_then(|args| args.split_once(' '))
+    {
+        Some(x) => x,
+        None => return command,
+    };
+    let node = match parent.ends_with('/') {
+        true => format!("{}{}", parent, name),
+        false => format!("{}/{}", parent, name),
+    };
+    format!("fdt get size fdt_node_size {} || {}", node, command)
+}
+
+/// 0xから始まる16進数、もしくは10進数のアドレスを読み込む
+fn parse_address(name: &str, value: &str) -> Result<u64> {
+    let parsed = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|err| anyhow!("Invalid {}: {:?} ({})", name, value, err))
+}
+
+/// Pi 4系のu-bootのモデル名か
+fn is_pi4(platform: &str) -> bool {
+    matches!(
+        PiModel::from_board_name(platform),
+        Some(PiModel::Pi4B | PiModel::Pi400 | PiModel::CM4)
+    )
+}
+
+impl Config {
+    /// plain commandのうち、u-bootの環境変数などに対応するものを変換する
+    fn generate_uboot_config(&self, options: &UbootOptions) -> Result<Vec<String>> {
+        if options.forward_env.contains(&self.key) {
+            // ;はu-bootのコマンドの区切りになってしまう
+            if self.value.contains(';') {
+                return Err(anyhow!(
+                    "Cannot forward {} to u-boot: value contains ';': {:?}",
+                    self.key,
+                    self.value
+                ));
+            }
+            return Ok(vec![format!("setenv {} {}", self.key, self.value)]);
+        }
+        match self.key.as_ref() {
+            "boot_delay" => {
+                let delay: u32 = self
+                    .value
+                    .parse()
+                    .map_err(|err| anyhow!("Invalid boot_delay: {:?} ({})", self.value, err))?;
+                Ok(vec![format!("setenv bootdelay {}", delay)])
+            }
+            // Pi 4はcomposite出力がデフォルトで無効になっている
+            // 400はcomposite出力を持たないので、4 Model BとCompute Module 4のみ有効にする
+            "enable_tvout" => match self.value.as_ref() {
+                "1" => Ok(vec![
+                    format!(
+                        "if {}",
+                        board_name_condition(&[PiModel::Pi4B, PiModel::CM4])
+                    ),
+                    "then".to_string(),
+                    "fdt set /soc/vec@7ec13000 status okay".to_string(),
+                    "fi".to_string(),
+                ]),
+                "0" => Ok(Vec::new()),
+                _ => Err(anyhow!("Invalid enable_tvout: {:?}", self.value)),
+            },
+            // firmwareはカメラを検出してセンサーのoverlayを読み込むが、u-bootでは検出できない
+            // CSIだけ有効にするので、センサーのoverlayはdtoverlayで指定する
+            "camera_auto_detect" => match self.value.as_ref() {
+                "1" => Ok(vec!["fdt set csi1 status okay".to_string()]),
+                "0" => Ok(Vec::new()),
+                _ => Err(anyhow!("Invalid camera_auto_detect: {:?}", self.value)),
+            },
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// plain commandがplatform向けのu-bootの設定に変換されない場合、その理由を返す
+    fn skip_reason(&self, platform: &str) -> Option<String> {
+        match self.key.as_ref() {
+            // convert_to_uboot_configで編集対象のfdtのアドレスとして使う
+            "device_tree_address" => None,
+            "boot_delay" => None,
+            "camera_auto_detect" => None,
+            "enable_tvout" => None,
+            key if key.starts_with("sdtv_") => Some(format!(
+                "{} configures the firmware composite video encoder and has no device-tree equivalent",
+                key
+            )),
+            "start_x" => Some(
+                "start_x only selects the legacy camera firmware (start_x.elf) and has no device-tree equivalent"
+                    .to_string(),
             ),
-            (
-                DTparam {
-                    configs: vec![Config {
-                        key: "pwr_led_activelow".to_string(),
-                        value: "off".to_string(),
-                    }],
-                },
-                vec!["fdt set /leds/pwr gpios < ? ? 0x00 >"]
-                    .iter_mut()
-                    .map(|x| x.to_string())
-                    .collect(),
+            "disable_splash" => Some(
+                "disable_splash only affects the firmware rainbow splash screen".to_string(),
             ),
-            (
-                DTparam {
-                    configs: vec![Config {
-                        key: "pwr_led_activelow".to_string(),
-                        value: "on".to_string(),
-                    }],
-                },
-                vec!["fdt set /leds/pwr gpios < ? ? 0x01 >"]
-                    .iter_mut()
-                    .map(|x| x.to_string())
-                    .collect(),
+            "device_tree" => Some(format!(
+                "device_tree={} selects the base DTB loaded by the firmware; u-boot edits the DTB it was given",
+                self.value
+            )),
+            "max_framebuffers" => Some(
+                "max_framebuffers is handled by the firmware framebuffer driver and has no device-tree equivalent"
+                    .to_string(),
             ),
-            (
-                DTparam {
-                    configs: vec![Config {
-                        key: "pwr_led_trigger".to_string(),
-                        value: "none".to_string(),
-                    }],
-                },
-                vec!["fdt set /leds/pwr linux,default-trigger none"]
-                    .iter_mut()
-                    .map(|x| x.to_string())
-                    .collect(),
+            // USBポートに流せる電流の上限はfirmwareが電源の能力から決めるので、device treeでは変えられない
+            "usb_max_current_enable" | "max_usb_current" => Some(format!(
+                "{} raises the USB current limit in the firmware and has no device-tree equivalent",
+                self.key
+            )),
+            // firmwareがu-bootを起動する前にピンに直接設定するもので、pinctrlのノードには現れない
+            "gpio" => Some(
+                "gpio is applied to the pins by the firmware before u-boot starts and has no device-tree equivalent"
+                    .to_string(),
             ),
-            (
-                DTparam {
-                    configs: vec![Config {
-                        key: "spi".to_string(),
-                        value: "on".to_string(),
-                    }],
-                },
-                vec!["fdt set spi0 status okay"]
-                    .iter_mut()
-                    .map(|x| x.to_string())
-                    .collect(),
+            // firmwareがdtoverlayやdtparamを適用する様子をログに出すだけなので、u-bootのfdtコマンドには効かない
+            "dtdebug" => Some(
+                "dtdebug only enables the firmware device-tree debug log (see vcdbg log msg); it does not apply to u-boot fdt commands"
+                    .to_string(),
             ),
-            (
-                DTparam {
-                    configs: vec![Config {
-                        key: "watchdog".to_string(),
-                        value: "on".to_string(),
-                    }],
-                },
-                vec!["fdt set watchdog status okay"]
-                    .iter_mut()
-                    .map(|x| x.to_string())
-                    .collect(),
+            // firmwareが確保するframebufferの大きさで、kernelはvideo=で解像度を指定する
+            "framebuffer_width" | "framebuffer_height" => Some(format!(
+                "{} sets the firmware framebuffer geometry and has no device-tree equivalent; use video= in cmdline.txt instead",
+                self.key
+            )),
+            // firmwareがARMのコアを起動する前に割り込みコントローラを切り替えるもので、
+            // Pi 4のdevice treeはGICを使う前提で書かれているので、u-bootから変えることはできない
+            "enable_gic" if platform != "all" && !is_pi4(platform) => Some(format!(
+                "enable_gic only applies to Pi 4 models, not {}",
+                platform
+            )),
+            "enable_gic" => Some(
+                "enable_gic selects the interrupt controller before the firmware starts the ARM cores and has no device-tree equivalent"
+                    .to_string(),
             ),
-            (
-                DTparam {
-                    configs: vec![Config {
-                        key: "i2c_arm_baudrate".to_string(),
-                        value: "400000".to_string(),
-                    }],
-                },
-                vec!["fdt set i2c clock-frequency < 0x61a80 >"]
-                    .iter_mut()
-                    .map(|x| x.to_string())
-                    .collect(),
+            "hdmi_enable_4kp60" if platform != "all" && !is_pi4(platform) => Some(format!(
+                "hdmi_enable_4kp60 only applies to Pi 4 models, not {}",
+                platform
+            )),
+            "hdmi_enable_4kp60" => Some(
+                "hdmi_enable_4kp60 raises the core clock in the firmware and has no device-tree equivalent"
+                    .to_string(),
             ),
-        ];
+            // アナログ出力のPWMはfirmwareが動かしていて、bcm2835のaudioドライバはこれらのpropertyを読まない
+            "audio_pwm_mode" | "disable_audio_dither" => Some(format!(
+                "{} configures the firmware analogue audio output and has no device-tree equivalent",
+                self.key
+            )),
+            _ => Some(format!("{} has no u-boot translation", self.key)),
+        }
+    }
+}
+
+/// filter内のplain commandの値を取り出す
+/// 同じkeyが複数ある場合は後に書かれたものを優先する
+fn last_command<'a>(configs: &'a [Entry], key: &str) -> Option<&'a str> {
+    configs.iter().rev().find_map(|entry| match &entry.config {
+        ConfigEntry::Command(c) if c.key == key => Some(c.value.as_ref()),
+        _ => None,
+    })
+}
+
+/// firmwareが解釈するconfig.txtのplain commandのkey
+/// hdmi_*とsdtv_*は種類が多いので、KNOWN_KEY_PREFIXESで判定する
+/// https://www.raspberrypi.com/documentation/computers/config_txt.html
+const KNOWN_COMMAND_KEYS: [&str; 128] = [
+    "arm_64bit",
+    "arm_boost",
+    "arm_control",
+    "arm_freq",
+    "arm_freq_min",
+    "arm_peri_high",
+    "audio_pwm_mode",
+    "auto_initramfs",
+    "avoid_pwm_pll",
+    "avoid_warnings",
+    "boot_delay",
+    "boot_delay_ms",
+    "boot_load_flags",
+    "boot_ramdisk",
+    "bootcode_delay",
+    "camera_auto_detect",
+    "cec_osd_name",
+    "cmdline",
+    "config_hdmi_boost",
+    "core_freq",
+    "core_freq_min",
+    "decode_MPG2",
+    "decode_WVC1",
+    "device_tree",
+    "device_tree_address",
+    "device_tree_end",
+    "disable_audio_dither",
+    "disable_camera_led",
+    "disable_commandline_tags",
+    "disable_fw_kms_setup",
+    "disable_l2cache",
+    "disable_overscan",
+    "disable_poe_fan",
+    "disable_pvt",
+    "disable_splash",
+    "disable_touchscreen",
+    "display_auto_detect",
+    "display_default_lcd",
+    "display_hdmi_rotate",
+    "display_lcd_rotate",
+    "display_rotate",
+    "dispmanx_offline",
+    "dpi_group",
+    "dpi_mode",
+    "dpi_output_format",
+    "dpi_timings",
+    "dtdebug",
+    "eeprom_write_protect",
+    "enable_audio_dither",
+    "enable_dpi_lcd",
+    "enable_gic",
+    "enable_jtag_gpio",
+    "enable_tvout",
+    "enable_uart",
+    "fixup_file",
+    "force_eeprom_read",
+    "force_pwm_open",
+    "force_turbo",
+    "framebuffer_aspect",
+    "framebuffer_depth",
+    "framebuffer_height",
+    "framebuffer_ignore_alpha",
+    "framebuffer_priority",
+    "framebuffer_width",
+    "gpio",
+    "gpu_freq",
+    "gpu_freq_min",
+    "gpu_mem_1024",
+    "gpu_mem_256",
+    "gpu_mem_512",
+    "h264_freq",
+    "hevc_freq",
+    "ignore_lcd",
+    "include",
+    "initial_turbo",
+    "initramfs",
+    "isp_freq",
+    "kernel",
+    "kernel_address",
+    "kernel_old",
+    "lcd_framerate",
+    "lcd_ignore",
+    "lcd_rotate",
+    "max_framebuffer_height",
+    "max_framebuffer_width",
+    "max_framebuffers",
+    "max_usb_current",
+    "never_over_voltage",
+    "os_check",
+    "os_prefix",
+    "otg_mode",
+    "over_voltage",
+    "over_voltage_delta",
+    "over_voltage_min",
+    "over_voltage_sdram",
+    "over_voltage_sdram_c",
+    "over_voltage_sdram_i",
+    "over_voltage_sdram_p",
+    "overlay_prefix",
+    "overscan_bottom",
+    "overscan_left",
+    "overscan_right",
+    "overscan_scale",
+    "overscan_top",
+    "power_force_3v3_pwm",
+    "program_serial_random",
+    "program_usb_boot_mode",
+    "program_usb_boot_timeout",
+    "psu_max_current",
+    "ramfsaddr",
+    "ramfsfile",
+    "sdram_freq",
+    "sdram_freq_min",
+    "sdram_schmoo",
+    "sha256",
+    "start_cd",
+    "start_debug",
+    "start_file",
+    "start_x",
+    "temp_limit",
+    "temp_soft_limit",
+    "test_mode",
+    "total_mem",
+    "uart_2ndstage",
+    "upstream_kernel",
+    "usb_max_current_enable",
+    "v3d_freq",
+    "v3d_freq_min",
+];
+const KNOWN_KEY_PREFIXES: [&str; 2] = ["hdmi_", "sdtv_"];
+
+/// firmwareが解釈するconfig.txtのkeyか
+fn is_known_key(key: &str) -> bool {
+    KNOWN_COMMAND_KEYS.binary_search(&key).is_ok()
+        || KNOWN_KEY_PREFIXES
+            .iter()
+            .any(|prefix| key.starts_with(prefix))
+}
+
+/// u-bootの設定に変換できるdtparamのkeyか
+/// translationsにあるkeyは、組み込みの変換が無くても変換できる
+/// 組み込みの変換で対応しているかはkeyだけで決まるので、値は何でもよい
+fn is_known_dtparam_key(key: &str, translations: &HashMap<String, Vec<String>>) -> bool {
+    translations.contains_key(key) || builtin_dtparam_commands(key, "on").is_some()
+}
+
+/// 電圧やクロックを変更する設定
+const OVERCLOCK_KEYS: [&str; 4] = [
+    "arm_boost",
+    "force_turbo",
+    "over_voltage",
+    "over_voltage_delta",
+];
+
+/// over_voltageの上限(force_turbo=1の場合を除く)
+const MAX_OVER_VOLTAGE: i32 = 6;
+
+/// overclock関連の設定のうち、危険な組み合わせや不正な値を警告する
+/// commandはkeyに対して有効な値を返す
+fn overclock_warnings<'a>(command: impl Fn(&str) -> Option<&'a str>) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let force_turbo = command("force_turbo") == Some("1");
+
+    if let Some(value) = command("arm_boost") {
+        if !matches!(value, "0" | "1") {
+            warnings.push(format!("arm_boost expects 0 or 1, got {:?}", value));
+        }
+    }
+    if let Some(value) = command("over_voltage") {
+        match value.parse::<i32>() {
+            Ok(0) => {}
+            Ok(voltage) if force_turbo => warnings.push(format!(
+                "force_turbo=1 with over_voltage={} sets the permanent warranty bit",
+                voltage
+            )),
+            Ok(voltage) if voltage > MAX_OVER_VOLTAGE => warnings.push(format!(
+                "over_voltage={} is limited to {} unless force_turbo=1",
+                voltage, MAX_OVER_VOLTAGE
+            )),
+            Ok(_) => {}
+            Err(err) => warnings.push(format!("Invalid over_voltage: {:?} ({})", value, err)),
+        }
+    }
+    if let Some(value) = command("over_voltage_delta") {
+        match value.parse::<i32>() {
+            Ok(0) => {}
+            Ok(delta) if force_turbo => warnings.push(format!(
+                "force_turbo=1 with over_voltage_delta={} sets the permanent warranty bit",
+                delta
+            )),
+            Ok(_) => {}
+            Err(err) => warnings.push(format!("Invalid over_voltage_delta: {:?} ({})", value, err)),
+        }
+    }
+
+    warnings
+}
+
+/// 同じモデルで同時に適用できないoverlayの組
+/// 各グループ内のoverlayは、どの2つを組み合わせても壊れたdevice treeになる
+const OVERLAY_CONFLICTS: [&[&str]; 4] = [
+    // どちらもbluetoothが使うUARTを付け替える
+    &["disable-bt", "miniuart-bt"],
+    &["pi3-disable-bt", "pi3-miniuart-bt"],
+    // KMSとfake KMSのドライバは同じvc4のノードを使う
+    &["vc4-kms-v3d", "vc4-kms-v3d-pi4", "vc4-fkms-v3d"],
+    // I2Sのサウンドカードは1つしか接続できない
+    &[
+        "hifiberry-dac",
+        "hifiberry-dacplus",
+        "hifiberry-digi",
+        "iqaudio-dac",
+        "iqaudio-dacplus",
+        "justboom-dac",
+    ],
+];
+
+/// configsの中で同時に適用できないoverlayの組を、書かれている順に列挙する
+fn overlay_conflicts<'a>(
+    configs: impl Iterator<Item = &'a ConfigEntry>,
+) -> Vec<(&'a str, &'a str)> {
+    let mut overlays: Vec<&str> = Vec::new();
+    for config in configs {
+        if let ConfigEntry::DTOverlay(overlay) = config {
+            let name = overlay
+                .overlay
+                .strip_suffix(".dtbo")
+                .unwrap_or(&overlay.overlay);
+            if !overlays.contains(&name) {
+                overlays.push(name);
+            }
+        }
+    }
+
+    let mut conflicts = Vec::new();
+    for (i, a) in overlays.iter().enumerate() {
+        for b in &overlays[i + 1..] {
+            if OVERLAY_CONFLICTS
+                .iter()
+                .any(|group| group.contains(a) && group.contains(b))
+            {
+                conflicts.push((*a, *b));
+            }
+        }
+    }
+    conflicts
+}
+
+/// 数値をとるパラメータを読み込み、範囲外の値はエラーにする
+fn parse_ranged(name: &str, value: &str, range: RangeInclusive<u32>) -> Result<u32> {
+    let number: u32 = value
+        .parse()
+        .map_err(|err| anyhow!("Invalid {}: {:?} ({})", name, value, err))?;
+    if !range.contains(&number) {
+        return Err(anyhow!(
+            "{} out of range: {} (expected {}..={})",
+            name,
+            number,
+            range.start(),
+            range.end()
+        ));
+    }
+    Ok(number)
+}
+
+impl DTparam {
+    /// TODO: U-Bootのconfigを現在は;で結合しているが、||や&&でも結合できるよう、戻り値をVec<String>から適切なものに変更する
+    /// 変換表を使わず、組み込みの変換だけで変換する
+    #[cfg(test)]
+    fn generate_uboot_config(&self) -> Result<Vec<String>> {
+        self.generate_uboot_config_with_options(&UbootOptions::default())
+    }
+
+    /// options.dtparam_translationsにあるkeyは組み込みの変換の代わりにテンプレートから生成する
+    fn generate_uboot_config_with_options(&self, options: &UbootOptions) -> Result<Vec<String>> {
+        let mut commands = Vec::new();
+
+        for (key, value) in self
+            .configs
+            .iter()
+            .map(|Config { key, value }| (key.as_ref(), value.as_ref()))
+        {
+            // dtparam=i2c_armのように値を省略した場合は、firmwareと同様に=onとして扱う
+            let value = match value {
+                "" => "on",
+                _ => value,
+            };
+            if let Some(templates) = options.dtparam_translations.get(key) {
+                commands.extend(
+                    templates
+                        .iter()
+                        .map(|template| template.replace("{value}", value)),
+                );
+                continue;
+            }
+            match builtin_dtparam_commands(key, value) {
+                Some(result) => commands.extend(result?),
+                None => return Err(unsupported_dtparam_key(key)),
+            }
+        }
+
+        Ok(commands)
+    }
+}
+
+/// 組み込みの変換でdtparamの1つのkeyと値をu-bootのコマンドに変換する
+/// 変換できないkeyの場合はNoneを返す。keyだけで決まるので、is_known_dtparam_keyもこれを使う
+fn builtin_dtparam_commands(key: &str, value: &str) -> Option<Result<Vec<String>>> {
+    fn dtparam_error(key: &str, value: &str) -> Result<String> {
+        Err(anyhow!("Unsupported dtparam option: {}={}", key, value))
+    }
+    // act_led_*とpwr_led_*のパラメータが対象とするLEDのノード
+    fn led_node(key: &str) -> &'static str {
+        match key.starts_with("act_") {
+            true => "/leds/act",
+            false => "/leds/pwr",
+        }
+    }
+
+    let fdt_command: Result<String> = match key {
+        "act_led_trigger" => match value {
+            "default-on" => Ok("fdt set /leds/act linux,default-trigger default-on".to_string()),
+            _ => dtparam_error(key, value),
+        },
+        "audio" => match value {
+            "on" => Ok("fdt set /soc/audio status okay".to_string()),
+            _ => dtparam_error(key, value),
+        },
+        "i2c_arm" => match value {
+            "on" => Ok("fdt set i2c_arm status okay".to_string()),
+            _ => dtparam_error(key, value),
+        },
+        "i2s" => match value {
+            "on" => Ok("fdt set i2s status okay".to_string()),
+            _ => dtparam_error(key, value),
+        },
+        // kernelのhci_uartがserdev経由でbluetoothを扱うかどうか
+        "krnbt" => match value {
+            "on" => Ok("fdt set bluetooth status okay".to_string()),
+            "off" => Ok("fdt set bluetooth status disabled".to_string()),
+            _ => dtparam_error(key, value),
+        },
+        "pwr_led_activelow" | "act_led_activelow" => {
+            let node = led_node(key);
+            match value {
+                // https://patchwork.ozlabs.org/project/uboot/patch/1496149544-32348-1-git-send-email-hannes.schmelzer@br-automation.com/
+                "off" => Ok(format!("fdt set {} gpios < ? ? 0x00 >", node)),
+                "on" => Ok(format!("fdt set {} gpios < ? ? 0x01 >", node)),
+                _ => dtparam_error(key, value),
+            }
+        }
+        // gpiosは< gpioコントローラのphandle ピン番号 フラグ >なので、ピン番号のみ書き換える
+        // ?のセルは元の値のままなので、*_led_activelowと組み合わせても順番に依らない
+        "pwr_led_gpio" | "act_led_gpio" => parse_ranged(key, value, GPIO_RANGE)
+            .map(|gpio| format!("fdt set {} gpios < ? {:#x} ? >", led_node(key), gpio)),
+        "pwr_led_trigger" => match value {
+            "none" => Ok("fdt set /leds/pwr linux,default-trigger none".to_string()),
+            _ => dtparam_error(key, value),
+        },
+        "spi" => match value {
+            "on" => Ok("fdt set spi0 status okay".to_string()),
+            _ => dtparam_error(key, value),
+        },
+        "watchdog" => match value {
+            "on" => Ok("fdt set watchdog status okay".to_string()),
+            _ => dtparam_error(key, value),
+        },
+        // i2c_baudrateはi2c_arm_baudrateの別名として扱う
+        "i2c_arm_baudrate" | "i2c_baudrate" => {
+            parse_ranged("i2c clock-frequency", value, I2C_BAUDRATE_RANGE)
+                .map(|baudrate| format!("fdt set i2c clock-frequency < {:#x} >", baudrate))
+        }
+        // CM4のアンテナ切り替えは複数のgpio-hogを書き換えるので、まとめて追加する
+        "ant1" | "ant2" | "noant" => match value {
+            "on" => return Some(Ok(cm4_antenna_commands(key))),
+            _ => dtparam_error(key, value),
+        },
+        // SDカードのコントローラ(Pi 3まではsdhost、Pi 4はemmc2)はmmc0のaliasで指定する
+        "sd_overclock" => parse_ranged("sd_overclock", value, SD_OVERCLOCK_RANGE)
+            .map(|clock| format!("fdt set mmc0 brcm,overclock-50 < {:#x} >", clock)),
+        "sd_force_pio" => match value {
+            "on" => Ok("fdt set mmc0 brcm,force-pio".to_string()),
+            _ => dtparam_error(key, value),
+        },
+        "sd_pio_limit" => value
+            .parse::<u32>()
+            .map(|limit| format!("fdt set mmc0 brcm,pio-limit < {:#x} >", limit))
+            .map_err(|err| anyhow!("Invalid sd_pio_limit: {:?} ({})", value, err)),
+        _ if key.starts_with("poe_fan_temp") => poe_fan_command(key, value)?,
+        // Pi 5のRTCと、バックアップ電池のトリクル充電の設定
+        "rtc" => match value {
+            "on" => return Some(Ok(pi5_rtc_commands("status okay"))),
+            "off" => return Some(Ok(pi5_rtc_commands("status disabled"))),
+            _ => dtparam_error(key, value),
+        },
+        "rtc_bbat_vchg" => {
+            return Some(
+                parse_ranged(key, value, RTC_BBAT_VCHG_RANGE).map(|microvolt| {
+                    pi5_rtc_commands(&format!("trickle-charge-microvolt < {:#x} >", microvolt))
+                }),
+            )
+        }
+        _ => return None,
+    };
+    Some(fdt_command.map(|command| vec![command]))
+}
+
+/// dtparamとして書かれることがあるが、基本のdevice treeのパラメータではないkeyと、その理由
+const REJECTED_DTPARAM_KEYS: [(&str, &str); 6] = [
+    (
+        "audio_channels",
+        "has no device-tree equivalent; neither the bcm2835 audio nor the i2s driver reads a channel count",
+    ),
+    (
+        "audio_enable_jack",
+        "is a parameter of the audremap overlay; use dtoverlay=audremap,enable_jack instead",
+    ),
+    (
+        "audio_pins",
+        "is a parameter of the audremap overlay; use dtoverlay=audremap,pins_<a>_<b> instead",
+    ),
+    (
+        "audio_pwm_mode",
+        "is a firmware setting, not a dtparam; write audio_pwm_mode on its own line",
+    ),
+    (
+        "audio_swap_lr",
+        "is a parameter of the audremap overlay; use dtoverlay=audremap,swap_lr instead",
+    ),
+    (
+        "disable_audio_dither",
+        "is a firmware setting, not a dtparam; write disable_audio_dither on its own line",
+    ),
+];
+
+/// 組み込みの変換に無いdtparamのkeyのエラー
+/// 番号などが付くkeyは、対応している範囲もエラーに含める
+fn unsupported_dtparam_key(key: &str) -> anyhow::Error {
+    if let Some((_, reason)) = REJECTED_DTPARAM_KEYS.iter().find(|(x, _)| *x == key) {
+        anyhow!("Unsupported dtparam key: {} {}", key, reason)
+    } else if key.starts_with("i2s_") {
+        // bcm2835-i2sドライバは再生・録音を切り替えるpropertyを読まないので、i2s以外は変換しない
+        anyhow!("Unsupported i2s dtparam key: {} (supported: i2s)", key)
+    } else if key.starts_with("poe_fan_temp") {
+        anyhow!(
+            "Unsupported dtparam key: {} (supported: poe_fan_temp0..3, poe_fan_temp0_hyst..3_hyst)",
+            key
+        )
+    } else {
+        anyhow!("Unsupported dtparam key: {}", key)
+    }
+}
+
+/// overlayを1つ適用する際に増えるfdtの大きさの見積もり
+const FDT_APPLY_GROWTH: usize = 0x800;
+/// ノードやプロパティを1つ追加・変更する際に増えるfdtの大きさの見積もり
+const FDT_SET_GROWTH: usize = 0x40;
+
+/// コマンドを実行した際に増えるfdtの大きさを見積もる
+fn estimate_fdt_growth(commands: &[String]) -> usize {
+    commands
+        .iter()
+        .map(|command| {
+            // ノードの有無を調べてから作るコマンドは、作る方で見積もる
+            let command = match command.rsplit_once(" || ") {
+                Some((_, command)) => command,
+                None => command,
+            };
+            if command.starts_with("fdt apply ") {
+                FDT_APPLY_GROWTH
+            } else if command.starts_with("fdt set ") || command.starts_with("fdt mknode ") {
+                FDT_SET_GROWTH
+            } else {
+                0
+            }
+        })
+        .sum()
+}
+
+/// 名前が変わったoverlayの、古い名前と現在の名前の対応
+/// https://github.com/raspberrypi/firmware/blob/master/boot/overlays/README の"Legacy"の項目
+const LEGACY_OVERLAY_NAMES: [(&str, &str); 4] = [
+    ("pi3-act-led", "act-led"),
+    ("pi3-disable-bt", "disable-bt"),
+    ("pi3-disable-wifi", "disable-wifi"),
+    ("pi3-miniuart-bt", "miniuart-bt"),
+];
+
+/// 古い名前のoverlayであれば現在の名前を返す
+fn modern_overlay_name(name: &str) -> &str {
+    LEGACY_OVERLAY_NAMES
+        .iter()
+        .find(|(legacy, _)| *legacy == name)
+        .map_or(name, |(_, modern)| modern)
+}
+
+/// overlaysディレクトリ内の.dtboのファイル名として使える名前か
+/// e.g. vc4-kms-v3d-pi4, rpi-display.v2
+fn is_overlay_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '+'))
+}
+
+/// Pi 5のRTCのノードにpropertyを設定する
+/// Pi 5以外のボードにはこのノードが無いので、board_nameで分岐する
+fn pi5_rtc_commands(property: &str) -> Vec<String> {
+    vec![
+        format!(
+            "if {}",
+            board_name_condition(&[PiModel::Pi5B, PiModel::Pi500, PiModel::CM5])
+        ),
+        "then".to_string(),
+        format!("fdt set {} {}", RPI_RTC_NODE, property),
+        "fi".to_string(),
+    ]
+}
+
+/// CM4のアンテナを切り替える
+/// ant1(内蔵)とant2(外部)はexpgpioのgpio-hogで選択されるので、その出力を書き換える
+/// CM4以外のボードにはこのノードが無いので、board_nameで分岐する
+fn cm4_antenna_commands(key: &str) -> Vec<String> {
+    let (ant1, ant2) = match key {
+        "ant1" => (true, false),
+        "ant2" => (false, true),
+        _ => (false, false),
+    };
+    let mut commands = vec![
+        format!("if {}", board_name_condition(&[PiModel::CM4])),
+        "then".to_string(),
+    ];
+    for (node, high) in [("ant1", ant1), ("ant2", ant2)] {
+        let (from, to) = match high {
+            true => ("output-low", "output-high"),
+            false => ("output-high", "output-low"),
+        };
+        commands.push(format!("fdt rm /soc/firmware/gpio/{} {}", node, from));
+        commands.push(format!("fdt set /soc/firmware/gpio/{} {}", node, to));
+    }
+    commands.push("fi".to_string());
+    commands
+}
+
+/// PoE HATのファンが回り始める温度の設定を、cpu-thermalのtripノードを書き換えるコマンドに変換する
+/// poe_fan_temp0からpoe_fan_temp3までと、それぞれの_hystに対応する
+/// tripノードはrpi-poe overlayが追加するので、dtoverlay=rpi-poeの後に書く必要がある
+/// 対応していないkeyの場合はNoneを返す
+fn poe_fan_command(key: &str, value: &str) -> Option<Result<String>> {
+    let trip = key.strip_prefix("poe_fan_temp")?;
+    let (trip, property, range) = match trip.strip_suffix("_hyst") {
+        Some(trip) => (trip, "hysteresis", POE_FAN_HYST_RANGE),
+        None => (trip, "temperature", POE_FAN_TEMP_RANGE),
+    };
+    if !matches!(trip, "0" | "1" | "2" | "3") {
+        return None;
+    }
+    Some(parse_ranged(key, value, range).map(|millicelsius| {
+        format!(
+            "fdt set /thermal-zones/cpu-thermal/trips/trip{} {} < {:#x} >",
+            trip, property, millicelsius
+        )
+    }))
+}
+
+/// i2c-rtc overlayで選択できるRTCのチップと、compatible、既定のI2Cアドレス
+/// overlayのチップごとのノードは__dormant__のfragmentにあり、fdt applyでは追加されない
+/// https://github.com/raspberrypi/linux/blob/rpi-6.6.y/arch/arm/boot/dts/overlays/i2c-rtc-common.dtsi
+const I2C_RTC_CHIPS: [(&str, &str, u32); 12] = [
+    ("abx80x", "abracon,abx80x", 0x69),
+    ("ds1307", "maxim,ds1307", 0x68),
+    ("ds1339", "dallas,ds1339", 0x68),
+    ("ds3231", "maxim,ds3231", 0x68),
+    ("m41t62", "st,m41t62", 0x68),
+    ("mcp7940x", "microchip,mcp7940x", 0x6f),
+    ("mcp7941x", "microchip,mcp7941x", 0x6f),
+    ("pcf2127", "nxp,pcf2127", 0x51),
+    ("pcf8523", "nxp,pcf8523", 0x68),
+    ("pcf85063", "nxp,pcf85063", 0x51),
+    ("pcf8563", "nxp,pcf8563", 0x51),
+    ("rv3028", "microcrystal,rv3028", 0x52),
+];
+
+/// dtoverlay=i2c-rtcのパラメータを、選択したチップのノードをi2c_armの下に作るコマンドに変換する
+/// 対応しているのは、チップを選択するフラグとaddrのみ
+fn i2c_rtc_commands(configs: &[Config]) -> Result<Vec<String>> {
+    let mut chip = None;
+    let mut addr = None;
+    for c in configs {
+        match (c.key.as_ref(), c.value.as_ref()) {
+            ("addr", value) => addr = Some(parse_address("i2c-rtc addr", value)?),
+            (key, "") => match I2C_RTC_CHIPS.iter().find(|(name, _, _)| *name == key) {
+                Some(_) if chip.is_some() => {
+                    return Err(anyhow!("dtoverlay=i2c-rtc accepts only one chip: {}", c))
+                }
+                Some(x) => chip = Some(x),
+                None => return Err(anyhow!("Unsupported dtoverlay parameter: i2c-rtc,{}", c)),
+            },
+            _ => return Err(anyhow!("Unsupported dtoverlay parameter: i2c-rtc,{}", c)),
+        }
+    }
+    let (name, compatible, default_addr) =
+        chip.ok_or(anyhow!("dtoverlay=i2c-rtc requires a chip (e.g. ds3231)"))?;
+
+    // overlayと同じく、ノード名は既定のアドレスのままにしてregのみaddrにする
+    let reg = addr.unwrap_or(u64::from(*default_addr));
+    let node = format!("{}@{:x}", name, default_addr);
+    Ok(vec![
+        "fdt set i2c_arm status okay".to_string(),
+        format!("fdt mknode i2c_arm {}", node),
+        format!("fdt set i2c_arm/{} compatible {}", node, compatible),
+        format!("fdt set i2c_arm/{} reg < {:#x} >", node, reg),
+    ])
+}
+
+impl DTOverlay {
+    /// overlayを読み込むだけでなく、BCM2835からBCM2711までのノードを直接書き換えるか
+    fn edits_nodes(&self) -> bool {
+        matches!(self.overlay.as_ref(), "i2smaster" | "gpio") || !self.configs.is_empty()
+    }
+
+    fn generate_uboot_config(&self, options: &UbootOptions) -> Result<Vec<String>> {
+        let overlay = &self.overlay;
+        let configs = &self.configs;
+        let mut commands: Vec<String> = Vec::new();
+
+        // i2sなど特殊対応のものに対応する
+        if overlay == "i2smaster" {
+            commands.push("fdt set i2s status okay".to_string());
+            return Ok(commands);
+        }
+
+        if overlay == "gpio" {
+            return self.generate_gpio_hog();
+        }
+
+        // ファイル名としてそのままloadのパスに入るので、パスの区切りや空白などは受け付けない
+        if !is_overlay_name(overlay) {
+            return Err(anyhow!("Invalid dtoverlay name: {:?}", overlay));
+        }
+        // .dtboまで書かれている場合はそのまま使う
+        let overlay = overlay.strip_suffix(".dtbo").unwrap_or(overlay);
+        // 例: pi3-disable-bt.dtbo -> disable-bt.dtbo
+        let overlay = match options.rename_legacy_overlays {
+            true => modern_overlay_name(overlay),
+            false => overlay,
+        };
+
+        // チップのノードはoverlayを適用しても作られないので、読み込まずに直接作る
+        // パラメータが無い場合は、どのチップも有効にせずに読み込むだけにする
+        if overlay == "i2c-rtc" && !configs.is_empty() {
+            return i2c_rtc_commands(configs);
+        }
+
+        // TODO: ロード元のアドレスを編集できるようにする
+        let load = format!("load ${{devtype}} ${{devnum}}:${{devpart}} ${{fdt_ovaddr}} ${{fdtdir}}/overlays/{}.dtbo", overlay);
+        let mut apply = vec!["fdt apply ${fdt_ovaddr}".to_string()];
+
+        if !configs.is_empty() {
+            // TODO: パラメータを修正するコードを入れる
+            for c in configs {
+                let command = match overlay {
+                    "dwc2" => format!("fdt set usb {} {}", c.key, c.value),
+                    _ => {
+                        return Err(anyhow!(
+                            "Unsupported dtoverlay parameter: {},{}",
+                            overlay,
+                            c
+                        ))
+                    }
+                };
+                apply.push(command);
+            }
+        }
+
+        if options.check_errors {
+            // overlayが読み込めなかった場合に、fdtを変更せずにそのことを表示する
+            commands.push(format!("if {}", load));
+            commands.push("then".to_string());
+            commands.append(&mut apply);
+            commands.push("else".to_string());
+            commands.push(format!("echo \"overlay {} missing\"", overlay));
+            commands.push("fi".to_string());
+        } else {
+            commands.push(load);
+            commands.append(&mut apply);
+        }
+        Ok(commands)
+    }
+
+    /// dtoverlay=gpioの設定を、gpioノードの下にgpio-hogを作るコマンドに変換する
+    /// overlayを読み込む代わりに、直接ノードを作る
+    fn generate_gpio_hog(&self) -> Result<Vec<String>> {
+        let mut gpio = None;
+        // include/dt-bindings/gpio/gpio.h のフラグ
+        let mut flags = 0u32;
+        let mut state = "input";
+        for c in &self.configs {
+            match (c.key.as_ref(), c.value.as_ref()) {
+                ("gpio", value) => gpio = Some(parse_ranged("gpio", value, GPIO_RANGE)?),
+                ("active_low", "" | "1" | "on") => flags |= GPIO_ACTIVE_LOW,
+                ("active_low", "0" | "off") => flags &= !GPIO_ACTIVE_LOW,
+                ("func", "in") => state = "input",
+                ("func", "out" | "low") => state = "output-low",
+                ("func", "high") => state = "output-high",
+                ("pull", "up") => flags = flags & !GPIO_PULL_DOWN | GPIO_PULL_UP,
+                ("pull", "down") => flags = flags & !GPIO_PULL_UP | GPIO_PULL_DOWN,
+                ("pull", "off" | "none") => flags &= !(GPIO_PULL_UP | GPIO_PULL_DOWN),
+                ("active_low" | "func" | "pull", _) => {
+                    return Err(anyhow!("Unsupported gpio overlay option: {}", c))
+                }
+                _ => return Err(anyhow!("Unsupported dtoverlay parameter: gpio,{}", c)),
+            }
+        }
+        let gpio = gpio.ok_or(anyhow!("dtoverlay=gpio requires gpio=<pin>"))?;
+
+        let node = format!("gpio/gpio{}-hog", gpio);
+        Ok(vec![
+            format!("fdt mknode gpio gpio{}-hog", gpio),
+            format!("fdt set {} gpio-hog", node),
+            format!("fdt set {} gpios < {:#x} {:#x} >", node, gpio, flags),
+            format!("fdt set {} {}", node, state),
+        ])
+    }
+}
+
+impl GpuMem {
+    /// config.txtに書かれていた形の設定
+    fn source(&self) -> ConfigEntry {
+        ConfigEntry::GpuMem(GpuMem {
+            model: None,
+            ..self.clone()
+        })
+    }
+
+    /// CPUとGPUに割り当てるメモリ量(byte)を (cpu, gpu) の組で返す
+    fn memory_split(&self, options: &UbootOptions) -> Result<(usize, usize)> {
+        // gpu_mem=*のようにメモリ量の指定が無い場合は、モデルの搭載メモリ量を使う
+        let total_ramsize = self
+            .total_ramsize
+            .or_else(|| options.model_ramsize.get(self.model.as_deref()?).copied())
+            .ok_or_else(|| {
+                anyhow!(
+                    "Unsupported total_ramsize: {:?} (from {})",
+                    self.model,
+                    self.source()
+                )
+            })?;
+        let total_ramsize = total_ramsize
+            .checked_mul(1024 * 1024)
+            .ok_or(anyhow!("total_ramsize is too large: {}MB", total_ramsize))?;
+        let gpu_ramsize = self
+            .gpu_ramsize
+            .checked_mul(1024 * 1024)
+            .ok_or(anyhow!("gpu_ramsize is too large: {}MB", self.gpu_ramsize))?;
+        let cpu_ramsize = total_ramsize
+            .checked_sub(gpu_ramsize)
+            .ok_or(anyhow!("gpu_ramsize must be smaller than total_ramsize"))?;
+        Ok((cpu_ramsize, gpu_ramsize))
+    }
+
+    fn generate_uboot_config(&self, options: &UbootOptions) -> Result<Vec<String>> {
+        let (cpu_ramsize, gpu_ramsize) = self.memory_split(options)?;
+
+        let hex = |value: usize| match options.fixed_width_hex {
+            true => format!("{:#010x}", value),
+            false => format!("{:#x}", value),
+        };
+        let zero = match options.fixed_width_hex {
+            true => hex(0),
+            false => "0x00".to_string(),
+        };
+
+        match &self.model {
+            Some(model) => match PiModel::from_board_name(model) {
+                Some(PiModel::Pi4B | PiModel::Pi400 | PiModel::CM4) => Ok(vec![
+                    format!(
+                        "fdt set / memreserve < {} {} >",
+                        hex(cpu_ramsize),
+                        hex(gpu_ramsize),
+                    ),
+                    format!(
+                        "fdt set /memory@0 reg < {zero} {zero} {} {zero} {} {} >",
+                        hex(cpu_ramsize),
+                        hex(0x40000000),
+                        hex(0xbc000000),
+                        zero = zero
+                    ),
+                ]),
+                Some(
+                    PiModel::Zero
+                    | PiModel::ZeroW
+                    | PiModel::Zero2W
+                    | PiModel::Pi3B
+                    | PiModel::Pi3BPlus
+                    | PiModel::Pi3APlus
+                    | PiModel::CM3
+                    | PiModel::CM3Plus,
+                ) => Ok(vec![
+                    format!(
+                        "fdt set / memreserve < {} {} >",
+                        hex(cpu_ramsize),
+                        hex(gpu_ramsize),
+                    ),
+                    format!("fdt set /memory@0 reg < {} {} >", zero, hex(cpu_ramsize)),
+                ]),
+                // TODO: Pi 5はメモリ配置が異なるので、対応するまではエラーにする
+                // gpu_mem_*からモデルに振り分けられた場合もあるので、元の設定も表示する
+                Some(PiModel::Pi5B | PiModel::Pi500 | PiModel::CM5) => Err(anyhow!(
+                    "gpu_mem is not yet supported on this model: {:?} (from {})",
+                    model,
+                    self.source()
+                )),
+                None => Err(anyhow!(
+                    "Unsupported platform: {:?}, command: {}",
+                    model,
+                    self.source()
+                )),
+            },
+            None => Err(anyhow!("gpu_mem.model is None")),
+        }
+    }
+}
+
+impl ConfigEntry {
+    /// 1つの設定をu-bootのコマンドに変換する
+    /// gpu_memはモデルによって出力が異なるので、modelにu-bootのモデル名を指定する
+    pub fn to_uboot_commands(&self, model: Option<&str>) -> Result<Vec<String>> {
+        match (self, model) {
+            (ConfigEntry::GpuMem(gpumem), Some(model)) => GpuMem {
+                model: Some(model.to_string()),
+                ..gpumem.clone()
+            }
+            .generate_uboot_config(&UbootOptions::default()),
+            _ => self.generate_uboot_config(&UbootOptions::default()),
+        }
+    }
+
+    /// U-Bootで設定が必要な部分を取り出して変換する
+    fn generate_uboot_config(&self, options: &UbootOptions) -> Result<Vec<String>> {
+        let commands = match self {
+            ConfigEntry::DTOverlay(x) => x.generate_uboot_config(options),
+            ConfigEntry::DTparam(x) => x.generate_uboot_config_with_options(options),
+            ConfigEntry::GpuMem(x) => x.generate_uboot_config(options),
+            ConfigEntry::Command(x) => x.generate_uboot_config(options),
+            _ => Ok(Vec::new()),
+        }?;
+        Ok(commands
+            .into_iter()
+            .map(|command| apply_node_path_style(command, options.node_path_style))
+            .map(|command| guard_mknode(command, options.idempotent))
+            .collect())
+    }
+
+    /// platform向けのu-bootの設定に変換されずに読み捨てられる場合、その理由を返す
+    fn skip_reason(&self, platform: &str) -> Option<String> {
+        match self {
+            ConfigEntry::Command(c) => c.skip_reason(platform),
+            ConfigEntry::HdmiTimings(_) => Some(
+                "hdmi_timings defines a custom HDMI mode for the firmware and has no device-tree equivalent"
+                    .to_string(),
+            ),
+            ConfigEntry::HdmiCvt(_) => Some(
+                "hdmi_cvt defines a custom HDMI mode for the firmware and has no device-tree equivalent"
+                    .to_string(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// arrange_for_ubootでmodelが埋められる前の状態に戻す
+    fn without_model(&self) -> ConfigEntry {
+        match self {
+            ConfigEntry::GpuMem(gpumem) => ConfigEntry::GpuMem(GpuMem {
+                model: None,
+                ..gpumem.clone()
+            }),
+            _ => self.clone(),
+        }
+    }
+
+    /// u-bootへの変換対象になる設定か
+    pub fn is_enabled(&self) -> bool {
+        !matches!(self, ConfigEntry::Disabled(_))
+    }
+
+    /// 設定を有効・無効にする
+    /// コメントとfilterは無効にできないので、何もしない
+    pub fn set_enabled(&mut self, enabled: bool) {
+        match (enabled, &*self) {
+            (true, ConfigEntry::Disabled(entry)) => *self = (**entry).clone(),
+            (false, ConfigEntry::Comment(_) | ConfigEntry::ConditionFilter(_)) => {}
+            (false, entry) if entry.is_enabled() => {
+                *self = ConfigEntry::Disabled(Box::new(entry.clone()))
+            }
+            _ => {}
+        }
+    }
+
+    /// 意味の上で同じ設定か比較する
+    /// コメントの空白の違いと、dtparamやdtoverlayのパラメータの順番は無視する
+    pub fn semantically_eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (ConfigEntry::Comment(a), ConfigEntry::Comment(b)) => {
+                a.split_whitespace().eq(b.split_whitespace())
+            }
+            (ConfigEntry::DTparam(a), ConfigEntry::DTparam(b)) => {
+                sorted_params(&a.configs) == sorted_params(&b.configs)
+            }
+            (ConfigEntry::DTOverlay(a), ConfigEntry::DTOverlay(b)) => {
+                a.overlay == b.overlay && sorted_params(&a.configs) == sorted_params(&b.configs)
+            }
+            _ => self == other,
+        }
+    }
+
+    /// diffで同じ設定の変更とみなすための識別子
+    fn diff_key(&self) -> String {
+        match self {
+            ConfigEntry::Comment(c) => format!("#{}", c),
+            ConfigEntry::Disabled(entry) => format!("#{}", entry),
+            ConfigEntry::Command(c) => c.key.clone(),
+            ConfigEntry::DTOverlay(o) => format!("dtoverlay={}", o.overlay),
+            ConfigEntry::DTparam(p) => format!(
+                "dtparam={}",
+                p.configs
+                    .iter()
+                    .map(|c| c.key.as_str())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+            ConfigEntry::ConditionFilter(f) => format!("[{}]", f),
+            ConfigEntry::GpuMem(g) => match g.total_ramsize {
+                Some(total) => format!("gpu_mem_{}", total),
+                None => "gpu_mem".to_string(),
+            },
+            ConfigEntry::HdmiTimings(_) => "hdmi_timings".to_string(),
+            ConfigEntry::HdmiCvt(_) => "hdmi_cvt".to_string(),
+        }
+    }
+}
+
+/// dtoverlayのパラメータをkeyの順に並べ替える
+/// 同じkeyが複数ある場合は後に書かれた方が優先されるので、順番で意味が変わるものとして並べ替えない
+fn sort_overlay_params(overlay: &mut DTOverlay) {
+    if ORDER_SENSITIVE_OVERLAYS.contains(&overlay.overlay.as_str()) {
+        return;
+    }
+    let mut keys: Vec<&str> = overlay.configs.iter().map(|c| c.key.as_str()).collect();
+    keys.sort_unstable();
+    if keys.windows(2).any(|pair| pair[0] == pair[1]) {
+        return;
+    }
+    overlay.configs.sort_by(|a, b| a.key.cmp(&b.key));
+}
+
+fn sorted_params(configs: &[Config]) -> Vec<(&str, &str)> {
+    let mut params: Vec<(&str, &str)> = configs
+        .iter()
+        .map(|c| (c.key.as_str(), c.value.as_str()))
+        .collect();
+    params.sort_unstable();
+    params
+}
+
+/// 2つのRPiConfigの差分
+/// 各要素は(filter, 設定)の組
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct ConfigDiff {
+    pub added: Vec<(String, ConfigEntry)>,
+    pub removed: Vec<(String, ConfigEntry)>,
+    /// (filter, 変更前, 変更後)
+    pub changed: Vec<(String, ConfigEntry, ConfigEntry)>,
+}
+
+impl ConfigDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    /// 1つのfilter内の設定を比較する
+    /// 並び順は無視し、意味の上で同じ設定が無いもののうちdiff_keyが同じものを変更とみなす
+    fn push_entries(&mut self, filter: &str, old: &[Entry], new: &[Entry]) {
+        let mut removed: Vec<&ConfigEntry> = old.iter().map(|entry| &entry.config).collect();
+        let mut added = Vec::new();
+        for entry in new.iter().map(|entry| &entry.config) {
+            match removed.iter().position(|x| x.semantically_eq(entry)) {
+                Some(pos) => {
+                    removed.remove(pos);
+                }
+                None => added.push(entry),
+            }
+        }
+        for entry in added {
+            match removed
+                .iter()
+                .position(|x| x.diff_key() == entry.diff_key())
+            {
+                Some(pos) => self.changed.push((
+                    filter.to_string(),
+                    removed.remove(pos).clone(),
+                    entry.clone(),
+                )),
+                None => self.added.push((filter.to_string(), entry.clone())),
+            }
+        }
+        self.removed.extend(
+            removed
+                .into_iter()
+                .map(|entry| (filter.to_string(), entry.clone())),
+        );
+    }
+}
+
+/// 搭載メモリ量(MB)がtotal_memsizeのモデルを列挙する
+fn models_with_ramsize(model_ramsize: &HashMap<String, usize>, total_memsize: usize) -> Vec<&str> {
+    model_ramsize
+        .iter()
+        .filter(|(_, ramsize)| **ramsize == total_memsize)
+        .map(|(model, _)| model.as_ref())
+        .collect()
+}
+
+/// config.txtを読み込んで作ったconfigをuboot向けにより細分化された状態にする関数
+/// 例: confitional filterのpi3はpi3 AとB両方を指すので、両方に設定が入るように分類する
+fn arrange_for_uboot(
+    piconfigs: &HashMap<String, Vec<Entry>>,
+    model_ramsize: &HashMap<String, usize>,
+) -> HashMap<String, Vec<Entry>> {
+    let mut ubootconfigs = merge_for_uboot(piconfigs, model_ramsize);
+
+    // 複数のfilterからgpu_memが振り分けられたモデルには、firmwareと同じく1つだけを適用する
+    for entries in ubootconfigs.values_mut() {
+        if let Some(effective) = effective_gpu_mem(entries) {
+            let mut index = 0;
+            entries.retain(|entry| {
+                let keep = index == effective || !matches!(entry.config, ConfigEntry::GpuMem(_));
+                index += 1;
+                keep
+            });
+        }
+    }
+
+    ubootconfigs
+}
+
+/// モデルに振り分けられたgpu_memのうち、firmwareが適用するもののindexを返す
+/// gpu_mem_*は書かれた順番によらずgpu_memより優先し、同じ種類の中では後に書かれたものを優先する
+/// 行番号はmergeした設定などでは記録されないので、書かれた順番はEntry::orderで比べる
+fn effective_gpu_mem(entries: &[Entry]) -> Option<usize> {
+    entries
+        .iter()
+        .enumerate()
+        .filter_map(|(index, entry)| match &entry.config {
+            ConfigEntry::GpuMem(gpumem) => {
+                Some((index, gpumem.total_ramsize.is_some(), entry.order))
+            }
+            _ => None,
+        })
+        // 同じ優先度のものは、max_by_keyが最後の要素を返すので後に追加したものになる
+        .max_by_key(|(_, specific, order)| (*specific, *order))
+        .map(|(index, _, _)| index)
+}
+
+/// arrange_for_ubootのうち、モデルごとに振り分けるところまでを行う
+/// 1つのモデルに複数のgpu_memが振り分けられた場合も、そのまま残す
+fn merge_for_uboot(
+    piconfigs: &HashMap<String, Vec<Entry>>,
+    model_ramsize: &HashMap<String, usize>,
+) -> HashMap<String, Vec<Entry>> {
+    use PiModel::*;
+
+    let mut ubootconfigs: HashMap<String, Vec<Entry>> = HashMap::new();
+    // 複数のfilterが同じモデルに当てはまる場合は、上書きせずに後ろに追加する
+    // gpu_memはモデルによって出力が異なるので、追加先のモデルを埋める
+    // gpu_mem=のように搭載メモリ量が無い場合は、変換時にモデルの搭載メモリ量を使う
+    // gpu_mem_*はfirmwareと同じく、搭載メモリ量が一致しないモデルには追加しない
+    fn push_entries(
+        ubootconfigs: &mut HashMap<String, Vec<Entry>>,
+        model_ramsize: &HashMap<String, usize>,
+        model: &str,
+        configs: &[Entry],
+    ) {
+        let entries = ubootconfigs.entry(model.to_string()).or_default();
+        entries.extend(configs.iter().filter_map(|entry| match &entry.config {
+            ConfigEntry::GpuMem(gpumem) if model != "all" => {
+                if gpumem
+                    .total_ramsize
+                    .is_some_and(|total| model_ramsize.get(model) != Some(&total))
+                {
+                    return None;
+                }
+                Some(Entry {
+                    config: ConfigEntry::GpuMem(GpuMem {
+                        model: Some(model.to_string()),
+                        ..gpumem.clone()
+                    }),
+                    ..entry.clone()
+                })
+            }
+            _ => Some(entry.clone()),
+        }));
+    }
+    fn push_models(
+        ubootconfigs: &mut HashMap<String, Vec<Entry>>,
+        model_ramsize: &HashMap<String, usize>,
+        models: &[PiModel],
+        configs: &[Entry],
+    ) {
+        for model in models {
+            push_entries(ubootconfigs, model_ramsize, model.as_board_name(), configs);
+        }
+    }
+
+    // 追加する順番が変わらないように、filter_orderの順に並べる
+    let mut filters: Vec<(&String, &Vec<Entry>)> = piconfigs.iter().collect();
+    filters.sort_by_key(|(filter, _)| filter_order(filter));
+
+    for (platform, configs) in filters {
+        // raspi bootloaderの荒い分類をu-bootのもう少し細かい分類に分け直す
+        // raspi model: https://www.raspberrypi.com/documentation/computers/config_txt.html#model-filters
+        // uboot model: https://github.com/u-boot/u-boot/blob/master/board/raspberrypi/rpi/rpi.c#L89
+        match &**platform {
+            "all" => {
+                push_entries(&mut ubootconfigs, model_ramsize, "all", configs);
+            }
+            "pi3" => push_models(
+                &mut ubootconfigs,
+                model_ramsize,
+                &[Pi3B, Pi3BPlus, Pi3APlus, CM3, CM3Plus],
+                configs,
+            ),
+            "pi3+" => push_models(
+                &mut ubootconfigs,
+                model_ramsize,
+                &[Pi3BPlus, Pi3APlus],
+                configs,
+            ),
+            "pi4" => push_models(
+                &mut ubootconfigs,
+                model_ramsize,
+                &[Pi4B, Pi400, CM4],
+                configs,
+            ),
+            "pi5" => push_models(
+                &mut ubootconfigs,
+                model_ramsize,
+                &[Pi5B, Pi500, CM5],
+                configs,
+            ),
+            "cm3" => push_models(&mut ubootconfigs, model_ramsize, &[CM3], configs),
+            "cm3+" => push_models(&mut ubootconfigs, model_ramsize, &[CM3Plus], configs),
+            "cm4" => push_models(&mut ubootconfigs, model_ramsize, &[CM4], configs),
+            "cm5" => push_models(&mut ubootconfigs, model_ramsize, &[CM5], configs),
+            "pi0" => push_models(
+                &mut ubootconfigs,
+                model_ramsize,
+                &[Zero, ZeroW, Zero2W],
+                configs,
+            ),
+            "pi0w" => push_models(&mut ubootconfigs, model_ramsize, &[ZeroW, Zero2W], configs),
+            "pi02" => push_models(&mut ubootconfigs, model_ramsize, &[Zero2W], configs),
+            "pi400" => push_models(&mut ubootconfigs, model_ramsize, &[Pi400], configs),
+            // 他のモデルに入れると別のモデルまで設定が変わってしまうので振り分けない
+            // 変換時はplatform_commandsでエラーにする
+            _ if unsupported_cm_filter(platform).is_some() => {}
+            // u-bootのモデル名がそのまま書かれている場合は、分け直さずにそのモデルに入れる
+            model if PiModel::from_board_name(model).is_some() => {
+                push_entries(&mut ubootconfigs, model_ramsize, model, configs);
+            }
+            // convert_to_uboot_configで環境変数を使って分岐する
+            _ if filter_guard(platform).is_some() => {}
+            _ => {
+                // TODO: 必要ならErrを出す？
+                info!("Unsupported platform: {}", platform);
+            }
+        }
+    }
+
+    // all以下にgpu_mem_*の設定があったら適切なmodel宛に再分類する
+    // u-bootでメモリ量に応じた条件分岐ができ無さそうなので、代わりにモデルで分類するため
+    // TODO: all以外に対応する
+    let all_configs = piconfigs.get("all").map(Vec::as_slice).unwrap_or(&[]);
+    for all_config in all_configs {
+        if let ConfigEntry::GpuMem(gpumem) = &all_config.config {
+            match gpumem.total_ramsize {
+                // gpu_mem=は搭載メモリ量がわかるモデルすべてに振り分ける
+                // gpu_mem_*との優先順位は、振り分けた後にモデルごとに決める
+                None => {
+                    for platform in &board_names() {
+                        if !model_ramsize.contains_key(*platform) {
+                            continue;
+                        }
+                        ubootconfigs
+                            .entry(platform.to_string())
+                            .or_default()
+                            .push(Entry {
+                                config: ConfigEntry::GpuMem(GpuMem {
+                                    total_ramsize: None,
+                                    gpu_ramsize: gpumem.gpu_ramsize,
+                                    model: Some(platform.to_string()),
+                                }),
+                                ..all_config.clone()
+                            });
+                    }
+                }
+                // 256MBのモデルのように該当するモデルが無い場合はどこにも振り分けない
+                // unsupported_entriesで報告する
+                Some(total_memsize) => {
+                    for platform in models_with_ramsize(model_ramsize, total_memsize) {
+                        let entry = Entry {
+                            config: ConfigEntry::GpuMem(GpuMem {
+                                total_ramsize: Some(total_memsize),
+                                gpu_ramsize: gpumem.gpu_ramsize,
+                                model: Some(platform.to_string()),
+                            }),
+                            ..all_config.clone()
+                        };
+                        match ubootconfigs.get_mut(platform) {
+                            Some(x) => x.push(entry),
+                            None => {
+                                ubootconfigs.insert(platform.to_string(), vec![entry]);
+                            }
+                        }
+                    }
+                }
+            }
+            // allからは設定を削除する
+            if let Some(x) = ubootconfigs.get_mut("all") {
+                x.retain(|y| y != all_config);
+            }
+        }
+    }
+
+    ubootconfigs
+}
+
+/// gpu_memが指定されていないモデルに、default_gpu_memの設定を追加する
+/// allにgpu_mem=のようなモデルを問わない設定がある場合は、全モデルに指定されているものとみなす
+fn apply_default_gpu_mem(configs: &mut HashMap<String, Vec<Entry>>, options: &UbootOptions) {
+    let gpu_ramsize = match options.default_gpu_mem {
+        Some(x) => x,
+        None => return,
+    };
+    let has_gpu_mem = |configs: Option<&Vec<Entry>>| -> bool {
+        configs.is_some_and(|configs| {
+            configs
+                .iter()
+                .any(|entry| matches!(entry.config, ConfigEntry::GpuMem(_)))
+        })
+    };
+    if has_gpu_mem(configs.get("all")) {
+        return;
+    }
+
+    // 追加する順番が変わらないように、PiModel::ALLの順に追加する
+    for model in &board_names() {
+        let total_ramsize = match options.model_ramsize.get(*model) {
+            Some(x) => *x,
+            None => continue,
+        };
+        if has_gpu_mem(configs.get(*model)) {
+            continue;
+        }
+        configs
+            .entry(model.to_string())
+            .or_default()
+            .push(Entry::new(
+                ConfigEntry::GpuMem(GpuMem {
+                    total_ramsize: Some(total_ramsize),
+                    gpu_ramsize,
+                    model: Some(model.to_string()),
+                }),
+                None,
+                0,
+            ));
+    }
+}
+
+/// シェルで1つの引数として渡せるように、シングルクォートで囲む
+/// 値の中のシングルクォートは、一度クォートを閉じてエスケープしてから開き直す
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// u-bootのコマンドの"で囲んだ引数として書けるようにエスケープする
+/// $は変数の展開に、;はコマンドの区切りにならないように、\を前に付ける
+fn uboot_quote_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '\\' | '"' | '$' | ';') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// .envファイルの"で囲んだ値として書けるようにエスケープする
+/// Docker Composeの.envの書式に従い、`\\`、`\"`、`\$`、`\n`、`\r`を使う
+/// https://docs.docker.com/compose/how-tos/environment-variables/variable-interpolation/#env-file-syntax
+/// 改行もエスケープするので、必ず1行になる
+fn dotenv_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' | '"' | '$' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// 結合テストから内部の変換処理を直接呼び出すためのAPI
+/// 安定したAPIではないので、testing featureを有効にした場合のみ公開する
+#[cfg(feature = "testing")]
+pub mod testing {
+    use std::collections::HashMap;
+
+    use anyhow::Result;
+
+    use crate::{ConfigEntry, RPiConfig, UbootOptions};
+
+    /// config.txtのfilterごとの設定を、u-bootのモデルごとに振り分ける
+    pub fn arrange_for_uboot(
+        piconfigs: &HashMap<String, Vec<ConfigEntry>>,
+        model_ramsize: &HashMap<String, usize>,
+    ) -> HashMap<String, Vec<ConfigEntry>> {
+        let piconfigs = RPiConfig::from(piconfigs.clone()).configs;
+        crate::arrange_for_uboot(&piconfigs, model_ramsize)
+            .into_iter()
+            .map(|(model, entries)| {
+                let configs = entries.into_iter().map(|entry| entry.config).collect();
+                (model, configs)
+            })
+            .collect()
+    }
+
+    /// 1つの設定をoptionsに従ってu-bootのコマンドに変換する
+    pub fn generate_uboot_config(
+        entry: &ConfigEntry,
+        options: &UbootOptions,
+    ) -> Result<Vec<String>> {
+        entry.generate_uboot_config(options)
+    }
+}
+
+/// platformごとに、変換元の設定1つ分ずつまとめたコマンド
+type PlatformCommands = Vec<(&'static str, Vec<Vec<String>>)>;
+
+/// モデルごとのコマンドのうち、設定のある全モデルに共通するものを取り出す
+/// 共通のコマンドと、それを実行するモデルを返す。モデルが1つだけの場合や共通のコマンドが無い場合はNone
+/// モデルごとの実行順が変わらないように、先頭から連続して一致する設定の分のみを取り出す
+/// gpu_memのように同じ設定でもモデルによってコマンドが異なるものは、設定の途中で分けずにモデルごとに残す
+fn hoist_common_commands(platforms: &mut PlatformCommands) -> Option<(Vec<PiModel>, Vec<String>)> {
+    if platforms.len() < 2 {
+        return None;
+    }
+    let first = &platforms[0].1;
+    let count = (0..first.len())
+        .take_while(|i| {
+            platforms
+                .iter()
+                .all(|(_, commands)| commands.get(*i) == Some(&first[*i]))
+        })
+        .count();
+    if count == 0 {
+        return None;
+    }
+
+    let common: Vec<String> = first[..count].concat();
+    let models = platforms
+        .iter_mut()
+        .filter_map(|(platform, commands)| {
+            commands.drain(..count);
+            PiModel::from_board_name(platform)
+        })
+        .collect();
+    Some((models, common))
+}
+
+/// 行番号は比較せず、設定の内容のみを比較する
+impl PartialEq for RPiConfig {
+    fn eq(&self, other: &Self) -> bool {
+        self.configs == other.configs
+    }
+}
+
+/// parseの結果から作る
+impl From<HashMap<String, Vec<ConfigEntry>>> for RPiConfig {
+    fn from(configs: HashMap<String, Vec<ConfigEntry>>) -> Self {
+        // filterの間で書かれた順番はわからないので、config.txtとして出力する時と同じく
+        // allを先頭にして、残りはfilterの名前順に書かれていたものとして順番を付ける
+        let mut filters: Vec<(String, Vec<ConfigEntry>)> = configs.into_iter().collect();
+        filters.sort_by(|(a, _), (b, _)| filter_order(a).cmp(&filter_order(b)));
+        let mut order = 0;
+        let configs = filters
+            .into_iter()
+            .map(|(filter, configs)| {
+                let entries = configs
+                    .into_iter()
+                    .map(|config| {
+                        order += 1;
+                        Entry::new(config, None, order)
+                    })
+                    .collect();
+                (filter, entries)
+            })
+            .collect();
+        RPiConfig {
+            configs,
+            explicit_all: false,
+        }
+    }
+}
+
+/// config.txtとして出力する
+/// allを先頭にして、残りのfilterは名前順に並べる
+impl fmt::Display for RPiConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write_config_txt(f, |_, config| config.to_string())
+    }
+}
+
+impl Default for RPiConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RPiConfig {
+    pub fn new() -> Self {
+        RPiConfig::from(HashMap::new())
+    }
+
+    /// /boot/config.txt から RasPiの設定を読み込む
+    pub fn load_from_config(src: &Path) -> Result<Self> {
+        let config = fs::read_to_string(src)
+            .with_context(|| format!("Failed to read config.txt from {}", src.display()))?;
+        Self::load_from_str(&config)
+    }
+
+    /// 複数のconfig.txtを順に読み込み、1つの設定にまとめる
+    /// 後のファイルの設定はmergeで追加する
+    /// 2つ以上のファイルを読み込んだ場合は、どのファイルの行番号か区別できないので行番号は記録しない
+    pub fn load_from_configs(srcs: &[&Path]) -> Result<Self> {
+        let mut rpiconfig = RPiConfig::new();
+        for (index, src) in srcs.iter().enumerate() {
+            let config = Self::load_from_config(src)?;
+            match index {
+                // 1つ目のファイルはそのまま使う。mergeすると行番号は記録されなくなる
+                0 => rpiconfig = config,
+                _ => rpiconfig.merge(config),
+            }
+        }
+        Ok(rpiconfig)
+    }
+
+    /// バイト列からRasPiの設定を読み込む
+    /// UTF-8として不正なバイトはU+FFFDに置き換えて読み込み、その場合は警告を出す
+    pub fn load_from_bytes(bytes: &[u8]) -> Result<Self> {
+        let config = String::from_utf8_lossy(bytes);
+        if let Cow::Owned(_) = config {
+            warn!("config.txt is not valid UTF-8; invalid bytes were replaced with U+FFFD");
+        }
+        Self::load_from_str(&config)
+    }
+
+    fn load_from_str(config: &str) -> Result<Self> {
+        let groups =
+            parse_groups(config).map_err(|err| anyhow!("Failed to parse config.txt at {}", err))?;
+        Ok(Self::from_groups(groups))
+    }
+
+    /// 行番号つきで読み込んだ設定から作る
+    fn from_groups(groups: FilterGroups) -> Self {
+        let explicit_all = groups.explicit_all();
+        Self {
+            configs: groups.into_entries(),
+            explicit_all,
+        }
+    }
+
+    /// all向けのplain commandの値を取り出す
+    /// 同じkeyが複数ある場合は後に書かれたものを優先する
+    fn all_command(&self, key: &str) -> Option<&str> {
+        last_command(self.configs.get("all")?, key)
+    }
+
+    /// filterをallを先頭にして、残りは名前順に並べて返す
+    fn sorted_filters(&self) -> Vec<&String> {
+        let mut filters: Vec<&String> = self.configs.keys().collect();
+        filters.sort_by_key(|filter| filter_order(filter));
+        filters
+    }
+
+    /// 全てのfilterの全ての設定をfで書き換える
+    /// filterはallを先頭にして名前順に、設定は書かれた順に渡す
+    pub fn visit_mut(&mut self, mut f: impl FnMut(&str, &mut ConfigEntry)) {
+        let filters: Vec<String> = self.sorted_filters().into_iter().cloned().collect();
+        for filter in filters {
+            if let Some(entries) = self.configs.get_mut(&filter) {
+                for entry in entries {
+                    f(&filter, &mut entry.config);
+                }
+            }
+        }
+    }
+
+    /// コメントを、書かれていたfilterと合わせて (filter, コメント) の組で順に返す
+    /// 行番号が記録されている場合はconfig.txtに書かれていた順に、
+    /// 記録されていない場合はallを先頭にしたfilterの名前順に並べる
+    pub fn comments(&self) -> Vec<(&str, &str)> {
+        let mut comments = Vec::new();
+        for (filter, entries) in &self.configs {
+            for (index, entry) in entries.iter().enumerate() {
+                if let ConfigEntry::Comment(comment) = &entry.config {
+                    comments.push((entry.line, filter.as_str(), index, comment.as_str()));
+                }
+            }
+        }
+        comments.sort_by_key(|(line, filter, index, _)| (*line, filter_order(filter), *index));
+        comments
+            .into_iter()
+            .map(|(_, filter, _, comment)| (filter, comment))
+            .collect()
+    }
+
+    /// 設定の種類ごとの数をfilterごとに数える
+    pub fn summary(&self) -> ConfigSummary {
+        let filters = self
+            .configs
+            .iter()
+            .filter(|(_, configs)| !configs.is_empty())
+            .map(|(filter, configs)| {
+                let mut counts = EntryCounts::default();
+                for entry in configs {
+                    counts.add(&entry.config);
+                }
+                (filter.clone(), counts)
+            })
+            .collect();
+        ConfigSummary { filters }
+    }
+
+    /// filterごとに、隣接するdtparamを1つにまとめ、隣接する全く同じdtoverlayを1つにする
+    /// 間に別の設定があるdtoverlayは、その設定との順番で意味が変わることがあるので残す
+    pub fn normalize(&mut self) {
+        self.normalize_with_options(&NormalizeOptions::default())
+    }
+
+    /// optionsに従って正規化する
+    pub fn normalize_with_options(&mut self, options: &NormalizeOptions) {
+        for configs in self.configs.values_mut() {
+            let old_configs = std::mem::take(configs);
+
+            // 行番号はまとめた設定のうち最初のものを残す
+            for mut entry in old_configs {
+                if let ConfigEntry::DTOverlay(overlay) = &mut entry.config {
+                    if options.sort_overlay_params {
+                        sort_overlay_params(overlay);
+                    }
+                }
+                if let (Some(ConfigEntry::DTparam(prev)), ConfigEntry::DTparam(dtparam)) = (
+                    configs.last_mut().map(|prev: &mut Entry| &mut prev.config),
+                    &entry.config,
+                ) {
+                    prev.configs.extend(dtparam.configs.iter().cloned());
+                    continue;
+                }
+                if matches!(entry.config, ConfigEntry::DTOverlay(_))
+                    && configs.last().map(|prev| &prev.config) == Some(&entry.config)
+                {
+                    continue;
+                }
+                configs.push(entry);
+            }
+        }
+    }
+
+    /// otherの設定をfilterごとに後ろに追加する
+    /// plain commandとgpu_memは後に書かれたものを優先するので、同じfilterにある同じ設定を置き換える
+    /// dtoverlayやdtparamなどは、そのまま後ろに追加する
+    /// どのファイルの行番号か区別できなくなるので、行番号は記録しなくなる
+    pub fn merge(&mut self, other: RPiConfig) {
+        for entry in self.configs.values_mut().flatten() {
+            entry.line = None;
+        }
+        self.explicit_all |= other.explicit_all;
+        // otherの設定はselfの全ての設定より後に書かれたものとして、順番をずらす
+        let offset = self
+            .configs
+            .values()
+            .flatten()
+            .map(|entry| entry.order)
+            .max()
+            .map_or(0, |order| order + 1);
+
+        // filterごとに別の設定に追加するので、filterの順番は結果に影響しない
+        for (filter, entries) in other.configs {
+            let configs = self.configs.entry(filter).or_default();
+            for Entry {
+                config: entry,
+                order,
+                ..
+            } in entries
+            {
+                if matches!(entry, ConfigEntry::Command(_) | ConfigEntry::GpuMem(_)) {
+                    let key = entry.diff_key();
+                    configs.retain(|x| {
+                        !(matches!(x.config, ConfigEntry::Command(_) | ConfigEntry::GpuMem(_))
+                            && x.config.diff_key() == key)
+                    });
+                }
+                configs.push(Entry::new(entry, None, offset + order));
+            }
+        }
+    }
+
+    /// otherとの差分をfilterごとに求める
+    /// コメントを含め、並び替えただけの設定は差分として扱わない
+    pub fn diff(&self, other: &RPiConfig) -> ConfigDiff {
+        let mut filters: Vec<&String> = self.configs.keys().chain(other.configs.keys()).collect();
+        filters.sort_by_key(|filter| filter_order(filter));
+        filters.dedup();
+
+        let mut diff = ConfigDiff::default();
+        for filter in filters {
+            diff.push_entries(
+                filter,
+                self.configs.get(filter).map_or(&[], Vec::as_slice),
+                other.configs.get(filter).map_or(&[], Vec::as_slice),
+            );
+        }
+        diff
+    }
+
+    /// 変換に影響しないが注意が必要な設定を検証する
+    /// 結果は助言であり、変換自体は行える
+    pub fn validate(&self) -> ValidationReport {
+        self.validate_with_options(&ValidationOptions::default())
+    }
+
+    /// optionsに従って設定を検証する
+    pub fn validate_with_options(&self, options: &ValidationOptions) -> ValidationReport {
+        let mut report = ValidationReport {
+            warnings: self.overclock_warnings(),
+            ..Default::default()
+        };
+
+        report.errors.extend(self.unsupported_cm_filter_errors());
+
+        // gpu_memとgpu_mem_*が同じモデルに当てはまる場合、firmwareと同じくgpu_mem_*が優先されることを知らせる
+        // 優先順位はモデルごとに決まるので、filterを合わせて振り分けた後に調べる
+        // 複数のモデルで同じ組み合わせになるものは、1度だけ報告する
+        // 振り分けたgpu_memにはモデルが埋められているので、config.txtに書かれていた形に戻して比べる
+        let source = |entry: &Entry| match &entry.config {
+            ConfigEntry::GpuMem(gpumem) => Entry {
+                config: gpumem.source(),
+                ..entry.clone()
+            },
+            config => Entry {
+                config: config.clone(),
+                ..entry.clone()
+            },
+        };
+        let is_gpu_mem = |entry: &Entry, specific: bool| matches!(&entry.config, ConfigEntry::GpuMem(gpumem) if gpumem.total_ramsize.is_some() == specific);
+        let mut overridden: Vec<(Entry, Entry)> = Vec::new();
+        let merged = merge_for_uboot(&self.configs, &options.model_ramsize);
+        for platform in &board_names() {
+            let entries = match merged.get(*platform) {
+                Some(entries) => entries,
+                None => continue,
+            };
+            let effective = match effective_gpu_mem(entries) {
+                Some(index) => &entries[index],
+                None => continue,
+            };
+            if !is_gpu_mem(effective, true) {
+                continue;
+            }
+            for entry in entries.iter().filter(|entry| is_gpu_mem(entry, false)) {
+                let pair = (source(effective), source(entry));
+                if !overridden.contains(&pair) {
+                    overridden.push(pair);
+                }
+            }
+        }
+        // どのfilterに書かれていたかを添えて報告する
+        let filter_of = |entry: &Entry| {
+            self.sorted_filters()
+                .into_iter()
+                .find(|filter| self.configs[*filter].contains(entry))
+                .map_or("all", String::as_str)
+        };
+        for (effective, entry) in overridden {
+            let (effective_filter, filter) = (filter_of(&effective), filter_of(&entry));
+            report.warnings.push(match effective_filter == filter {
+                true => format!(
+                    "[{}] {} takes precedence over {}",
+                    filter, effective.config, entry.config
+                ),
+                false => format!(
+                    "[{}] {} takes precedence over [{}] {}",
+                    effective_filter, effective.config, filter, entry.config
+                ),
+            });
+        }
+
+        let all: Vec<&ConfigEntry> = self
+            .configs
+            .get("all")
+            .map_or(&[][..], Vec::as_slice)
+            .iter()
+            .map(|entry| &entry.config)
+            .collect();
+
+        // モデルごとに、allの設定と合わせて同時に適用されるoverlayを調べる
+        // allだけで衝突しているものは、allの分として1度だけ報告する
+        let all_conflicts = overlay_conflicts(all.iter().copied());
+        let ubootconfigs = arrange_for_uboot(&self.configs, &options.model_ramsize);
+        for platform in ["all"].into_iter().chain(board_names()) {
+            let conflicts = match platform {
+                "all" => all_conflicts.clone(),
+                _ => match ubootconfigs.get(platform) {
+                    Some(configs) => overlay_conflicts(
+                        all.iter()
+                            .copied()
+                            .chain(configs.iter().map(|entry| &entry.config)),
+                    )
+                    .into_iter()
+                    .filter(|conflict| !all_conflicts.contains(conflict))
+                    .collect(),
+                    None => continue,
+                },
+            };
+            report.errors.extend(conflicts.into_iter().map(|(a, b)| {
+                format!(
+                    "[{}] dtoverlay={} conflicts with dtoverlay={}",
+                    platform, a, b
+                )
+            }));
+        }
+
+        // モデルごとに、振り分けられたgpu_memがARMにメモリを残すか調べる
+        // 搭載メモリ量と同じ場合も、ARMが使えるメモリが無くなるのでエラーにする
+        let model_ramsize = &options.model_ramsize;
+        for platform in &board_names() {
+            let configs = match ubootconfigs.get(*platform) {
+                Some(configs) => configs,
+                None => continue,
+            };
+            for entry in configs {
+                let gpumem = match &entry.config {
+                    ConfigEntry::GpuMem(gpumem) => gpumem,
+                    _ => continue,
+                };
+                let total_ramsize = match gpumem
+                    .total_ramsize
+                    .or_else(|| model_ramsize.get(*platform).copied())
+                {
+                    Some(x) => x,
+                    None => continue,
+                };
+                if gpumem.gpu_ramsize >= total_ramsize {
+                    report.errors.push(format!(
+                        "[{}] {} leaves none of the {}MB RAM of this model for the ARM",
+                        platform,
+                        gpumem.source(),
+                        total_ramsize
+                    ));
+                }
+            }
+        }
+
+        if options.strict_unknown_keys {
+            self.check_unknown_keys(&options.dtparam_translations, &mut report);
+        }
+        if let Some(level) = options.firmware_level {
+            self.check_firmware_level(level, &options.firmware_levels, &mut report);
+        }
+
+        report
+    }
+
+    /// plain commandのkeyを調べ、firmwareが解釈しないkeyをエラーに、
+    /// 解釈はするがu-bootの設定に変換されないkeyを警告にする
+    fn check_unknown_keys(
+        &self,
+        translations: &HashMap<String, Vec<String>>,
+        report: &mut ValidationReport,
+    ) {
+        let filters = self.sorted_filters();
+        for filter in filters {
+            for entry in &self.configs[filter] {
+                let command = match &entry.config {
+                    ConfigEntry::Command(command) => command,
+                    _ => continue,
+                };
+                if !is_known_key(&command.key) {
+                    report.errors.push(format!(
+                        "[{}] unknown config.txt key: {}",
+                        filter, command.key
+                    ));
+                } else if command.skip_reason(filter).is_some() {
+                    report.warnings.push(format!(
+                        "[{}] {} is not translated to u-boot",
+                        filter, command.key
+                    ));
+                }
+            }
+        }
+        for (filter, line, key) in self.unknown_dtparam_keys_in(translations) {
+            report.errors.push(match line {
+                Some(line) => format!("[{}] line {}: unknown dtparam key: {}", filter, line, key),
+                None => format!("[{}] unknown dtparam key: {}", filter, key),
+            });
+        }
+    }
+
+    /// 変換できないdtparamのkeyを、(filter, 行番号, key) の組で列挙する
+    /// 行番号は行単位で読み込んだ場合のみ返す
+    pub fn unknown_dtparam_keys(&self) -> Vec<(String, Option<usize>, String)> {
+        self.unknown_dtparam_keys_in(&HashMap::new())
+    }
+
+    /// optionsのdtparam_translationsにあるkeyも変換できるものとして、変換できないdtparamのkeyを列挙する
+    pub fn unknown_dtparam_keys_with_options(
+        &self,
+        options: &UbootOptions,
+    ) -> Vec<(String, Option<usize>, String)> {
+        self.unknown_dtparam_keys_in(&options.dtparam_translations)
+    }
+
+    fn unknown_dtparam_keys_in(
+        &self,
+        translations: &HashMap<String, Vec<String>>,
+    ) -> Vec<(String, Option<usize>, String)> {
+        let filters = self.sorted_filters();
+
+        let mut result = Vec::new();
+        for filter in filters {
+            for entry in &self.configs[filter] {
+                let dtparam = match &entry.config {
+                    ConfigEntry::DTparam(dtparam) => dtparam,
+                    _ => continue,
+                };
+                for c in &dtparam.configs {
+                    if !is_known_dtparam_key(&c.key, translations) {
+                        result.push((filter.clone(), entry.line, c.key.clone()));
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// overclock関連の設定の警告を、filterごとに求める
+    fn overclock_warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        for filter in self.sorted_filters() {
+            let configs = &self.configs[filter];
+            // all以外のfilterは、overclockの設定を持つ場合のみallの設定と合わせて調べる
+            if filter != "all"
+                && !OVERCLOCK_KEYS
+                    .iter()
+                    .any(|key| last_command(configs, key).is_some())
+            {
+                continue;
+            }
+            let command = |key: &str| last_command(configs, key).or_else(|| self.all_command(key));
+            warnings.extend(
+                overclock_warnings(command)
+                    .into_iter()
+                    .map(|warning| format!("[{}] {}", filter, warning)),
+            );
+        }
+        warnings
+    }
+
+    /// levelのfirmwareより後に追加されたkeyを警告にする
+    /// firmwareのリリース日だけでは対応しているか決まらないこともあるので、エラーにはしない
+    fn check_firmware_level(
+        &self,
+        level: u32,
+        firmware_levels: &HashMap<String, u32>,
+        report: &mut ValidationReport,
+    ) {
+        let filters = self.sorted_filters();
+        for filter in filters {
+            for entry in &self.configs[filter] {
+                let command = match &entry.config {
+                    ConfigEntry::Command(command) => command,
+                    _ => continue,
+                };
+                let required = match firmware_levels.get(&command.key) {
+                    Some(required) if *required > level => required,
+                    _ => continue,
+                };
+                report.warnings.push(format!(
+                    "[{}] {} may not be supported by firmware older than {} (firmware_level: {})",
+                    filter, command.key, required, level
+                ));
+            }
+        }
+    }
+
+    /// config.txtの形式で書き出す
+    /// 各設定の行は、(filter, 設定)からline_ofで作る
+    fn write_config_txt(
+        &self,
+        f: &mut impl fmt::Write,
+        line_of: impl Fn(&str, &ConfigEntry) -> String,
+    ) -> fmt::Result {
+        let filters = self.sorted_filters();
+        for filter in filters {
+            let configs = &self.configs[filter];
+            // 元のconfig.txtに[all]が無ければ、filterの無い先頭部分として出力する
+            if filter == "all" && !self.explicit_all {
+                if configs.is_empty() {
+                    continue;
+                }
+            } else {
+                writeln!(f, "[{}]", filter)?;
+            }
+            for entry in configs {
+                writeln!(f, "{}", line_of(filter, &entry.config))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// u-bootの設定に変換されない設定を理由と合わせてコメントアウトしたconfig.txtを出力する
+    /// 残った設定はすべてu-bootの設定に変換できる
+    pub fn to_config_txt_annotated(&self) -> String {
+        self.to_config_txt_annotated_with_options(&UbootOptions::default())
+    }
+
+    /// optionsに従って変換した場合に変換されない設定を、コメントアウトしたconfig.txtを出力する
+    pub fn to_config_txt_annotated_with_options(&self, options: &UbootOptions) -> String {
+        let unsupported = self.unsupported_entries_with_options(options);
+        let mut output = String::new();
+        self.write_config_txt(&mut output, |filter, config| {
+            match unsupported
+                .iter()
+                .find(|(x, entry, _)| x == filter && entry == config)
+            {
+                Some((_, _, reason)) => {
+                    format!("# [unsupported by pibootcfg] {} ({})", config, reason)
+                }
+                None => config.to_string(),
+            }
+        })
+        .expect("Failed to write config.txt to String");
+        output
+    }
+
+    /// u-bootの設定に変換されない(エラーになる、もしくは読み捨てられる)設定を
+    /// (filter, 設定, 理由) の組で列挙する
+    pub fn unsupported_entries(&self) -> Vec<(String, ConfigEntry, String)> {
+        self.unsupported_entries_with_options(&UbootOptions::default())
+    }
+
+    /// optionsに従って変換した場合に、u-bootの設定に変換されない設定を列挙する
+    pub fn unsupported_entries_with_options(
+        &self,
+        options: &UbootOptions,
+    ) -> Vec<(String, ConfigEntry, String)> {
+        let filters = self.sorted_filters();
+
+        let mut result = Vec::new();
+        for filter in filters {
+            let configs = &self.configs[filter];
+            // filterごとにu-boot向けに振り分けて、どのplatformで問題が起きるか調べる
+            let ubootconfigs = arrange_for_uboot(
+                &HashMap::from([(filter.clone(), configs.clone())]),
+                &options.model_ramsize,
+            );
+
+            for config in configs.iter().map(|entry| &entry.config) {
+                let reason = match config {
+                    ConfigEntry::Comment(_)
+                    | ConfigEntry::Disabled(_)
+                    | ConfigEntry::ConditionFilter(_) => continue,
+                    _ => {
+                        // 環境変数で判定するfilterは、全ボード向けの設定と同様に変換される
+                        let arranged: Vec<(&str, &ConfigEntry)> = match filter_guard(filter) {
+                            Some(_) => vec![("all", config)],
+                            None => ["all"]
+                                .into_iter()
+                                .chain(board_names())
+                                .filter_map(|platform| {
+                                    Some((platform, ubootconfigs.get(platform)?))
+                                })
+                                .flat_map(|(platform, x)| {
+                                    x.iter().map(move |x| (platform, &x.config))
+                                })
+                                .filter(|(_, x)| x.without_model() == *config)
+                                .collect(),
+                        };
+                        if arranged.is_empty() {
+                            match config {
+                                ConfigEntry::GpuMem(GpuMem {
+                                    total_ramsize: Some(total_ramsize),
+                                    ..
+                                }) if models_with_ramsize(
+                                    &options.model_ramsize,
+                                    *total_ramsize,
+                                )
+                                .is_empty() =>
+                                {
+                                    format!(
+                                        "{} in [{}] is not applied: no supported platform has {}MB of RAM",
+                                        config, filter, total_ramsize
+                                    )
+                                }
+                                _ => format!(
+                                    "{} in [{}] is not applied to any supported platform",
+                                    config, filter
+                                ),
+                            }
+                        } else {
+                            match arranged.iter().find_map(|(platform, x)| {
+                                match x.generate_uboot_config(options) {
+                                    Err(err) => Some(err.to_string()),
+                                    Ok(commands) if commands.is_empty() => x.skip_reason(platform),
+                                    Ok(_) => None,
+                                }
+                            }) {
+                                Some(reason) => reason,
+                                None => continue,
+                            }
+                        }
+                    }
+                };
+                result.push((filter.clone(), config.clone(), reason));
+            }
+        }
+
+        result
+    }
+
+    /// config.txtを1行ずつ読み込みながらRasPiの設定を読み込む
+    /// ファイルやソケットなど、BufReadから直接読み込む場合に使う
+    pub fn load_from_reader<R: BufRead>(mut reader: R) -> Result<Self> {
+        let mut groups = FilterGroups::new();
+        let mut line = String::new();
+        let mut line_number = 0;
+
+        loop {
+            line.clear();
+            if reader
+                .read_line(&mut line)
+                .context("Failed to read config.txt")?
+                == 0
+            {
+                break;
+            }
+            line_number += 1;
+
+            let entry = parse_numbered_line(line_number, &line)
+                .map_err(|err| anyhow!("Failed to parse config.txt at {}", err))?;
+            if let Some(entry) = entry {
+                groups.push_at(entry, line_number);
+            }
+        }
+
+        Ok(Self::from_groups(groups))
+    }
+
+    /// u-bootで扱えないCompute Module向けのfilterに、変換される設定があればエラーの文言を返す
+    /// コメントしか無い場合は変換に影響しないので許す
+    fn unsupported_cm_filter_errors(&self) -> Vec<String> {
+        let filters = self.sorted_filters();
+        filters
+            .into_iter()
+            .filter_map(|filter| {
+                let reason = unsupported_cm_filter(filter)?;
+                self.configs[filter]
+                    .iter()
+                    .any(|config| {
+                        !matches!(
+                            config.config,
+                            ConfigEntry::Comment(_) | ConfigEntry::Disabled(_)
+                        )
+                    })
+                    .then(|| format!("Unsupported filter: [{}] ({})", filter, reason))
+            })
+            .collect()
+    }
+
+    /// u-boot向けのコマンドをplatformごとに、変換元の設定1つ分ずつまとめて生成する
+    /// allを先頭にして、残りはPiModel::ALLの順に並び、コマンドが無いplatformは含まない
+    fn platform_commands(&self, options: &UbootOptions) -> Result<PlatformCommands> {
+        if let Some(err) = self.unsupported_cm_filter_errors().into_iter().next() {
+            return Err(anyhow!(err));
+        }
+        let mut configs = arrange_for_uboot(&self.configs, &options.model_ramsize);
+        apply_default_gpu_mem(&mut configs, options);
+        check_pi5_overlays(&configs)?;
+
+        let mut result = Vec::new();
+        let platforms = ["all"].into_iter().chain(board_names());
+        for platform in platforms {
+            let platform_configs = match configs.get(platform) {
+                None => continue,
+                Some(x) => x,
+            };
+
+            let tmp_commands = self.generate_entry_commands(platform_configs, options)?;
+            if !tmp_commands.is_empty() {
+                result.push((platform, tmp_commands));
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// シリアル番号などu-bootの環境変数で判定するfilterのコマンドを、filterの名前順に生成する
+    fn guard_commands(&self, options: &UbootOptions) -> Result<Vec<(FilterGuard, Vec<String>)>> {
+        let filters = self.sorted_filters();
+
+        let mut result = Vec::new();
+        for filter in filters {
+            let guard = match filter_guard(filter) {
+                None => continue,
+                Some(x) => x,
+            };
+            let tmp_commands = self.generate_commands(&self.configs[filter], options)?;
+            if !tmp_commands.is_empty() {
+                result.push((guard, tmp_commands));
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// 設定を順にu-bootのコマンドに変換する
+    fn generate_commands(&self, configs: &[Entry], options: &UbootOptions) -> Result<Vec<String>> {
+        Ok(self.generate_entry_commands(configs, options)?.concat())
+    }
+
+    /// 設定を順にu-bootのコマンドに変換し、設定1つ分ずつまとめて返す
+    /// コマンドにならない設定は含まない
+    fn generate_entry_commands(
+        &self,
+        configs: &[Entry],
+        options: &UbootOptions,
+    ) -> Result<Vec<Vec<String>>> {
+        let mut commands: Vec<Vec<String>> = Vec::new();
+
+        for Entry { config, line, .. } in configs {
+            // U-Bootで設定が必要な部分を取り出して変換する
+            // コマンドは;で結合するので、;を含むコマンドはu-bootで別のコマンドに分かれてしまう
+            // エラーになった設定がconfig.txtのどこに書かれているかわかるようにする
+            let mut entry_commands = config
+                .generate_uboot_config(options)
+                .and_then(
+                    |commands| match commands.iter().find(|command| command.contains(';')) {
+                        Some(command) => Err(anyhow!(
+                            "Generated u-boot command contains ';': {:?}",
+                            command
+                        )),
+                        None => Ok(commands),
+                    },
+                )
+                .map_err(|err| match line {
+                    Some(line) => anyhow!("line {}: {}", line, err),
+                    None => err,
+                })?;
+            if entry_commands.is_empty() {
+                continue;
+            }
+            // どの設定から生成されたコマンドかわかるように、変換元をechoする
+            // 設定の値はそのままではu-bootのコマンドとして解釈されてしまうので、エスケープする
+            if options.annotate {
+                entry_commands.insert(
+                    0,
+                    format!(
+                        "echo \"config.txt: {}\"",
+                        uboot_quote_escape(&config.to_string())
+                    ),
+                );
+            }
+            commands.push(entry_commands);
+        }
+
+        Ok(commands)
+    }
+
+    /// fdtの編集など、u-bootで実行するコマンドに変換される設定があるか
+    /// コメントや読み捨てられる設定しか無い場合はfalseになる
+    /// 変換に失敗する設定がある場合は、変換してエラーを確認できるようにtrueを返す
+    pub fn has_uboot_effect(&self) -> bool {
+        self.has_uboot_effect_with_options(&UbootOptions::default())
+    }
+
+    /// optionsに従って変換した場合に、u-bootで実行するコマンドに変換される設定があるか
+    pub fn has_uboot_effect_with_options(&self, options: &UbootOptions) -> bool {
+        match (
+            self.platform_commands(options),
+            self.guard_commands(options),
+        ) {
+            (Ok(platforms), Ok(guards)) => !platforms.is_empty() || !guards.is_empty(),
+            _ => true,
+        }
+    }
+
+    /// gpu_memの設定によってCPUとGPUに割り当てられるメモリ量(byte)を、u-bootのモデルごとに
+    /// (cpu, gpu) の組で返す
+    /// gpu_memが無いモデルや、メモリ量を計算できないモデルは含まない
+    pub fn memory_split(&self) -> BTreeMap<String, (usize, usize)> {
+        self.memory_split_with_options(&UbootOptions::default())
+    }
+
+    /// optionsに従って、モデルごとのメモリの割り当てを求める
+    pub fn memory_split_with_options(
+        &self,
+        options: &UbootOptions,
+    ) -> BTreeMap<String, (usize, usize)> {
+        let mut configs = arrange_for_uboot(&self.configs, &options.model_ramsize);
+        apply_default_gpu_mem(&mut configs, options);
+
+        let mut result = BTreeMap::new();
+        for model in &board_names() {
+            // 同じモデルに複数ある場合は、後に書かれたものがfdtに残る
+            let split = configs.get(*model).and_then(|configs| {
+                configs.iter().rev().find_map(|entry| match &entry.config {
+                    ConfigEntry::GpuMem(gpumem) => Some(gpumem.memory_split(options)),
+                    _ => None,
+                })
+            });
+            match split {
+                Some(Ok(split)) => {
+                    result.insert(model.to_string(), split);
+                }
+                Some(Err(err)) => info!("Failed to compute memory split for {}: {}", model, err),
+                None => {}
+            }
+        }
+        result
+    }
+
+    /// u-boot向けのコマンドをモデルごとに分けて出力する
+    /// 全ボード向けの(board_nameで分岐しない)コマンドは"all"に入る
+    pub fn uboot_commands_by_model(&self) -> Result<BTreeMap<String, Vec<String>>> {
+        self.uboot_commands_by_model_with_options(&UbootOptions::default())
+    }
+
+    /// optionsに従って、u-boot向けのコマンドをモデルごとに分けて出力する
+    pub fn uboot_commands_by_model_with_options(
+        &self,
+        options: &UbootOptions,
+    ) -> Result<BTreeMap<String, Vec<String>>> {
+        Ok(self
+            .platform_commands(options)?
+            .into_iter()
+            .map(|(platform, commands)| (platform.to_string(), commands.concat()))
+            .collect())
+    }
+
+    /// configsの中身を読んで u-boot 向けのconfigを出力する
+    pub fn convert_to_uboot_config(&self, envval_name: &str) -> Result<Option<String>> {
+        self.convert_to_uboot_config_with_options(envval_name, &UbootOptions::default())
+    }
+
+    /// optionsに従って u-boot 向けのconfigを出力する
+    pub fn convert_to_uboot_config_with_options(
+        &self,
+        envval_name: &str,
+        options: &UbootOptions,
+    ) -> Result<Option<String>> {
+        Ok(self.uboot_command_list(options)?.map(|commands| {
+            format!(
+                "{}={}{}",
+                envval_name,
+                commands.join(";"),
+                options.line_ending()
+            )
+        }))
+    }
+
+    /// u-bootの環境変数をfw_setenvで書き込むシェルスクリプトを出力する
+    /// uEnv.txtではなく、起動後にu-bootの環境変数を書き換える場合に使う
+    pub fn convert_to_fw_setenv_script(&self, envval_name: &str) -> Result<Option<String>> {
+        self.convert_to_fw_setenv_script_with_options(envval_name, &UbootOptions::default())
+    }
+
+    /// optionsに従って fw_setenv で書き込むシェルスクリプトを出力する
+    pub fn convert_to_fw_setenv_script_with_options(
+        &self,
+        envval_name: &str,
+        options: &UbootOptions,
+    ) -> Result<Option<String>> {
+        // \rがあるとshebangが壊れるので、crlfの指定によらずLFにする
+        Ok(self.uboot_command_list(options)?.map(|commands| {
+            format!(
+                "#!/bin/sh\nfw_setenv {} {}\n",
+                envval_name,
+                shell_quote(&commands.join(";"))
+            )
+        }))
+    }
+
+    /// u-bootの環境変数を、.envファイルの KEY="value" の1行として出力する
+    /// 値の中の${devtype}などが展開されないように、`\`、`"`、`$`と改行をエスケープする
+    pub fn convert_to_dotenv(&self, envval_name: &str) -> Result<Option<String>> {
+        self.convert_to_dotenv_with_options(envval_name, &UbootOptions::default())
+    }
+
+    /// optionsに従って .env ファイルの1行を出力する
+    pub fn convert_to_dotenv_with_options(
+        &self,
+        envval_name: &str,
+        options: &UbootOptions,
+    ) -> Result<Option<String>> {
+        Ok(self.uboot_command_list(options)?.map(|commands| {
+            format!(
+                "{}=\"{}\"\n",
+                envval_name,
+                dotenv_escape(&commands.join(";"))
+            )
+        }))
+    }
+
+    /// u-boot向けのコマンドを {"env": envval_name, "commands": [...]} のJSONで出力する
+    /// 変換する設定が無い場合、commandsは空になる
+    #[cfg(feature = "serde")]
+    pub fn convert_to_uboot_json(&self, envval_name: &str) -> Result<String> {
+        self.convert_to_uboot_json_with_options(envval_name, &UbootOptions::default())
+    }
+
+    /// optionsに従って u-boot 向けのコマンドをJSONで出力する
+    #[cfg(feature = "serde")]
+    pub fn convert_to_uboot_json_with_options(
+        &self,
+        envval_name: &str,
+        options: &UbootOptions,
+    ) -> Result<String> {
+        #[derive(serde::Serialize)]
+        struct UbootJson<'a> {
+            env: &'a str,
+            commands: Vec<String>,
+        }
+
+        let json = UbootJson {
+            env: envval_name,
+            commands: self.uboot_command_list(options)?.unwrap_or_default(),
+        };
+        serde_json::to_string(&json).context("Failed to serialize u-boot commands")
+    }
+
+    /// u-boot向けの環境変数に入れるコマンドを順に生成する
+    fn uboot_command_list(&self, options: &UbootOptions) -> Result<Option<Vec<String>>> {
+        // 空のconfig.txtや、コメントか無効にされた設定しか無い場合は何もしない
+        if self.configs.values().flatten().all(|entry| {
+            matches!(
+                entry.config,
+                ConfigEntry::Comment(_) | ConfigEntry::Disabled(_)
+            )
+        }) {
+            return Ok(None);
+        }
+
+        let mut commands: Vec<String> = Vec::new();
+
+        // device_tree_addressが指定されている場合は、firmwareがそのアドレスにfdtを置くので、そちらを編集する
+        // TODO: all以外に対応する
+        let fdt_addr = match self.all_command("device_tree_address") {
+            Some(addr) => format!("{:#x}", parse_address("device_tree_address", addr)?),
+            None => "${fdt_addr}".to_string(),
+        };
+        // 変換はできるが危険なoverclockの設定を知らせる
+        // 他の検証はoptionsによって結果が変わるので、必要な場合はvalidate_with_optionsで行う
+        for warning in self.overclock_warnings() {
+            warn!("{}", warning);
+        }
+        if let Some(dtb) = self.all_command("device_tree") {
+            warn!(
+                "custom base DTB is configured (device_tree={}), but u-boot edits the DTB at {}",
+                dtb, fdt_addr
+            );
+        }
+
+        // fdt resizeの大きさを見積もるため、先に設定のコマンドを生成する
+        let mut body: Vec<String> = Vec::new();
+        let mut growth = 0;
+        // モデルで分岐したブロックはどれか1つしか実行されないので、最も大きいものだけ数える
+        let mut guarded_growth = 0;
+        // dtoverlay or dtparamの設定を抜き出す
+        // 全ボード向けのdtoverlay or dtparam を設定する
+        let (all, mut platforms): (Vec<_>, Vec<_>) = self
+            .platform_commands(options)?
+            .into_iter()
+            .partition(|(platform, _)| *platform == "all");
+        for (_, tmp_commands) in all {
+            let mut tmp_commands = tmp_commands.concat();
+            growth += estimate_fdt_growth(&tmp_commands);
+            body.append(&mut tmp_commands);
+        }
+        // 設定のある全モデルに共通するコマンドは、それらのモデルをまとめた条件で1度だけ実行する
+        if let Some((models, mut tmp_commands)) = hoist_common_commands(&mut platforms) {
+            growth += estimate_fdt_growth(&tmp_commands);
+            body.push(format!("if {}", board_name_condition(&models)));
+            body.push("then".to_string());
+            body.append(&mut tmp_commands);
+            body.push("fi".to_string());
+        }
+        for (platform, tmp_commands) in platforms {
+            let mut tmp_commands = tmp_commands.concat();
+            if !tmp_commands.is_empty() {
+                guarded_growth = guarded_growth.max(estimate_fdt_growth(&tmp_commands));
+                body.push(format!("if test \"${{board_name}}\" = \"{}\"", platform));
+                body.push("then".to_string());
+                body.append(&mut tmp_commands);
+                body.push("fi".to_string());
+            }
+        }
+        // シリアル番号やboard-typeのfilterは、それぞれの環境変数で分岐する
+        // 複数のfilterに当てはまることがあるので、それぞれ数える
+        for (guard, mut tmp_commands) in self.guard_commands(options)? {
+            growth += estimate_fdt_growth(&tmp_commands);
+            body.push(format!("if {}", guard.condition()));
+            body.push("then".to_string());
+            body.append(&mut tmp_commands);
+            body.push("fi".to_string());
+        }
+        // TODO: VC memoryの設定を行う
+        // シリアル番号の設定を行う
+        if options.emit_revision_node {
+            let mut revision = vec![
+                guard_mknode("fdt mknode / system".to_string(), options.idempotent),
+                "fdt set /system linux,revision < ${board_revision} >".to_string(),
+            ];
+            growth += estimate_fdt_growth(&revision);
+            body.append(&mut revision);
+        }
+        // 追加のコマンドもfdtを編集することがあるので、合わせて見積もる
+        let growth = growth
+            + guarded_growth
+            + estimate_fdt_growth(&options.prologue)
+            + estimate_fdt_growth(&options.epilogue);
+        // 0x1000単位に切り上げる
+        let resize = options.fdt_resize_floor.max((growth + 0xfff) & !0xfff);
+
+        // 項目追加時に必要なので、fdtのアドレスを伸長する
+        commands.push(format!("setexpr fdt_ovaddr {} + 0x40000", fdt_addr));
+        commands.push(format!("fdt addr {}", fdt_addr));
+        commands.push(format!("fdt resize {:#x}", resize));
+        // 追加のコマンドも生成したコマンドと同じく;で結合して1行に書くので、
+        // ;や改行を含むと別のコマンドや別の変数として読まれてしまう
+        for (name, extra) in [
+            ("prologue", &options.prologue),
+            ("epilogue", &options.epilogue),
+        ] {
+            if let Some(command) = extra
+                .iter()
+                .find(|command| command.contains([';', '\n', '\r']))
+            {
+                return Err(anyhow!(
+                    "{} command contains ';' or a line break: {:?}",
+                    name,
+                    command
+                ));
+            }
+        }
+        commands.extend(options.prologue.iter().cloned());
+        commands.append(&mut body);
+        commands.extend(options.epilogue.iter().cloned());
+
+        Ok(match commands.is_empty() {
+            true => None,
+            false => Some(commands),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[allow(clippy::useless_vec)]
+    fn test_dtoverlay_uboot() {
+        let expected: Vec<(DTOverlay, Vec<String>)> = vec![
+            (
+                DTOverlay {
+                    overlay: "pi3-disable-bt".to_string(),
+                    configs: vec![],
+                },
+                vec!["load ${devtype} ${devnum}:${devpart} ${fdt_ovaddr} ${fdtdir}/overlays/pi3-disable-bt.dtbo",
+                "fdt apply ${fdt_ovaddr}",
+                ].iter_mut().map(|x| x.to_string()).collect(),
+            ),
+            (
+                DTOverlay {
+                    overlay: "pi3-disable-wifi".to_string(),
+                    configs: vec![],
+                },
+                vec!["load ${devtype} ${devnum}:${devpart} ${fdt_ovaddr} ${fdtdir}/overlays/pi3-disable-wifi.dtbo",
+                "fdt apply ${fdt_ovaddr}",
+                ].iter_mut().map(|x| x.to_string()).collect(),
+            ),
+            (
+                DTOverlay {
+                    overlay: "disable-bt".to_string(),
+                    configs: vec![],
+                },
+                vec!["load ${devtype} ${devnum}:${devpart} ${fdt_ovaddr} ${fdtdir}/overlays/disable-bt.dtbo",
+                "fdt apply ${fdt_ovaddr}",
+                ].iter_mut().map(|x| x.to_string()).collect(),
+            ),
+            (
+                DTOverlay {
+                    overlay: "dwc2".to_string(),
+                    configs: vec![Config {
+                        key: "dr_mode".to_string(),
+                        value: "host".to_string(),
+                    }],
+                },
+                vec!["load ${devtype} ${devnum}:${devpart} ${fdt_ovaddr} ${fdtdir}/overlays/dwc2.dtbo",
+                "fdt apply ${fdt_ovaddr}",
+                "fdt set usb dr_mode host",
+                ].iter_mut().map(|x| x.to_string()).collect(),
+            ),
+            (
+                DTOverlay {
+                    overlay: "i2smaster".to_string(),
+                    configs: vec![],
+                },
+                vec!["fdt set i2s status okay"].iter_mut().map(|x| x.to_string()).collect(),
+            ),
+            (
+                DTOverlay {
+                    overlay: "vc4-fkms-v3d".to_string(),
+                    configs: vec![],
+                },
+                vec!["load ${devtype} ${devnum}:${devpart} ${fdt_ovaddr} ${fdtdir}/overlays/vc4-fkms-v3d.dtbo",
+                "fdt apply ${fdt_ovaddr}",
+                ].iter_mut().map(|x| x.to_string()).collect(),
+            ),
+        ];
+
+        for tmp in expected {
+            let dtbo = tmp.0;
+            let expected = tmp.1;
+
+            let result = dtbo
+                .generate_uboot_config(&UbootOptions::default())
+                .unwrap();
+            assert_eq!(expected, result);
+        }
+    }
+
+    #[test]
+    #[allow(clippy::useless_vec)]
+    fn test_dtparam_uboot() {
+        let expected: Vec<(DTparam, Vec<String>)> = vec![
+            (
+                DTparam {
+                    configs: vec![Config {
+                        key: "act_led_trigger".to_string(),
+                        value: "default-on".to_string(),
+                    }],
+                },
+                vec!["fdt set /leds/act linux,default-trigger default-on"]
+                    .iter_mut()
+                    .map(|x| x.to_string())
+                    .collect(),
+            ),
+            (
+                DTparam {
+                    configs: vec![Config {
+                        key: "audio".to_string(),
+                        value: "on".to_string(),
+                    }],
+                },
+                vec!["fdt set /soc/audio status okay"]
+                    .iter_mut()
+                    .map(|x| x.to_string())
+                    .collect(),
+            ),
+            (
+                DTparam {
+                    configs: vec![Config {
+                        key: "i2c_arm".to_string(),
+                        value: "on".to_string(),
+                    }],
+                },
+                vec!["fdt set i2c_arm status okay"]
+                    .iter_mut()
+                    .map(|x| x.to_string())
+                    .collect(),
+            ),
+            (
+                DTparam {
+                    configs: vec![Config {
+                        key: "i2s".to_string(),
+                        value: "on".to_string(),
+                    }],
+                },
+                vec!["fdt set i2s status okay"]
+                    .iter_mut()
+                    .map(|x| x.to_string())
+                    .collect(),
+            ),
+            (
+                DTparam {
+                    configs: vec![Config {
+                        key: "pwr_led_activelow".to_string(),
+                        value: "off".to_string(),
+                    }],
+                },
+                vec!["fdt set /leds/pwr gpios < ? ? 0x00 >"]
+                    .iter_mut()
+                    .map(|x| x.to_string())
+                    .collect(),
+            ),
+            (
+                DTparam {
+                    configs: vec![Config {
+                        key: "pwr_led_activelow".to_string(),
+                        value: "on".to_string(),
+                    }],
+                },
+                vec!["fdt set /leds/pwr gpios < ? ? 0x01 >"]
+                    .iter_mut()
+                    .map(|x| x.to_string())
+                    .collect(),
+            ),
+            (
+                DTparam {
+                    configs: vec![Config {
+                        key: "pwr_led_trigger".to_string(),
+                        value: "none".to_string(),
+                    }],
+                },
+                vec!["fdt set /leds/pwr linux,default-trigger none"]
+                    .iter_mut()
+                    .map(|x| x.to_string())
+                    .collect(),
+            ),
+            (
+                DTparam {
+                    configs: vec![Config {
+                        key: "spi".to_string(),
+                        value: "on".to_string(),
+                    }],
+                },
+                vec!["fdt set spi0 status okay"]
+                    .iter_mut()
+                    .map(|x| x.to_string())
+                    .collect(),
+            ),
+            (
+                DTparam {
+                    configs: vec![Config {
+                        key: "watchdog".to_string(),
+                        value: "on".to_string(),
+                    }],
+                },
+                vec!["fdt set watchdog status okay"]
+                    .iter_mut()
+                    .map(|x| x.to_string())
+                    .collect(),
+            ),
+            (
+                DTparam {
+                    configs: vec![Config {
+                        key: "i2c_arm_baudrate".to_string(),
+                        value: "400000".to_string(),
+                    }],
+                },
+                vec!["fdt set i2c clock-frequency < 0x61a80 >"]
+                    .iter_mut()
+                    .map(|x| x.to_string())
+                    .collect(),
+            ),
+        ];
+
+        for tmp in expected {
+            let dtparam = tmp.0;
+            let expected = tmp.1;
+
+            let result = dtparam.generate_uboot_config().unwrap();
+            assert_eq!(expected, result);
+        }
+    }
+
+    #[test]
+    fn test_dtparam_uboot_i2c_arm_baudrate_range() {
+        let dtparam = |value: &str| DTparam {
+            configs: vec![Config {
+                key: "i2c_arm_baudrate".to_string(),
+                value: value.to_string(),
+            }],
+        };
+
+        let err = dtparam("0").generate_uboot_config().unwrap_err();
+        assert_eq!(
+            "i2c clock-frequency out of range: 0 (expected 1000..=1000000)",
+            err.to_string()
+        );
+
+        let err = dtparam("4294967295").generate_uboot_config().unwrap_err();
+        assert_eq!(
+            "i2c clock-frequency out of range: 4294967295 (expected 1000..=1000000)",
+            err.to_string()
+        );
+
+        let err = dtparam("fast").generate_uboot_config().unwrap_err();
+        assert!(err.to_string().contains("\"fast\""));
+
+        assert_eq!(
+            vec!["fdt set i2c clock-frequency < 0x3e8 >".to_string()],
+            dtparam("1000").generate_uboot_config().unwrap()
+        );
+        assert_eq!(
+            vec!["fdt set i2c clock-frequency < 0xf4240 >".to_string()],
+            dtparam("1000000").generate_uboot_config().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_dtparam_uboot_bare_flag() {
+        let flag = parse_line("dtparam=i2c_arm").unwrap();
+        let on = parse_line("dtparam=i2c_arm=on").unwrap();
+        assert_eq!(
+            vec!["fdt set i2c_arm status okay".to_string()],
+            flag.to_uboot_commands(None).unwrap()
+        );
+        assert_eq!(
+            on.to_uboot_commands(None).unwrap(),
+            flag.to_uboot_commands(None).unwrap()
+        );
+        // 書かれていたとおりに出力する
+        assert_eq!("dtparam=i2c_arm", flag.to_string());
+
+        let flag = parse_line("dtparam=i2c_arm,spi,audio").unwrap();
+        let on = parse_line("dtparam=i2c_arm=on,spi=on,audio=on").unwrap();
+        assert_eq!(
+            on.to_uboot_commands(None).unwrap(),
+            flag.to_uboot_commands(None).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_dtparam_uboot_led_gpio() {
+        let entry = parse_line("dtparam=act_led_gpio=16").unwrap();
+        assert_eq!(
+            vec!["fdt set /leds/act gpios < ? 0x10 ? >".to_string()],
+            entry.to_uboot_commands(None).unwrap()
+        );
+
+        // 順番を入れ替えても、それぞれのセルのみ書き換える
+        for line in [
+            "dtparam=act_led_gpio=16,act_led_activelow=on",
+            "dtparam=act_led_activelow=on,act_led_gpio=16",
+        ] {
+            let mut commands = parse_line(line).unwrap().to_uboot_commands(None).unwrap();
+            commands.sort();
+            assert_eq!(
+                vec![
+                    "fdt set /leds/act gpios < ? 0x10 ? >".to_string(),
+                    "fdt set /leds/act gpios < ? ? 0x01 >".to_string(),
+                ],
+                commands
+            );
+        }
+
+        let entry = parse_line("dtparam=pwr_led_gpio=35").unwrap();
+        assert_eq!(
+            vec!["fdt set /leds/pwr gpios < ? 0x23 ? >".to_string()],
+            entry.to_uboot_commands(None).unwrap()
+        );
+        let entry = parse_line("dtparam=act_led_gpio=58").unwrap();
+        assert_eq!(
+            "act_led_gpio out of range: 58 (expected 0..=57)",
+            entry.to_uboot_commands(None).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_dtparam_uboot_sd_overclock() {
+        let entry = parse_line("dtparam=sd_overclock=100").unwrap();
+        assert_eq!(
+            vec!["fdt set mmc0 brcm,overclock-50 < 0x64 >".to_string()],
+            entry.to_uboot_commands(None).unwrap()
+        );
+        let entry = parse_line("dtparam=sd_overclock=101").unwrap();
+        assert_eq!(
+            "sd_overclock out of range: 101 (expected 50..=100)",
+            entry.to_uboot_commands(None).unwrap_err().to_string()
+        );
+        let entry = parse_line("dtparam=sd_overclock=fast").unwrap();
+        assert!(entry.to_uboot_commands(None).is_err());
+
+        let entry = parse_line("dtparam=sd_force_pio,sd_pio_limit=2").unwrap();
+        assert_eq!(
+            vec![
+                "fdt set mmc0 brcm,force-pio".to_string(),
+                "fdt set mmc0 brcm,pio-limit < 0x2 >".to_string(),
+            ],
+            entry.to_uboot_commands(None).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_dtparam_uboot_i2c_baudrate_alias() {
+        let alias = parse_line("dtparam=i2c_baudrate=400000").unwrap();
+        let expected = parse_line("dtparam=i2c_arm_baudrate=400000").unwrap();
+        assert_eq!(
+            vec!["fdt set i2c clock-frequency < 0x61a80 >".to_string()],
+            alias.to_uboot_commands(None).unwrap()
+        );
+        assert_eq!(
+            expected.to_uboot_commands(None).unwrap(),
+            alias.to_uboot_commands(None).unwrap()
+        );
+
+        let alias = parse_line("dtparam=i2c_baudrate=0").unwrap();
+        assert_eq!(
+            "i2c clock-frequency out of range: 0 (expected 1000..=1000000)",
+            alias.to_uboot_commands(None).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_audio_firmware_settings() {
+        // audio_pwm_modeとdisable_audio_ditherはfirmwareの設定なので、u-bootでは何もしない
+        let rpiconfig =
+            RPiConfig::load_from_str("audio_pwm_mode=2\ndisable_audio_dither=1\n").unwrap();
+        assert!(!rpiconfig.has_uboot_effect());
+        assert_eq!(
+            vec![
+                "audio_pwm_mode configures the firmware analogue audio output and has no device-tree equivalent",
+                "disable_audio_dither configures the firmware analogue audio output and has no device-tree equivalent",
+            ],
+            rpiconfig
+                .unsupported_entries()
+                .into_iter()
+                .map(|(_, _, reason)| reason)
+                .collect::<Vec<_>>()
+        );
+        // dtparamとして書かれた場合は変換しない
+        let entry = parse_line("dtparam=audio=on,disable_audio_dither=1").unwrap();
+        assert_eq!(
+            "Unsupported dtparam key: disable_audio_dither is a firmware setting, not a dtparam; write disable_audio_dither on its own line",
+            entry.to_uboot_commands(None).unwrap_err().to_string()
+        );
+    }
+
+    // RPiConfig
+    #[test]
+    fn test_convert_to_uboot_config() {
+        let rpiconfig = RPiConfig::from(HashMap::from([
+            (
+                "all".to_string(),
+                vec![ConfigEntry::DTparam(DTparam {
+                    configs: vec![Config {
+                        key: "audio".to_string(),
+                        value: "on".to_string(),
+                    }],
+                })],
+            ),
+            (
+                "pi4".to_string(),
+                vec![ConfigEntry::DTOverlay(DTOverlay {
+                    overlay: "vc4-fkms-v3d".to_string(),
+                    configs: vec![],
+                })],
+            ),
+        ]));
+        let expected = vec!["setexpr fdt_ovaddr ${fdt_addr} + 0x40000",
+        "fdt addr ${fdt_addr}",
+        "fdt resize 0x2000",
+        "fdt set /soc/audio status okay",
+        "if test \"${board_name}\" = \"4 Model B\" || test \"${board_name}\" = \"400\" || test \"${board_name}\" = \"Compute Module 4\"",
+        "then",
+        "load ${devtype} ${devnum}:${devpart} ${fdt_ovaddr} ${fdtdir}/overlays/vc4-fkms-v3d.dtbo",
+        "fdt apply ${fdt_ovaddr}",
+        "fi",
+        "fdt mknode / system",
+        "fdt set /system linux,revision < ${board_revision} >"];
+        let expected = format!("bootconfig={}", expected.join(";"));
+
+        let output = rpiconfig
+            .convert_to_uboot_config("bootconfig")
+            .unwrap()
+            .unwrap();
+        assert_eq!(expected, output);
+
+        // TODO: gpu_memの設定を入れる
+
+        let rpiconfig = RPiConfig::from(HashMap::from([(
+            "all".to_string(),
+            vec![ConfigEntry::GpuMem(GpuMem {
+                total_ramsize: Some(1024),
+                gpu_ramsize: 128,
+                model: None,
+            })],
+        )]));
+        let expected = vec![
+            "setexpr fdt_ovaddr ${fdt_addr} + 0x40000",
+            "fdt addr ${fdt_addr}",
+            "fdt resize 0x2000",
+            "if test \"${board_name}\" = \"3 Model B\"",
+            "then",
+            "fdt set / memreserve < 0x38000000 0x8000000 >",
+            "fdt set /memory@0 reg < 0x00 0x38000000 >",
+            "fi",
+            "if test \"${board_name}\" = \"3 Model B+\"",
+            "then",
+            "fdt set / memreserve < 0x38000000 0x8000000 >",
+            "fdt set /memory@0 reg < 0x00 0x38000000 >",
+            "fi",
+            "if test \"${board_name}\" = \"Compute Module 3\"",
+            "then",
+            "fdt set / memreserve < 0x38000000 0x8000000 >",
+            "fdt set /memory@0 reg < 0x00 0x38000000 >",
+            "fi",
+            "if test \"${board_name}\" = \"Compute Module 3+\"",
+            "then",
+            "fdt set / memreserve < 0x38000000 0x8000000 >",
+            "fdt set /memory@0 reg < 0x00 0x38000000 >",
+            "fi",
+            "if test \"${board_name}\" = \"4 Model B\"",
+            "then",
+            "fdt set / memreserve < 0x38000000 0x8000000 >",
+            "fdt set /memory@0 reg < 0x00 0x00 0x38000000 0x00 0x40000000 0xbc000000 >",
+            "fi",
+            "if test \"${board_name}\" = \"400\"",
+            "then",
+            "fdt set / memreserve < 0x38000000 0x8000000 >",
+            "fdt set /memory@0 reg < 0x00 0x00 0x38000000 0x00 0x40000000 0xbc000000 >",
+            "fi",
+            "if test \"${board_name}\" = \"Compute Module 4\"",
+            "then",
+            "fdt set / memreserve < 0x38000000 0x8000000 >",
+            "fdt set /memory@0 reg < 0x00 0x00 0x38000000 0x00 0x40000000 0xbc000000 >",
+            "fi",
+            "fdt mknode / system",
+            "fdt set /system linux,revision < ${board_revision} >",
+        ];
+
+        let expected = format!("bootconfig={}", expected.join(";"));
+
+        let output = rpiconfig
+            .convert_to_uboot_config("bootconfig")
+            .unwrap()
+            .unwrap();
+        assert_eq!(expected, output);
+    }
+
+    #[test]
+    fn test_convert_to_uboot_config_annotate() {
+        let rpiconfig = RPiConfig::from(HashMap::from([(
+            "all".to_string(),
+            vec![
+                ConfigEntry::Comment(" audio".to_string()),
+                ConfigEntry::DTparam(DTparam {
+                    configs: vec![Config {
+                        key: "audio".to_string(),
+                        value: "on".to_string(),
+                    }],
+                }),
+            ],
+        )]));
+        let options = UbootOptions {
+            annotate: true,
+            ..Default::default()
+        };
+        let expected = [
+            "setexpr fdt_ovaddr ${fdt_addr} + 0x40000",
+            "fdt addr ${fdt_addr}",
+            "fdt resize 0x2000",
+            "echo \"config.txt: dtparam=audio=on\"",
+            "fdt set /soc/audio status okay",
+            "fdt mknode / system",
+            "fdt set /system linux,revision < ${board_revision} >",
+        ];
+        let expected = format!("bootconfig={}", expected.join(";"));
+
+        let output = rpiconfig
+            .convert_to_uboot_config_with_options("bootconfig", &options)
+            .unwrap()
+            .unwrap();
+        assert_eq!(expected, output);
+
+        // 値に含まれる"や$、;はechoの引数から外れないようにエスケープする
+        assert_eq!(
+            r#"dtoverlay=foo,a=\"\$x\;b\\"#,
+            uboot_quote_escape(r#"dtoverlay=foo,a="$x;b\"#)
+        );
+    }
+
+    #[test]
+    fn test_convert_to_uboot_config_pi5() {
+        let (_, configs) = parse(
+            r"[pi5]
+dtparam=audio=on
+",
+        )
+        .unwrap();
+        assert!(configs.contains_key("pi5"));
+
+        let rpiconfig = RPiConfig::from(configs);
+        let output = rpiconfig
+            .convert_to_uboot_config("bootconfig")
+            .unwrap()
+            .unwrap();
+        // Pi 5系の3モデルに共通するので、まとめて1度だけ実行する
+        let guard = format!(
+            "if {};then;fdt set /soc/audio status okay;fi",
+            board_name_condition(&[PiModel::Pi5B, PiModel::Pi500, PiModel::CM5])
+        );
+        assert!(output.contains(&guard), "{} is not guarded", output);
+        assert!(!output.contains("\"4 Model B\""));
+
+        let gpumem = GpuMem {
+            total_ramsize: Some(1024),
+            gpu_ramsize: 128,
+            model: Some("5 Model B".to_string()),
+        };
+        assert_eq!(
+            "gpu_mem is not yet supported on this model: \"5 Model B\" (from gpu_mem_1024=128)",
+            gpumem
+                .generate_uboot_config(&UbootOptions::default())
+                .unwrap_err()
+                .to_string()
+        );
+
+        // ノードを直接書き換えるoverlayはエラーにし、読み込むだけのものはそのまま変換する
+        let text = "[pi5]\ndtoverlay=dwc2\ndtoverlay=dwc2,dr_mode=host\n";
+        let rpiconfig = RPiConfig::load_from_reader(text.as_bytes()).unwrap();
+        let err = rpiconfig.convert_to_uboot_config("bootconfig").unwrap_err();
+        assert_eq!(
+            "line 3: dtoverlay is not yet supported on this model: \"5 Model B\" (from dtoverlay=dwc2,dr_mode=host)",
+            err.to_string()
+        );
+        let (_, configs) = parse("[pi5]\ndtoverlay=dwc2\n").unwrap();
+        let by_model = RPiConfig::from(configs).uboot_commands_by_model().unwrap();
+        assert_eq!(
+            vec![
+                "load ${devtype} ${devnum}:${devpart} ${fdt_ovaddr} ${fdtdir}/overlays/dwc2.dtbo",
+                "fdt apply ${fdt_ovaddr}"
+            ],
+            by_model["5 Model B"]
+        );
+    }
+
+    #[test]
+    fn test_convert_to_uboot_config_model_ramsize() {
+        let rpiconfig = RPiConfig::from(HashMap::from([(
+            "all".to_string(),
+            vec![ConfigEntry::GpuMem(GpuMem {
+                total_ramsize: Some(1024),
+                gpu_ramsize: 128,
+                model: None,
+            })],
+        )]));
+        let guard = "if test \"${board_name}\" = \"3 Model A+\";then;fdt set / memreserve < 0x38000000 0x8000000 >;fdt set /memory@0 reg < 0x00 0x38000000 >;fi";
+
+        // 3 Model A+は512MBとして扱われるので、gpu_mem_1024の対象にならない
+        let output = rpiconfig
+            .convert_to_uboot_config("bootconfig")
+            .unwrap()
+            .unwrap();
+        assert!(!output.contains(guard));
+
+        let mut options = UbootOptions::default();
+        options.model_ramsize.insert("3 Model A+".to_string(), 1024);
+        let output = rpiconfig
+            .convert_to_uboot_config_with_options("bootconfig", &options)
+            .unwrap()
+            .unwrap();
+        assert!(output.contains(guard));
+    }
+
+    #[test]
+    fn test_validation_options_from_uboot_options() {
+        let rpiconfig = RPiConfig::load_from_str("[pi3+]\ngpu_mem=768\n").unwrap();
+        let mut options = UbootOptions::default();
+        options.model_ramsize.insert("3 Model A+".to_string(), 1024);
+
+        // 既定では3 Model A+は512MBとして扱われるので、gpu_memが搭載メモリ量を超える
+        assert_eq!(
+            vec!["[3 Model A+] gpu_mem=768 leaves none of the 512MB RAM of this model for the ARM"],
+            rpiconfig.validate().errors
+        );
+        // 変換と同じmodel_ramsizeで検証すれば、エラーにならない
+        let report = rpiconfig.validate_with_options(&ValidationOptions::from(&options));
+        assert!(report.errors.is_empty());
+        assert!(rpiconfig
+            .convert_to_uboot_config_with_options("bootconfig", &options)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_unsupported_entries() {
+        let (_, configs) = parse(
+            r"dtparam=audio=on
+dtparam=foo=on
+enable_uart=1
+gpu_mem_256=64
+
+[pi4]
+# comment
+dtoverlay=vc4-fkms-v3d
+
+[pi2]
+dtoverlay=dwc2
+",
+        )
+        .unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+
+        let expected = vec![
+            (
+                "all".to_string(),
+                ConfigEntry::DTparam(DTparam {
+                    configs: vec![Config {
+                        key: "foo".to_string(),
+                        value: "on".to_string(),
+                    }],
+                }),
+                "Unsupported dtparam key: foo".to_string(),
+            ),
+            (
+                "all".to_string(),
+                ConfigEntry::Command(Config {
+                    key: "enable_uart".to_string(),
+                    value: "1".to_string(),
+                }),
+                "enable_uart has no u-boot translation".to_string(),
+            ),
+            (
+                "all".to_string(),
+                ConfigEntry::GpuMem(GpuMem {
+                    total_ramsize: Some(256),
+                    gpu_ramsize: 64,
+                    model: None,
+                }),
+                "gpu_mem_256=64 in [all] is not applied: no supported platform has 256MB of RAM"
+                    .to_string(),
+            ),
+            (
+                "pi2".to_string(),
+                ConfigEntry::DTOverlay(DTOverlay {
+                    overlay: "dwc2".to_string(),
+                    configs: vec![],
+                }),
+                "dtoverlay=dwc2 in [pi2] is not applied to any supported platform".to_string(),
+            ),
+        ];
+        assert_eq!(expected, rpiconfig.unsupported_entries());
+    }
+
+    #[test]
+    fn test_summary() {
+        let config = r"dtparam=audio=on
+
+[pi4]
+# Enable DRM VC4 V3D driver on top of the dispmanx display stack
+dtoverlay=vc4-fkms-v3d
+max_framebuffers=2
+
+[all]
+#dtoverlay=vc4-fkms-v3d
+enable_uart=1
+dtparam=i2c_arm=on
+dtoverlay=dwc2,dr_mode=host
+dtoverlay=spi0-1cs,cs0_pin=7,cs1_spidev=disabled
+";
+        let summary = RPiConfig::load_from_str(config).unwrap().summary();
+
+        let expected = ConfigSummary {
+            filters: BTreeMap::from([
+                (
+                    "all".to_string(),
+                    EntryCounts {
+                        overlays: 2,
+                        dtparams: 2,
+                        commands: 1,
+                        comments: 1,
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "pi4".to_string(),
+                    EntryCounts {
+                        overlays: 1,
+                        commands: 1,
+                        comments: 1,
+                        ..Default::default()
+                    },
+                ),
+            ]),
+        };
+        assert_eq!(expected, summary);
+        assert_eq!(
+            EntryCounts {
+                overlays: 3,
+                dtparams: 2,
+                commands: 2,
+                comments: 2,
+                ..Default::default()
+            },
+            summary.total()
+        );
+        assert_eq!(
+            "3 overlays, 2 dtparams, 0 gpu_mem settings across 2 sections.",
+            summary.to_string()
+        );
+
+        let summary = RPiConfig::load_from_str("gpu_mem=64\n#gpu_mem=128\n")
+            .unwrap()
+            .summary();
+        assert_eq!(
+            "0 overlays, 0 dtparams, 1 gpu_mem setting across 1 section.",
+            summary.to_string()
+        );
+        assert_eq!(1, summary.total().comments);
+        assert_eq!(
+            "0 overlays, 0 dtparams, 0 gpu_mem settings across 0 sections.",
+            RPiConfig::new().summary().to_string()
+        );
+    }
+
+    #[test]
+    fn test_comments() {
+        // parser::tests::test_parseと同じconfig.txt
+        let text = r"dtparam=audio=on
+
+[pi4]
+# Enable DRM VC4 V3D driver on top of the dispmanx display stack
+dtoverlay=vc4-fkms-v3d
+max_framebuffers=2
+
+[all]
+#dtoverlay=vc4-fkms-v3d
+enable_uart=1
+dtparam=i2c_arm=on
+dtoverlay=dwc2,dr_mode=host
+dtoverlay=spi0-1cs,cs0_pin=7,cs1_spidev=disabled
+";
+        // 行番号があるので、config.txtに書かれていた順に並ぶ
+        let rpiconfig = RPiConfig::load_from_reader(text.as_bytes()).unwrap();
+        assert_eq!(
+            vec![
+                (
+                    "pi4",
+                    " Enable DRM VC4 V3D driver on top of the dispmanx display stack"
+                ),
+                ("all", "dtoverlay=vc4-fkms-v3d"),
+            ],
+            rpiconfig.comments()
+        );
+
+        // 行番号が無い場合はallが先になる
+        let (_, configs) = parse(text).unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+        let comments = rpiconfig.comments();
+        assert_eq!(2, comments.len());
+        assert_eq!(("all", "dtoverlay=vc4-fkms-v3d"), comments[0]);
+    }
+
+    #[test]
+    fn test_load_from_reader() {
+        let text = r"dtparam=audio=on
+
+[pi4]
+# Enable DRM VC4 V3D driver on top of the dispmanx display stack
+dtoverlay=vc4-fkms-v3d
+max_framebuffers=2
+
+[all]
+#dtoverlay=vc4-fkms-v3d
+enable_uart=1
+dtparam=i2c_arm=on
+dtoverlay=dwc2,dr_mode=host
+dtoverlay=spi0-1cs,cs0_pin=7,cs1_spidev=disabled
+";
+        let (_, configs) = parse(text).unwrap();
+        assert_eq!(
+            RPiConfig::from(configs),
+            RPiConfig::load_from_reader(text.as_bytes()).unwrap()
+        );
+
+        let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/config.txt");
+        let reader = std::io::BufReader::new(fs::File::open(&src).unwrap());
+        assert_eq!(
+            RPiConfig::load_from_config(&src).unwrap(),
+            RPiConfig::load_from_reader(reader).unwrap()
+        );
+    }
+
+    /// 大きなconfig.txtを読み込む時間を、文字列全体をパースする場合と比べる
+    /// cargo test --release -- --ignored --nocapture bench_load_from_reader で実行する
+    #[test]
+    #[ignore]
+    fn bench_load_from_reader() {
+        let section = "[pi4]\n# comment\ndtoverlay=dwc2,dr_mode=host\ndtparam=i2c_arm=on,spi=on\nenable_uart=1\n[all]\ngpu_mem=64\n";
+        let text = section.repeat(64 * 1024 * 1024 / section.len() / 8);
+
+        let start = std::time::Instant::now();
+        let expected = RPiConfig::load_from_str(&text).unwrap();
+        let whole = start.elapsed();
+
+        let start = std::time::Instant::now();
+        let streamed = RPiConfig::load_from_reader(text.as_bytes()).unwrap();
+        let reader = start.elapsed();
+
+        println!(
+            "{} bytes: load_from_str {:?}, load_from_reader {:?}",
+            text.len(),
+            whole,
+            reader
+        );
+        assert_eq!(expected, streamed);
+    }
+
+    #[test]
+    fn test_convert_to_uboot_config_device_tree_address() {
+        let (_, configs) = parse(
+            r"device_tree_address=0x3000000
+dtparam=audio=on
+",
+        )
+        .unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+        let expected = [
+            "setexpr fdt_ovaddr 0x3000000 + 0x40000",
+            "fdt addr 0x3000000",
+            "fdt resize 0x2000",
+            "fdt set /soc/audio status okay",
+            "fdt mknode / system",
+            "fdt set /system linux,revision < ${board_revision} >",
+        ];
+        let expected = format!("bootconfig={}", expected.join(";"));
+        let output = rpiconfig
+            .convert_to_uboot_config("bootconfig")
+            .unwrap()
+            .unwrap();
+        assert_eq!(expected, output);
+        assert!(rpiconfig.unsupported_entries().is_empty());
+
+        let (_, configs) = parse("device_tree_address=fdt\n").unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+        assert!(rpiconfig.convert_to_uboot_config("bootconfig").is_err());
+    }
+
+    #[test]
+    fn test_convert_to_uboot_config_device_tree() {
+        let (_, configs) = parse(
+            r"device_tree=bcm2711-rpi-cm4.dtb
+dtparam=audio=on
+",
+        )
+        .unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+        let output = rpiconfig
+            .convert_to_uboot_config("bootconfig")
+            .unwrap()
+            .unwrap();
+        assert!(output.starts_with(
+            "bootconfig=setexpr fdt_ovaddr ${fdt_addr} + 0x40000;fdt addr ${fdt_addr};"
+        ));
+
+        let unsupported = rpiconfig.unsupported_entries();
+        assert_eq!(1, unsupported.len());
+        assert!(unsupported[0]
+            .2
+            .starts_with("device_tree=bcm2711-rpi-cm4.dtb selects"));
+    }
+
+    #[test]
+    fn test_max_framebuffers() {
+        let (_, configs) = parse(
+            r"[pi4]
+dtoverlay=vc4-fkms-v3d
+max_framebuffers=2
+",
+        )
+        .unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+
+        let output = rpiconfig
+            .convert_to_uboot_config("bootconfig")
+            .unwrap()
+            .unwrap();
+        assert!(!output.contains("framebuffers"));
+
+        assert_eq!(
+            vec![(
+                "pi4".to_string(),
+                ConfigEntry::Command(Config {
+                    key: "max_framebuffers".to_string(),
+                    value: "2".to_string(),
+                }),
+                "max_framebuffers is handled by the firmware framebuffer driver and has no device-tree equivalent"
+                    .to_string(),
+            )],
+            rpiconfig.unsupported_entries()
+        );
+    }
+
+    #[test]
+    fn test_convert_to_uboot_config_multiple_all() {
+        let (_, configs) = parse(
+            r"[all]
+dtparam=audio=on
+[pi4]
+dtoverlay=vc4-fkms-v3d
+[all]
+dtparam=i2c_arm=on
+[pi4]
+max_framebuffers=2
+[all]
+dtparam=spi=on
+",
+        )
+        .unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+        let output = rpiconfig
+            .convert_to_uboot_config("bootconfig")
+            .unwrap()
+            .unwrap();
+
+        let all = "fdt resize 0x2000;fdt set /soc/audio status okay;fdt set i2c_arm status okay;fdt set spi0 status okay;if test";
+        assert!(output.contains(all));
+        assert_eq!(1, output.matches("fdt set i2c_arm status okay").count());
+    }
+
+    #[test]
+    fn test_dtoverlay_i2c_rtc() {
+        // チップのノードはoverlayの__dormant__なfragmentにあるので、読み込まずに作る
+        let entry = parse_line("dtoverlay=i2c-rtc,ds3231").unwrap();
+        let expected: Vec<String> = [
+            "fdt set i2c_arm status okay",
+            "fdt mknode i2c_arm ds3231@68",
+            "fdt set i2c_arm/ds3231@68 compatible maxim,ds3231",
+            "fdt set i2c_arm/ds3231@68 reg < 0x68 >",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+        assert_eq!(expected, entry.to_uboot_commands(None).unwrap());
+
+        let entry = parse_line("dtoverlay=i2c-rtc,pcf8563,addr=0x52").unwrap();
+        let expected: Vec<String> = [
+            "fdt set i2c_arm status okay",
+            "fdt mknode i2c_arm pcf8563@51",
+            "fdt set i2c_arm/pcf8563@51 compatible nxp,pcf8563",
+            "fdt set i2c_arm/pcf8563@51 reg < 0x52 >",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+        assert_eq!(expected, entry.to_uboot_commands(None).unwrap());
+
+        // 作ったノードを変更するコマンドは、全てmknodeの後に並ぶ
+        let commands = entry.to_uboot_commands(None).unwrap();
+        let mknode = commands
+            .iter()
+            .position(|command| command.starts_with("fdt mknode "))
+            .unwrap();
+        assert!(commands[..mknode]
+            .iter()
+            .all(|command| !command.contains("pcf8563@51")));
+
+        // 何度実行してもノードが重複しないようにする
+        let options = UbootOptions {
+            idempotent: true,
+            ..Default::default()
+        };
+        assert!(entry.generate_uboot_config(&options).unwrap().contains(
+            &"fdt get size fdt_node_size i2c_arm/pcf8563@51 || fdt mknode i2c_arm pcf8563@51"
+                .to_string()
+        ));
+
+        for (line, err) in [
+            (
+                "dtoverlay=i2c-rtc,addr=0x68",
+                "dtoverlay=i2c-rtc requires a chip (e.g. ds3231)",
+            ),
+            (
+                "dtoverlay=i2c-rtc,ds3231,ds1307",
+                "dtoverlay=i2c-rtc accepts only one chip: ds1307",
+            ),
+            (
+                "dtoverlay=i2c-rtc,ds9999",
+                "Unsupported dtoverlay parameter: i2c-rtc,ds9999",
+            ),
+            (
+                "dtoverlay=i2c-rtc,ds3231,wakeup-source",
+                "Unsupported dtoverlay parameter: i2c-rtc,wakeup-source",
+            ),
+        ] {
+            let entry = parse_line(line).unwrap();
+            assert_eq!(err, entry.to_uboot_commands(None).unwrap_err().to_string());
+        }
+    }
+
+    #[test]
+    fn test_dtoverlay_uboot_check_errors() {
+        let dtbo = DTOverlay {
+            overlay: "dwc2".to_string(),
+            configs: vec![Config {
+                key: "dr_mode".to_string(),
+                value: "host".to_string(),
+            }],
+        };
+        let options = UbootOptions {
+            check_errors: true,
+            ..Default::default()
+        };
+        let expected: Vec<String> = [
+            "if load ${devtype} ${devnum}:${devpart} ${fdt_ovaddr} ${fdtdir}/overlays/dwc2.dtbo",
+            "then",
+            "fdt apply ${fdt_ovaddr}",
+            "fdt set usb dr_mode host",
+            "else",
+            "echo \"overlay dwc2 missing\"",
+            "fi",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+        assert_eq!(expected, dtbo.generate_uboot_config(&options).unwrap());
+
+        let rpiconfig = RPiConfig::from(HashMap::from([(
+            "all".to_string(),
+            vec![ConfigEntry::DTOverlay(dtbo)],
+        )]));
+        let output = rpiconfig
+            .convert_to_uboot_config_with_options("bootconfig", &options)
+            .unwrap()
+            .unwrap();
+        assert!(output.contains(&format!(
+            "fdt resize 0x2000;{};fdt mknode",
+            expected.join(";")
+        )));
+    }
+
+    #[test]
+    fn test_convert_to_uboot_config_starts_with_filter() {
+        let (_, configs) = parse(
+            r"[pi4]
+dtparam=audio=on
+",
+        )
+        .unwrap();
+        assert_eq!(Some(&vec![]), configs.get("all"));
+
+        let rpiconfig = RPiConfig::from(configs);
+        let expected = [
+            "setexpr fdt_ovaddr ${fdt_addr} + 0x40000",
+            "fdt addr ${fdt_addr}",
+            "fdt resize 0x2000",
+            "if test \"${board_name}\" = \"4 Model B\" || test \"${board_name}\" = \"400\" || test \"${board_name}\" = \"Compute Module 4\"",
+            "then",
+            "fdt set /soc/audio status okay",
+            "fi",
+            "fdt mknode / system",
+            "fdt set /system linux,revision < ${board_revision} >",
+        ];
+        let expected = format!("bootconfig={}", expected.join(";"));
+        let output = rpiconfig
+            .convert_to_uboot_config("bootconfig")
+            .unwrap()
+            .unwrap();
+        assert_eq!(expected, output);
+    }
+
+    #[test]
+    fn test_to_uboot_commands() {
+        let entry = ConfigEntry::DTparam(DTparam {
+            configs: vec![Config {
+                key: "i2c_arm".to_string(),
+                value: "on".to_string(),
+            }],
+        });
+        assert_eq!(
+            vec!["fdt set i2c_arm status okay".to_string()],
+            entry.to_uboot_commands(None).unwrap()
+        );
+
+        let entry = ConfigEntry::GpuMem(GpuMem {
+            total_ramsize: Some(1024),
+            gpu_ramsize: 128,
+            model: None,
+        });
+        assert!(entry.to_uboot_commands(None).is_err());
+        assert_eq!(
+            vec![
+                "fdt set / memreserve < 0x38000000 0x8000000 >".to_string(),
+                "fdt set /memory@0 reg < 0x00 0x38000000 >".to_string(),
+            ],
+            entry.to_uboot_commands(Some("3 Model B")).unwrap()
+        );
+
+        let entry = ConfigEntry::Comment(" comment".to_string());
+        assert!(entry.to_uboot_commands(None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_enable_gic() {
+        let (_, configs) = parse("[pi4]\nenable_gic=1\n[pi0]\nenable_gic=1\n").unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+
+        assert!(!rpiconfig.has_uboot_effect());
+
+        let entry = parse_line("enable_gic=1").unwrap();
+        assert_eq!(
+            vec![
+                (
+                    "pi0".to_string(),
+                    entry.clone(),
+                    "enable_gic only applies to Pi 4 models, not Zero".to_string()
+                ),
+                (
+                    "pi4".to_string(),
+                    entry,
+                    "enable_gic selects the interrupt controller before the firmware starts the ARM cores and has no device-tree equivalent"
+                        .to_string()
+                ),
+            ],
+            rpiconfig.unsupported_entries()
+        );
+    }
+
+    #[test]
+    fn test_hdmi_enable_4kp60() {
+        let (_, configs) = parse(
+            r"[pi4]
+hdmi_enable_4kp60=1
+[pi3]
+hdmi_enable_4kp60=1
+",
+        )
+        .unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+
+        let output = rpiconfig
+            .convert_to_uboot_config("bootconfig")
+            .unwrap()
+            .unwrap();
+        assert!(!output.contains("board_name"));
+
+        let entry = ConfigEntry::Command(Config {
+            key: "hdmi_enable_4kp60".to_string(),
+            value: "1".to_string(),
+        });
+        assert_eq!(
+            vec![
+                (
+                    "pi3".to_string(),
+                    entry.clone(),
+                    "hdmi_enable_4kp60 only applies to Pi 4 models, not 3 Model A+".to_string()
+                ),
+                (
+                    "pi4".to_string(),
+                    entry,
+                    "hdmi_enable_4kp60 raises the core clock in the firmware and has no device-tree equivalent"
+                        .to_string()
+                ),
+            ],
+            rpiconfig.unsupported_entries()
+        );
+    }
+
+    #[test]
+    fn test_convert_to_uboot_config_without_revision_node() {
+        let rpiconfig = RPiConfig::from(HashMap::from([(
+            "all".to_string(),
+            vec![ConfigEntry::DTparam(DTparam {
+                configs: vec![Config {
+                    key: "audio".to_string(),
+                    value: "on".to_string(),
+                }],
+            })],
+        )]));
+        let options = UbootOptions {
+            emit_revision_node: false,
+            ..Default::default()
+        };
+        let expected = [
+            "setexpr fdt_ovaddr ${fdt_addr} + 0x40000",
+            "fdt addr ${fdt_addr}",
+            "fdt resize 0x2000",
+            "fdt set /soc/audio status okay",
+        ];
+        let expected = format!("bootconfig={}", expected.join(";"));
+        let output = rpiconfig
+            .convert_to_uboot_config_with_options("bootconfig", &options)
+            .unwrap()
+            .unwrap();
+        assert_eq!(expected, output);
+        assert!(!output.contains("/system"));
+    }
+
+    #[test]
+    fn test_dtparam_uboot_i2s_sub_options() {
+        let dtparam = |configs: &[(&str, &str)]| DTparam {
+            configs: configs
+                .iter()
+                .map(|(key, value)| Config {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                })
+                .collect(),
+        };
+
+        assert_eq!(
+            vec!["fdt set i2s status okay".to_string()],
+            dtparam(&[("i2s", "on")]).generate_uboot_config().unwrap()
+        );
+
+        // i2sノードには再生・録音を切り替えるpropertyが無い
+        for key in ["i2s_playback", "i2s_capture", "i2s_mclk_fs"] {
+            assert_eq!(
+                format!("Unsupported i2s dtparam key: {} (supported: i2s)", key),
+                dtparam(&[("i2s", "on"), (key, "on")])
+                    .generate_uboot_config()
+                    .unwrap_err()
+                    .to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn test_uboot_commands_by_model() {
+        let rpiconfig = RPiConfig::from(HashMap::from([
+            (
+                "all".to_string(),
+                vec![ConfigEntry::DTparam(DTparam {
+                    configs: vec![Config {
+                        key: "audio".to_string(),
+                        value: "on".to_string(),
+                    }],
+                })],
+            ),
+            (
+                "pi4".to_string(),
+                vec![ConfigEntry::DTOverlay(DTOverlay {
+                    overlay: "vc4-fkms-v3d".to_string(),
+                    configs: vec![],
+                })],
+            ),
+        ]));
+        let vc4: Vec<String> = [
+            "load ${devtype} ${devnum}:${devpart} ${fdt_ovaddr} ${fdtdir}/overlays/vc4-fkms-v3d.dtbo",
+            "fdt apply ${fdt_ovaddr}",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+        let expected = BTreeMap::from([
+            (
+                "all".to_string(),
+                vec!["fdt set /soc/audio status okay".to_string()],
+            ),
+            ("4 Model B".to_string(), vc4.clone()),
+            ("400".to_string(), vc4.clone()),
+            ("Compute Module 4".to_string(), vc4),
+        ]);
+        assert_eq!(expected, rpiconfig.uboot_commands_by_model().unwrap());
+    }
+
+    #[test]
+    fn test_boot_delay_and_disable_splash() {
+        let (_, configs) = parse(
+            r"boot_delay=3
+disable_splash=1
+",
+        )
+        .unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+        let expected = [
+            "setexpr fdt_ovaddr ${fdt_addr} + 0x40000",
+            "fdt addr ${fdt_addr}",
+            "fdt resize 0x2000",
+            "setenv bootdelay 3",
+            "fdt mknode / system",
+            "fdt set /system linux,revision < ${board_revision} >",
+        ];
+        let expected = format!("bootconfig={}", expected.join(";"));
+        let output = rpiconfig
+            .convert_to_uboot_config("bootconfig")
+            .unwrap()
+            .unwrap();
+        assert_eq!(expected, output);
+
+        assert_eq!(
+            vec![(
+                "all".to_string(),
+                ConfigEntry::Command(Config {
+                    key: "disable_splash".to_string(),
+                    value: "1".to_string(),
+                }),
+                "disable_splash only affects the firmware rainbow splash screen".to_string(),
+            )],
+            rpiconfig.unsupported_entries()
+        );
+
+        let (_, configs) = parse("boot_delay=soon\n").unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+        assert!(rpiconfig.convert_to_uboot_config("bootconfig").is_err());
+    }
+
+    #[test]
+    fn test_gpumem_uboot_overflow() {
+        let gpumem = GpuMem {
+            total_ramsize: Some(usize::MAX),
+            gpu_ramsize: 256,
+            model: Some("4 Model B".to_string()),
+        };
+        assert!(gpumem
+            .generate_uboot_config(&UbootOptions::default())
+            .is_err());
+
+        let gpumem = GpuMem {
+            total_ramsize: Some(1024),
+            gpu_ramsize: usize::MAX,
+            model: Some("4 Model B".to_string()),
+        };
+        assert!(gpumem
+            .generate_uboot_config(&UbootOptions::default())
+            .is_err());
+    }
+
+    #[test]
+    fn test_validate_overclock() {
+        let config = "force_turbo=1\nover_voltage=4\narm_boost=1\n";
+        let (_, configs) = parse(config).unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+        assert_eq!(
+            rpiconfig.validate().warnings,
+            vec!["[all] force_turbo=1 with over_voltage=4 sets the permanent warranty bit"]
+        );
+        // 警告は助言なので変換はできる
+        assert!(rpiconfig.convert_to_uboot_config("bootcmd").is_ok());
+
+        let config =
+            "over_voltage=8\n[pi4]\nforce_turbo=1\nover_voltage_delta=50000\n[pi3]\narm_boost=2\n";
+        let (_, configs) = parse(config).unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+        assert_eq!(
+            rpiconfig.validate().warnings,
+            vec![
+                "[all] over_voltage=8 is limited to 6 unless force_turbo=1",
+                "[pi3] arm_boost expects 0 or 1, got \"2\"",
+                "[pi3] over_voltage=8 is limited to 6 unless force_turbo=1",
+                "[pi4] force_turbo=1 with over_voltage=8 sets the permanent warranty bit",
+                "[pi4] force_turbo=1 with over_voltage_delta=50000 sets the permanent warranty bit",
+            ]
+        );
+
+        let config = "over_voltage=2\narm_boost=1\n";
+        let (_, configs) = parse(config).unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+        assert_eq!(rpiconfig.validate(), ValidationReport::default());
+    }
+
+    #[test]
+    fn test_validate_unknown_keys() {
+        let config =
+            "boot_delay=1\nenable_uart=1\nenabel_uart=1\n[pi4]\nhdmi_group=1\narm_frq=1500\n";
+        let (_, configs) = parse(config).unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+        // 既定では調べない
+        assert_eq!(rpiconfig.validate(), ValidationReport::default());
+
+        let options = ValidationOptions {
+            strict_unknown_keys: true,
+            ..Default::default()
+        };
+        let report = rpiconfig.validate_with_options(&options);
+        assert_eq!(
+            vec![
+                "[all] unknown config.txt key: enabel_uart",
+                "[pi4] unknown config.txt key: arm_frq",
+            ],
+            report.errors
+        );
+        assert_eq!(
+            vec![
+                "[all] enable_uart is not translated to u-boot",
+                "[pi4] hdmi_group is not translated to u-boot",
+            ],
+            report.warnings
+        );
+        // binary_searchで探すので、名前順に並んでいること
+        assert!(KNOWN_COMMAND_KEYS.windows(2).all(|x| x[0] < x[1]));
+        // ドキュメントにあるkeyは、u-bootに変換しないものもunknownにしない
+        for key in [
+            "initial_turbo",
+            "arm_control",
+            "ignore_lcd",
+            "display_default_lcd",
+            "disable_touchscreen",
+            "disable_poe_fan",
+            "include",
+        ] {
+            assert!(is_known_key(key), "{}", key);
+        }
+    }
+
+    #[test]
+    fn test_validate_gpu_mem_exceeds_ram() {
+        let (_, configs) = parse("[pi0]\ngpu_mem=768\n[pi4]\ngpu_mem=768\n").unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+
+        // 1GBのPi 4には収まるが、512MBのZeroには収まらない
+        assert_eq!(
+            vec![
+                "[Zero] gpu_mem=768 leaves none of the 512MB RAM of this model for the ARM",
+                "[Zero W] gpu_mem=768 leaves none of the 512MB RAM of this model for the ARM",
+                "[Zero 2 W] gpu_mem=768 leaves none of the 512MB RAM of this model for the ARM",
+            ],
+            rpiconfig.validate().errors
+        );
+
+        // 搭載メモリ量と同じ場合もARMに残らない
+        let (_, configs) = parse("[pi0]\ngpu_mem=512\n").unwrap();
+        assert_eq!(3, RPiConfig::from(configs).validate().errors.len());
+        let (_, configs) = parse("[pi0]\ngpu_mem=511\n").unwrap();
+        assert!(RPiConfig::from(configs).validate().errors.is_empty());
+
+        // 変換と同じく、指定した搭載メモリ量で調べる
+        let mut options = ValidationOptions::default();
+        for model in ["Zero", "Zero W", "Zero 2 W"] {
+            options.model_ramsize.insert(model.to_string(), 1024);
+        }
+        assert!(rpiconfig.validate_with_options(&options).errors.is_empty());
+    }
+
+    #[test]
+    fn test_unknown_dtparam_keys() {
+        let text = "dtparam=audio=on\n[pi4]\ndtparam=spi=on,notakey=on\n";
+        let rpiconfig = RPiConfig::load_from_reader(text.as_bytes()).unwrap();
+        assert_eq!(
+            vec![("pi4".to_string(), Some(3), "notakey".to_string())],
+            rpiconfig.unknown_dtparam_keys()
+        );
+
+        let options = ValidationOptions {
+            strict_unknown_keys: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            vec!["[pi4] line 3: unknown dtparam key: notakey"],
+            rpiconfig.validate_with_options(&options).errors
+        );
+
+        // 変換できるかどうかは組み込みの変換と同じ判定になること
+        let translations = HashMap::new();
+        for key in ["spi", "act_led_gpio", "poe_fan_temp0", "poe_fan_temp3_hyst"] {
+            assert!(is_known_dtparam_key(key, &translations), "{}", key);
+        }
+        for key in ["notakey", "i2s_mclk_fs", "poe_fan_temp4", "poe_fan_temp"] {
+            assert!(!is_known_dtparam_key(key, &translations), "{}", key);
+        }
+    }
+
+    #[test]
+    fn test_validate_firmware_level() {
+        let (_, configs) = parse("camera_auto_detect=1\n[pi4]\nhdmi_enable_4kp60=1\n").unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+        // 既定では調べない
+        assert_eq!(rpiconfig.validate(), ValidationReport::default());
+
+        // firmware_levelsの日付より古いfirmwareを対象にすると警告する
+        // 日付はテスト用の値で、実際のfirmwareのリリース日ではない
+        let firmware_levels: HashMap<String, u32> = [
+            ("camera_auto_detect".to_string(), 20211001),
+            ("hdmi_enable_4kp60".to_string(), 20190601),
+        ]
+        .into_iter()
+        .collect();
+        let options = ValidationOptions {
+            firmware_level: Some(20200101),
+            firmware_levels: firmware_levels.clone(),
+            ..Default::default()
+        };
+        let report = rpiconfig.validate_with_options(&options);
+        assert_eq!(
+            vec!["[all] camera_auto_detect may not be supported by firmware older than 20211001 (firmware_level: 20200101)"],
+            report.warnings
+        );
+        assert!(report.errors.is_empty());
+
+        let options = ValidationOptions {
+            firmware_level: Some(20230101),
+            firmware_levels,
+            ..Default::default()
+        };
+        assert!(rpiconfig
+            .validate_with_options(&options)
+            .warnings
+            .is_empty());
+    }
+
+    #[test]
+    fn test_validate_overlay_conflicts() {
+        let config = "dtoverlay=disable-bt\ndtoverlay=miniuart-bt\ndtoverlay=dwc2\n";
+        let (_, configs) = parse(config).unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+        let report = rpiconfig.validate();
+        assert!(report.warnings.is_empty());
+        assert_eq!(
+            vec!["[all] dtoverlay=disable-bt conflicts with dtoverlay=miniuart-bt"],
+            report.errors
+        );
+
+        // allとfilterの設定が合わさるモデルのみ報告する
+        let config = "dtoverlay=vc4-fkms-v3d\n[pi4]\ndtoverlay=vc4-kms-v3d-pi4.dtbo\n[pi3]\ndtoverlay=dwc2\n";
+        let (_, configs) = parse(config).unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+        assert_eq!(
+            vec![
+                "[4 Model B] dtoverlay=vc4-fkms-v3d conflicts with dtoverlay=vc4-kms-v3d-pi4",
+                "[400] dtoverlay=vc4-fkms-v3d conflicts with dtoverlay=vc4-kms-v3d-pi4",
+                "[Compute Module 4] dtoverlay=vc4-fkms-v3d conflicts with dtoverlay=vc4-kms-v3d-pi4",
+            ],
+            rpiconfig.validate().errors
+        );
+
+        let config =
+            "dtoverlay=disable-bt\n[pi4]\ndtoverlay=hifiberry-dac\n[pi3]\ndtoverlay=iqaudio-dac\n";
+        let (_, configs) = parse(config).unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+        assert_eq!(rpiconfig.validate(), ValidationReport::default());
+    }
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(
+            parse_line("# comment").unwrap(),
+            ConfigEntry::Comment(" comment".to_string())
+        );
+        assert_eq!(
+            parse_line("boot_delay=1\n").unwrap(),
+            ConfigEntry::Command(Config {
+                key: "boot_delay".to_string(),
+                value: "1".to_string(),
+            })
+        );
+        assert_eq!(
+            parse_line("dtoverlay=dwc2,dr_mode=host").unwrap(),
+            ConfigEntry::DTOverlay(DTOverlay {
+                overlay: "dwc2".to_string(),
+                configs: vec![Config {
+                    key: "dr_mode".to_string(),
+                    value: "host".to_string(),
+                }],
+            })
+        );
+        assert_eq!(
+            parse_line("dtparam=audio=on").unwrap(),
+            ConfigEntry::DTparam(DTparam {
+                configs: vec![Config {
+                    key: "audio".to_string(),
+                    value: "on".to_string(),
+                }],
+            })
+        );
+        assert_eq!(
+            parse_line("gpu_mem=128").unwrap(),
+            ConfigEntry::GpuMem(GpuMem {
+                total_ramsize: None,
+                gpu_ramsize: 128,
+                model: None,
+            })
+        );
+        assert_eq!(
+            "gpu_mem_1024=256".parse::<ConfigEntry>().unwrap(),
+            ConfigEntry::GpuMem(GpuMem {
+                total_ramsize: Some(1024),
+                gpu_ramsize: 256,
+                model: None,
+            })
+        );
+
+        assert_eq!(
+            parse_line("[pi4]").unwrap_err().to_string(),
+            "[pi4] is a condition filter, not a single config entry"
+        );
+        assert!(parse_line("").is_err());
+        assert!(parse_line("gpu_mem=128K").is_err());
+    }
+
+    #[test]
+    fn test_forward_env() {
+        let config = "bootcode_delay=2\nlcd_rotate=2\n";
+        let (_, configs) = parse(config).unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+
+        let options = UbootOptions {
+            emit_revision_node: false,
+            ..Default::default()
+        };
+        let expected = [
+            "setexpr fdt_ovaddr ${fdt_addr} + 0x40000",
+            "fdt addr ${fdt_addr}",
+            "fdt resize 0x2000",
+            "setenv bootcode_delay 2",
+        ]
+        .join(";");
+        assert_eq!(
+            Some(format!("bootcmd={}", expected)),
+            rpiconfig
+                .convert_to_uboot_config_with_options("bootcmd", &options)
+                .unwrap()
+        );
+
+        let mut options = options;
+        options.forward_env.insert("lcd_rotate".to_string());
+        let expected = [
+            "setexpr fdt_ovaddr ${fdt_addr} + 0x40000",
+            "fdt addr ${fdt_addr}",
+            "fdt resize 0x2000",
+            "setenv bootcode_delay 2",
+            "setenv lcd_rotate 2",
+        ]
+        .join(";");
+        assert_eq!(
+            Some(format!("bootcmd={}", expected)),
+            rpiconfig
+                .convert_to_uboot_config_with_options("bootcmd", &options)
+                .unwrap()
+        );
+        // 引き継がれる設定はスキップされたものとして報告しない
+        assert!(rpiconfig
+            .unsupported_entries_with_options(&options)
+            .is_empty());
+
+        // kernelやcmdlineは既定では引き継がない
+        let rpiconfig = RPiConfig::load_from_str("kernel=u-boot.bin\ncmdline=a\n").unwrap();
+        let uenv = rpiconfig
+            .convert_to_uboot_config("bootcmd")
+            .unwrap()
+            .unwrap();
+        assert!(!uenv.contains("setenv"));
+
+        let config = "cmdline=a;reset\n";
+        let (_, configs) = parse(config).unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+        let mut options = UbootOptions::default();
+        options.forward_env.insert("cmdline".to_string());
+        assert!(rpiconfig
+            .convert_to_uboot_config_with_options("bootcmd", &options)
+            .is_err());
+    }
+
+    #[test]
+    fn test_diff() {
+        let base = "# base\ndtparam=audio=on\ndtoverlay=dwc2\n[pi4]\ngpu_mem=128\n";
+        let (_, configs) = parse(base).unwrap();
+        let base = RPiConfig::from(configs);
+
+        // コメントと設定の並び替えは差分にならない
+        let reordered = "dtoverlay=dwc2\n# base\ndtparam=audio=on\n[pi4]\ngpu_mem=128\n";
+        let (_, configs) = parse(reordered).unwrap();
+        assert!(base.diff(&RPiConfig::from(configs)).is_empty());
+
+        let changed = "# base\ndtparam=audio=off\ndtoverlay=dwc2\n[pi4]\ndtoverlay=disable-bt\n";
+        let (_, configs) = parse(changed).unwrap();
+        let changed = RPiConfig::from(configs);
+        let entry = |line: &str| parse_line(line).unwrap();
+        assert_eq!(
+            base.diff(&changed),
+            ConfigDiff {
+                added: vec![("pi4".to_string(), entry("dtoverlay=disable-bt"))],
+                removed: vec![("pi4".to_string(), entry("gpu_mem=128"))],
+                changed: vec![(
+                    "all".to_string(),
+                    entry("dtparam=audio=on"),
+                    entry("dtparam=audio=off")
+                )],
+            }
+        );
+    }
+
+    #[test]
+    fn test_dtoverlay_gpio() {
+        let entry = parse_line("dtoverlay=gpio,gpio=17,active_low,func=high,pull=up").unwrap();
+        let expected: Vec<String> = [
+            "fdt mknode gpio gpio17-hog",
+            "fdt set gpio/gpio17-hog gpio-hog",
+            "fdt set gpio/gpio17-hog gpios < 0x11 0x11 >",
+            "fdt set gpio/gpio17-hog output-high",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+        assert_eq!(expected, entry.to_uboot_commands(None).unwrap());
+
+        let entry = parse_line("dtoverlay=gpio,gpio=4,func=in,pull=down").unwrap();
+        let expected: Vec<String> = [
+            "fdt mknode gpio gpio4-hog",
+            "fdt set gpio/gpio4-hog gpio-hog",
+            "fdt set gpio/gpio4-hog gpios < 0x4 0x20 >",
+            "fdt set gpio/gpio4-hog input",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+        assert_eq!(expected, entry.to_uboot_commands(None).unwrap());
+
+        let err = |line: &str| {
+            parse_line(line)
+                .unwrap()
+                .to_uboot_commands(None)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            "dtoverlay=gpio requires gpio=<pin>",
+            err("dtoverlay=gpio,func=out")
+        );
+        assert_eq!(
+            "gpio out of range: 99 (expected 0..=57)",
+            err("dtoverlay=gpio,gpio=99")
+        );
+        assert_eq!(
+            "Unsupported gpio overlay option: pull=sideways",
+            err("dtoverlay=gpio,gpio=4,pull=sideways")
+        );
+        assert_eq!(
+            "Unsupported dtoverlay parameter: gpio,drive=8",
+            err("dtoverlay=gpio,gpio=4,drive=8")
+        );
+    }
+
+    #[test]
+    fn test_load_from_bytes() {
+        let bytes = b"# \xff\xfe broken\ndtparam=audio=on\n";
+        let rpiconfig = RPiConfig::load_from_bytes(bytes).unwrap();
+        let expected = HashMap::from([(
+            "all".to_string(),
+            vec![
+                ConfigEntry::Comment(" \u{fffd}\u{fffd} broken".to_string()),
+                parse_line("dtparam=audio=on").unwrap(),
+            ],
+        )]);
+        assert_eq!(RPiConfig::from(expected), rpiconfig);
+
+        let rpiconfig = RPiConfig::load_from_bytes(b"dtparam=audio=on\n").unwrap();
+        assert_eq!(
+            RPiConfig::load_from_reader("dtparam=audio=on\n".as_bytes()).unwrap(),
+            rpiconfig
+        );
+    }
+
+    #[test]
+    fn test_hoist_common_overlays() {
+        let config = "[pi0]\ndtoverlay=dwc2\n[pi3]\ndtoverlay=dwc2\n[pi4]\ndtoverlay=dwc2\ndtparam=audio=on\n[pi5]\ndtoverlay=dwc2\n";
+        let (_, configs) = parse(config).unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+
+        let options = UbootOptions {
+            emit_revision_node: false,
+            ..Default::default()
+        };
+        let expected = [
+            "setexpr fdt_ovaddr ${fdt_addr} + 0x40000",
+            "fdt addr ${fdt_addr}",
+            "fdt resize 0x2000",
+            &format!("if {}", board_name_condition(&PiModel::ALL)),
+            "then",
+            "load ${devtype} ${devnum}:${devpart} ${fdt_ovaddr} ${fdtdir}/overlays/dwc2.dtbo",
+            "fdt apply ${fdt_ovaddr}",
+            "fi",
+            "if test \"${board_name}\" = \"4 Model B\"",
+            "then",
+            "fdt set /soc/audio status okay",
+            "fi",
+            "if test \"${board_name}\" = \"400\"",
+            "then",
+            "fdt set /soc/audio status okay",
+            "fi",
+            "if test \"${board_name}\" = \"Compute Module 4\"",
+            "then",
+            "fdt set /soc/audio status okay",
+            "fi",
+        ]
+        .join(";");
+        assert_eq!(
+            Some(format!("bootcmd={}", expected)),
+            rpiconfig
+                .convert_to_uboot_config_with_options("bootcmd", &options)
+                .unwrap()
+        );
+
+        // モデルごとの出力ではまとめない
+        let by_model = rpiconfig.uboot_commands_by_model().unwrap();
+        assert_eq!(14, by_model.len());
+        assert_eq!(
+            vec![
+                "load ${devtype} ${devnum}:${devpart} ${fdt_ovaddr} ${fdtdir}/overlays/dwc2.dtbo",
+                "fdt apply ${fdt_ovaddr}",
+            ],
+            by_model["Zero"]
+        );
+
+        // 一部のモデルにしか無い場合も、設定のあるモデルだけを条件にしてまとめる
+        let config = "[pi3]\ndtoverlay=dwc2\n[pi4]\ndtoverlay=dwc2\n[pi3]\ndtparam=audio=on\n";
+        let rpiconfig = RPiConfig::load_from_str(config).unwrap();
+        let models = [
+            PiModel::Pi3APlus,
+            PiModel::Pi3B,
+            PiModel::Pi3BPlus,
+            PiModel::CM3,
+            PiModel::CM3Plus,
+            PiModel::Pi4B,
+            PiModel::Pi400,
+            PiModel::CM4,
+        ];
+        let mut expected = vec![
+            "setexpr fdt_ovaddr ${fdt_addr} + 0x40000".to_string(),
+            "fdt addr ${fdt_addr}".to_string(),
+            "fdt resize 0x2000".to_string(),
+            format!("if {}", board_name_condition(&models)),
+            "then".to_string(),
+            "load ${devtype} ${devnum}:${devpart} ${fdt_ovaddr} ${fdtdir}/overlays/dwc2.dtbo"
+                .to_string(),
+            "fdt apply ${fdt_ovaddr}".to_string(),
+            "fi".to_string(),
+        ];
+        for model in &models[..5] {
+            expected.extend([
+                format!(
+                    "if test \"${{board_name}}\" = \"{}\"",
+                    model.as_board_name()
+                ),
+                "then".to_string(),
+                "fdt set /soc/audio status okay".to_string(),
+                "fi".to_string(),
+            ]);
+        }
+        assert_eq!(
+            Some(format!("bootcmd={}", expected.join(";"))),
+            rpiconfig
+                .convert_to_uboot_config_with_options("bootcmd", &options)
+                .unwrap()
+        );
+
+        // 設定のあるモデルが1つだけの場合はまとめない
+        let rpiconfig = RPiConfig::load_from_str("[cm4]\ndtoverlay=dwc2\n").unwrap();
+        let script = rpiconfig
+            .convert_to_uboot_config_with_options("bootcmd", &options)
+            .unwrap()
+            .unwrap();
+        assert!(script.contains("if test \"${board_name}\" = \"Compute Module 4\";then;load"));
+    }
+
+    #[test]
+    fn test_gpumem_512_only() {
+        let config = "gpu_mem_512=128\n";
+        let (_, configs) = parse(config).unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+
+        let expected: Vec<String> = [
+            "fdt set / memreserve < 0x18000000 0x8000000 >",
+            "fdt set /memory@0 reg < 0x00 0x18000000 >",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+        let by_model = rpiconfig.uboot_commands_by_model().unwrap();
+        assert_eq!(
+            vec!["3 Model A+", "Zero", "Zero 2 W", "Zero W"],
+            by_model.keys().collect::<Vec<_>>()
+        );
+        for commands in by_model.values() {
+            assert_eq!(&expected, commands);
+        }
+    }
+
+    #[test]
+    fn test_hdmi_timings() {
+        let line = "hdmi_timings=800 0 40 48 88 480 0 13 3 32 0 0 0 60 0 32000000 6";
+        let entry = parse_line(line).unwrap();
+        assert_eq!(
+            ConfigEntry::HdmiTimings(vec![
+                800, 0, 40, 48, 88, 480, 0, 13, 3, 32, 0, 0, 0, 60, 0, 32000000, 6
+            ]),
+            entry
+        );
+        assert_eq!(line, entry.to_string());
+
+        let (_, configs) = parse(&format!("{}\n", line)).unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+        assert_eq!(
+            vec![(
+                "all".to_string(),
+                entry,
+                "hdmi_timings defines a custom HDMI mode for the firmware and has no device-tree equivalent"
+                    .to_string()
+            )],
+            rpiconfig.unsupported_entries()
+        );
+    }
+
+    #[test]
+    fn test_hdmi_cvt() {
+        let line = "hdmi_cvt=800 480 60 6 0 0 0";
+        let entry = parse_line(line).unwrap();
+        assert_eq!(line, entry.to_string());
+
+        let (_, configs) = parse(&format!("{}\n", line)).unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+        assert!(!rpiconfig.has_uboot_effect());
+        assert_eq!(
+            vec![(
+                "all".to_string(),
+                entry,
+                "hdmi_cvt defines a custom HDMI mode for the firmware and has no device-tree equivalent"
+                    .to_string()
+            )],
+            rpiconfig.unsupported_entries()
+        );
+    }
+
+    #[test]
+    fn test_camera_auto_detect() {
+        let entry = parse_line("camera_auto_detect=1").unwrap();
+        assert_eq!(
+            vec!["fdt set csi1 status okay".to_string()],
+            entry.to_uboot_commands(None).unwrap()
+        );
+        let entry = parse_line("camera_auto_detect=0").unwrap();
+        assert!(entry.to_uboot_commands(None).unwrap().is_empty());
+        let entry = parse_line("camera_auto_detect=yes").unwrap();
+        assert_eq!(
+            "Invalid camera_auto_detect: \"yes\"",
+            entry.to_uboot_commands(None).unwrap_err().to_string()
+        );
+
+        let (_, configs) = parse("camera_auto_detect=1\nstart_x=1\n").unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+        assert_eq!(
+            vec![(
+                "all".to_string(),
+                parse_line("start_x=1").unwrap(),
+                "start_x only selects the legacy camera firmware (start_x.elf) and has no device-tree equivalent"
+                    .to_string()
+            )],
+            rpiconfig.unsupported_entries()
+        );
+    }
+
+    #[test]
+    fn test_convert_error_line_number() {
+        let text = "# comment\ndtparam=audio=on\n\n[pi4]\ndtparam=notakey=on\n";
+        let expected = "line 5: Unsupported dtparam key: notakey";
+
+        let rpiconfig = RPiConfig::load_from_reader(text.as_bytes()).unwrap();
+        let err = rpiconfig.convert_to_uboot_config("bootcmd").unwrap_err();
+        assert_eq!(expected, err.to_string());
+
+        let rpiconfig = RPiConfig::load_from_bytes(text.as_bytes()).unwrap();
+        let err = rpiconfig.convert_to_uboot_config("bootcmd").unwrap_err();
+        assert_eq!(expected, err.to_string());
+
+        // 行番号が無い場合はそのまま
+        let (_, configs) = parse(text).unwrap();
+        let err = RPiConfig::from(configs)
+            .convert_to_uboot_config("bootcmd")
+            .unwrap_err();
+        assert_eq!("Unsupported dtparam key: notakey", err.to_string());
+
+        // 同じ設定が複数回書かれていても、それぞれの行番号を報告する
+        let text = "[pi4]\ndtparam=notakey=on\n[pi3]\ndtparam=notakey=on\n";
+        let rpiconfig = RPiConfig::load_from_reader(text.as_bytes()).unwrap();
+        let err = rpiconfig.convert_to_uboot_config("bootcmd").unwrap_err();
+        assert_eq!("line 4: Unsupported dtparam key: notakey", err.to_string());
+    }
+
+    #[test]
+    fn test_enable_tvout() {
+        let entry = parse_line("enable_tvout=1").unwrap();
+        let expected: Vec<String> = [
+            "if test \"${board_name}\" = \"4 Model B\" || test \"${board_name}\" = \"Compute Module 4\"",
+            "then",
+            "fdt set /soc/vec@7ec13000 status okay",
+            "fi",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+        assert_eq!(expected, entry.to_uboot_commands(None).unwrap());
+        let entry = parse_line("enable_tvout=0").unwrap();
+        assert!(entry.to_uboot_commands(None).unwrap().is_empty());
+
+        let (_, configs) = parse("enable_tvout=1\nsdtv_mode=2\n").unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+        assert_eq!(
+            vec![(
+                "all".to_string(),
+                parse_line("sdtv_mode=2").unwrap(),
+                "sdtv_mode configures the firmware composite video encoder and has no device-tree equivalent"
+                    .to_string()
+            )],
+            rpiconfig.unsupported_entries()
+        );
+    }
+
+    #[test]
+    fn test_semantically_eq() {
+        let a = parse_line("# Enable  audio").unwrap();
+        let b = parse_line("#Enable audio ").unwrap();
+        assert_ne!(a, b);
+        assert!(a.semantically_eq(&b));
+        assert!(!a.semantically_eq(&parse_line("# Enable video").unwrap()));
+
+        let a = parse_line("dtparam=i2c_arm=on,spi=on").unwrap();
+        let b = parse_line("dtparam=spi=on,i2c_arm=on").unwrap();
+        assert_ne!(a, b);
+        assert!(a.semantically_eq(&b));
+        assert!(!a.semantically_eq(&parse_line("dtparam=spi=on,i2c_arm=off").unwrap()));
+
+        let a = parse_line("dtoverlay=spi0-1cs,cs0_pin=7,cs1_spidev=disabled").unwrap();
+        let b = parse_line("dtoverlay=spi0-1cs,cs1_spidev=disabled,cs0_pin=7").unwrap();
+        assert!(a.semantically_eq(&b));
+        assert!(!a.semantically_eq(
+            &parse_line("dtoverlay=spi0-2cs,cs0_pin=7,cs1_spidev=disabled").unwrap()
+        ));
+
+        // diffも意味の上で同じ設定は差分にしない
+        let (_, configs) = parse("# Enable  audio\ndtparam=i2c_arm=on,spi=on\n").unwrap();
+        let base = RPiConfig::from(configs);
+        let (_, configs) = parse("#Enable audio\ndtparam=spi=on,i2c_arm=on\n").unwrap();
+        assert!(base.diff(&RPiConfig::from(configs)).is_empty());
+    }
+
+    #[test]
+    fn test_write_atomically() {
+        let dir = std::env::temp_dir().join(format!("pibootcfg-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dest = dir.join("uEnv.txt");
+
+        fs::write(&dest, "bootcfg=old").unwrap();
+        write_atomically(&dest, b"bootcfg=new").unwrap();
+        assert_eq!("bootcfg=new", fs::read_to_string(&dest).unwrap());
+        assert!(!dir.join(".uEnv.txt.tmp").exists());
+
+        // 置き換えに失敗した場合は、元の内容が残り一時ファイルも消える
+        let blocked = dir.join("blocked");
+        fs::create_dir_all(blocked.join("child")).unwrap();
+        assert!(write_atomically(&blocked, b"bootcfg=new").is_err());
+        assert!(blocked.join("child").is_dir());
+        assert!(!dir.join(".blocked.tmp").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_serial_and_board_type_filters() {
+        let config =
+            "dtparam=audio=on\n[0x12345678]\ndtparam=spi=on\n[board-type=0x11]\ndtoverlay=dwc2\n";
+        let (_, configs) = parse(config).unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+
+        let options = UbootOptions {
+            emit_revision_node: false,
+            ..Default::default()
+        };
+        let expected = [
+            "setexpr fdt_ovaddr ${fdt_addr} + 0x40000",
+            "fdt addr ${fdt_addr}",
+            "fdt resize 0x2000",
+            "fdt set /soc/audio status okay",
+            "if test \"${serial#}\" = \"0000000012345678\"",
+            "then",
+            "fdt set spi0 status okay",
+            "fi",
+            "if test \"${board_rev}\" = \"17\"",
+            "then",
+            "load ${devtype} ${devnum}:${devpart} ${fdt_ovaddr} ${fdtdir}/overlays/dwc2.dtbo",
+            "fdt apply ${fdt_ovaddr}",
+            "fi",
+        ]
+        .join(";");
+        assert_eq!(
+            Some(format!("bootcmd={}", expected)),
+            rpiconfig
+                .convert_to_uboot_config_with_options("bootcmd", &options)
+                .unwrap()
+        );
+        assert!(rpiconfig.unsupported_entries().is_empty());
+
+        // 判定できないfilterは読み捨てられたものとして報告する
+        let (_, configs) = parse("[board-type=pi]\ndtparam=spi=on\n").unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+        assert_eq!(
+            vec![(
+                "board-type=pi".to_string(),
+                parse_line("dtparam=spi=on").unwrap(),
+                "dtparam=spi=on in [board-type=pi] is not applied to any supported platform"
+                    .to_string()
+            )],
+            rpiconfig.unsupported_entries()
+        );
+    }
+
+    #[test]
+    fn test_normalize() {
+        let text = "dtparam=audio=on\ndtparam=i2c_arm=on\ndtparam=spi=on\ndtoverlay=dwc2\ndtoverlay=dwc2\ndtparam=bogus=on\n[pi4]\ndtoverlay=dwc2\n";
+        let mut rpiconfig = RPiConfig::load_from_reader(text.as_bytes()).unwrap();
+        rpiconfig.normalize();
+
+        let expected = "dtparam=audio=on,i2c_arm=on,spi=on\ndtoverlay=dwc2\ndtparam=bogus=on\n[pi4]\ndtoverlay=dwc2\n";
+        let (_, configs) = parse(expected).unwrap();
+        assert_eq!(RPiConfig::from(configs), rpiconfig);
+
+        // まとめた後も行番号は元の行を指す
+        let err = rpiconfig.convert_to_uboot_config("bootcmd").unwrap_err();
+        assert_eq!("line 6: Unsupported dtparam key: bogus", err.to_string());
+
+        // 隣接していないdtoverlayや、パラメータが異なるdtoverlayはそのまま残す
+        let text = "dtoverlay=dwc2
+dtoverlay=disable-bt
+dtoverlay=dwc2
+dtoverlay=dwc2,dr_mode=host
+";
+        let (_, configs) = parse(text).unwrap();
+        let mut rpiconfig = RPiConfig::from(configs);
+        rpiconfig.normalize();
+        assert_eq!(text, rpiconfig.to_string());
+    }
+
+    #[test]
+    fn test_normalize_sort_overlay_params() {
+        let options = NormalizeOptions {
+            sort_overlay_params: true,
+        };
+        let (_, configs) = parse("dtoverlay=spi0-1cs,cs1_spidev=disabled,cs0_pin=7\n").unwrap();
+        let mut a = RPiConfig::from(configs);
+        let (_, configs) = parse("dtoverlay=spi0-1cs,cs0_pin=7,cs1_spidev=disabled\n").unwrap();
+        let mut b = RPiConfig::from(configs);
+        assert_ne!(a, b);
+        a.normalize_with_options(&options);
+        b.normalize_with_options(&options);
+        assert_eq!(a, b);
+        assert_eq!(
+            "dtoverlay=spi0-1cs,cs0_pin=7,cs1_spidev=disabled\n",
+            a.to_string()
+        );
+
+        // 順番で意味が変わるものは並べ替えない
+        let text = "dtoverlay=vc4-kms-v3d,cma-256,cma-128\ndtoverlay=gpio-fan,temp=60000,gpiopin=14,temp=55000\n";
+        let (_, configs) = parse(text).unwrap();
+        let mut rpiconfig = RPiConfig::from(configs);
+        rpiconfig.normalize_with_options(&options);
+        assert_eq!(text, rpiconfig.to_string());
+
+        // オプションが無い場合は並べ替えない
+        let (_, configs) = parse("dtoverlay=spi0-1cs,cs1_spidev=disabled,cs0_pin=7\n").unwrap();
+        let mut rpiconfig = RPiConfig::from(configs);
+        rpiconfig.normalize();
+        assert_eq!(
+            "dtoverlay=spi0-1cs,cs1_spidev=disabled,cs0_pin=7\n",
+            rpiconfig.to_string()
+        );
+    }
+
+    #[test]
+    fn test_dtparam_cm4_antenna() {
+        let entry = parse_line("dtparam=ant2").unwrap();
+        let expected: Vec<String> = [
+            "if test \"${board_name}\" = \"Compute Module 4\"",
+            "then",
+            "fdt rm /soc/firmware/gpio/ant1 output-high",
+            "fdt set /soc/firmware/gpio/ant1 output-low",
+            "fdt rm /soc/firmware/gpio/ant2 output-low",
+            "fdt set /soc/firmware/gpio/ant2 output-high",
+            "fi",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+        assert_eq!(expected, entry.to_uboot_commands(None).unwrap());
+
+        let entry = parse_line("dtparam=noant=on").unwrap();
+        let commands = entry.to_uboot_commands(None).unwrap();
+        assert_eq!("fdt set /soc/firmware/gpio/ant1 output-low", commands[3]);
+        assert_eq!("fdt set /soc/firmware/gpio/ant2 output-low", commands[5]);
+
+        let entry = parse_line("dtparam=ant1=off").unwrap();
+        assert_eq!(
+            "Unsupported dtparam option: ant1=off",
+            entry.to_uboot_commands(None).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_gpumem_fixed_width_hex() {
+        let options = UbootOptions {
+            fixed_width_hex: true,
+            ..Default::default()
+        };
+        let gpumem = GpuMem {
+            total_ramsize: Some(1024),
+            gpu_ramsize: 128,
+            model: Some("4 Model B".to_string()),
+        };
+        let expected: Vec<String> = [
+            "fdt set / memreserve < 0x38000000 0x08000000 >",
+            "fdt set /memory@0 reg < 0x00000000 0x00000000 0x38000000 0x00000000 0x40000000 0xbc000000 >",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+        assert_eq!(expected, gpumem.generate_uboot_config(&options).unwrap());
+
+        let gpumem = GpuMem {
+            total_ramsize: Some(512),
+            gpu_ramsize: 128,
+            model: Some("Zero".to_string()),
+        };
+        let expected: Vec<String> = [
+            "fdt set / memreserve < 0x18000000 0x08000000 >",
+            "fdt set /memory@0 reg < 0x00000000 0x18000000 >",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+        assert_eq!(expected, gpumem.generate_uboot_config(&options).unwrap());
+    }
+
+    #[test]
+    fn test_pi02_filter() {
+        let (_, configs) = parse("[pi02]\ndtoverlay=dwc2\n[pi0w]\ndtparam=audio=on\n").unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+
+        let by_model = rpiconfig.uboot_commands_by_model().unwrap();
+        assert_eq!(
+            vec!["Zero 2 W", "Zero W"],
+            by_model.keys().collect::<Vec<_>>()
+        );
+        let expected: Vec<String> = [
+            "load ${devtype} ${devnum}:${devpart} ${fdt_ovaddr} ${fdtdir}/overlays/dwc2.dtbo",
+            "fdt apply ${fdt_ovaddr}",
+            "fdt set /soc/audio status okay",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+        assert_eq!(expected, by_model["Zero 2 W"]);
+        assert_eq!(expected[2..], by_model["Zero W"]);
+    }
+
+    #[test]
+    fn test_gpu_mem_in_model_filter() {
+        let (_, configs) = parse("[pi4]\ngpu_mem=64\n").unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+
+        // [pi4]の各モデルの搭載メモリ量(1GB)から、CPUに割り当てる量を求める
+        let by_model = rpiconfig.uboot_commands_by_model().unwrap();
+        let expected: Vec<String> = [
+            "fdt set / memreserve < 0x3c000000 0x4000000 >",
+            "fdt set /memory@0 reg < 0x00 0x00 0x3c000000 0x00 0x40000000 0xbc000000 >",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+        assert_eq!(
+            vec!["4 Model B", "400", "Compute Module 4"],
+            by_model.keys().collect::<Vec<_>>()
+        );
+        for model in ["4 Model B", "400", "Compute Module 4"] {
+            assert_eq!(expected, by_model[model]);
+        }
+        assert!(rpiconfig.unsupported_entries().is_empty());
+    }
+
+    #[test]
+    fn test_gpu_mem_ramsize_mismatch_in_model_filter() {
+        // [pi4]のモデルは1GBなので、gpu_mem_256やgpu_mem_512はfirmwareと同じく無視する
+        for text in ["[pi4]\ngpu_mem_256=16\n", "[pi4]\ngpu_mem_512=128\n"] {
+            let (_, configs) = parse(text).unwrap();
+            let rpiconfig = RPiConfig::from(configs);
+            let by_model = rpiconfig.uboot_commands_by_model().unwrap();
+            for (model, commands) in &by_model {
+                assert!(
+                    commands.iter().all(|x| !x.contains("memreserve")),
+                    "{}: {:?}",
+                    model,
+                    commands
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_gpu_mem_unsupported_model() {
+        // gpu_mem_4096がallから振り分けられたモデルと、元の設定をエラーに含める
+        let text = "dtparam=audio=on\ngpu_mem_4096=256\n";
+        let rpiconfig = RPiConfig::load_from_reader(text.as_bytes()).unwrap();
+        let mut options = UbootOptions::default();
+        options.model_ramsize.insert("5 Model B".to_string(), 4096);
+        let err = rpiconfig
+            .convert_to_uboot_config_with_options("bootconfig", &options)
+            .unwrap_err();
+        assert_eq!(
+            "line 2: gpu_mem is not yet supported on this model: \"5 Model B\" (from gpu_mem_4096=256)",
+            err.to_string()
+        );
+
+        // 搭載メモリ量がわからないモデル
+        let text = "[pi5]\ngpu_mem=64\n";
+        let rpiconfig = RPiConfig::load_from_reader(text.as_bytes()).unwrap();
+        let err = rpiconfig.convert_to_uboot_config("bootconfig").unwrap_err();
+        assert_eq!(
+            "line 2: Unsupported total_ramsize: Some(\"5 Model B\") (from gpu_mem=64)",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_pi400_cm4_filter() {
+        let text = "[pi400]\ndtparam=audio=on\n[cm4]\ndtoverlay=dwc2\n[cm4s]\n# CM4S\n";
+        let (_, configs) = parse(text).unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+
+        let by_model = rpiconfig.uboot_commands_by_model().unwrap();
+        assert_eq!(
+            vec!["400", "Compute Module 4"],
+            by_model.keys().collect::<Vec<_>>()
+        );
+        assert!(!by_model.contains_key("4 Model B"));
+        assert_eq!(vec!["fdt set /soc/audio status okay"], by_model["400"]);
+        assert_eq!(
+            vec![
+                "load ${devtype} ${devnum}:${devpart} ${fdt_ovaddr} ${fdtdir}/overlays/dwc2.dtbo",
+                "fdt apply ${fdt_ovaddr}",
+            ],
+            by_model["Compute Module 4"]
+        );
+    }
+
+    #[test]
+    fn test_cm_filter() {
+        let text = "[cm3]\ndtparam=audio=on\n[cm3+]\ndtparam=i2c_arm=on\n[cm4]\ndtoverlay=dwc2\n";
+        let rpiconfig = RPiConfig::load_from_str(text).unwrap();
+
+        let by_model = rpiconfig.uboot_commands_by_model().unwrap();
+        assert_eq!(
+            vec!["Compute Module 3", "Compute Module 3+", "Compute Module 4"],
+            by_model.keys().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["fdt set /soc/audio status okay"],
+            by_model["Compute Module 3"]
+        );
+        assert_eq!(
+            vec!["fdt set i2c_arm status okay"],
+            by_model["Compute Module 3+"]
+        );
+        assert_eq!(
+            vec![
+                "load ${devtype} ${devnum}:${devpart} ${fdt_ovaddr} ${fdtdir}/overlays/dwc2.dtbo",
+                "fdt apply ${fdt_ovaddr}",
+            ],
+            by_model["Compute Module 4"]
+        );
+        assert!(rpiconfig.validate().errors.is_empty());
+
+        // u-bootで区別できないCompute Module向けの設定は、捨てずにエラーにする
+        let text = "[cm4]\ndtoverlay=dwc2\n[cm4s]\ndtparam=i2c_arm=on\n[cm1]\ndtparam=audio=on\n";
+        let rpiconfig = RPiConfig::load_from_str(text).unwrap();
+        assert_eq!(
+            "Unsupported filter: [cm1] (pibootcfg does not support BCM2835 boards)",
+            rpiconfig
+                .convert_to_uboot_config("bootcfg")
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(
+            vec![
+                "Unsupported filter: [cm1] (pibootcfg does not support BCM2835 boards)",
+                "Unsupported filter: [cm4s] (u-boot has no board_name for CM4S)",
+            ],
+            rpiconfig.validate().errors
+        );
+    }
+
+    #[test]
+    fn test_gpu_mem_precedence() {
+        let (_, configs) = parse("gpu_mem_1024=128\ngpu_mem=64\n").unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+
+        // 1GBのモデルには、後に書かれたgpu_memではなくgpu_mem_1024だけが適用される
+        let by_model = rpiconfig.uboot_commands_by_model().unwrap();
+        let expected: Vec<String> = [
+            "fdt set / memreserve < 0x38000000 0x8000000 >",
+            "fdt set /memory@0 reg < 0x00 0x00 0x38000000 0x00 0x40000000 0xbc000000 >",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+        assert_eq!(expected, by_model["4 Model B"]);
+        assert_eq!(
+            vec![
+                "fdt set / memreserve < 0x38000000 0x8000000 >",
+                "fdt set /memory@0 reg < 0x00 0x38000000 >",
+            ],
+            by_model["3 Model B"]
+        );
+        // 512MBのモデルにはgpu_memが適用される
+        assert_eq!(
+            vec![
+                "fdt set / memreserve < 0x1c000000 0x4000000 >",
+                "fdt set /memory@0 reg < 0x00 0x1c000000 >",
+            ],
+            by_model["Zero"]
+        );
+
+        assert_eq!(
+            vec!["[all] gpu_mem_1024=128 takes precedence over gpu_mem=64"],
+            rpiconfig.validate().warnings
+        );
+    }
+
+    #[test]
+    fn test_gpu_mem_precedence_across_filters() {
+        let expected = |gpu_ramsize: &str, cpu_ramsize: &str| -> Vec<String> {
+            vec![
+                format!("fdt set / memreserve < {} {} >", cpu_ramsize, gpu_ramsize),
+                format!(
+                    "fdt set /memory@0 reg < 0x00 0x00 {} 0x00 0x40000000 0xbc000000 >",
+                    cpu_ramsize
+                ),
+            ]
+        };
+
+        // [all]のgpu_memと[pi4]のgpu_memが両方当てはまるモデルには、後に書かれた方だけを適用する
+        let rpiconfig = RPiConfig::load_from_str("gpu_mem=64\n[pi4]\ngpu_mem=128\n").unwrap();
+        let by_model = rpiconfig.uboot_commands_by_model().unwrap();
+        for model in ["4 Model B", "400", "Compute Module 4"] {
+            assert_eq!(expected("0x8000000", "0x38000000"), by_model[model]);
+        }
+        assert_eq!(
+            vec![
+                "fdt set / memreserve < 0x1c000000 0x4000000 >",
+                "fdt set /memory@0 reg < 0x00 0x1c000000 >",
+            ],
+            by_model["Zero"]
+        );
+
+        let rpiconfig =
+            RPiConfig::load_from_str("[pi4]\ngpu_mem=128\n[all]\ngpu_mem=64\n").unwrap();
+        let by_model = rpiconfig.uboot_commands_by_model().unwrap();
+        for model in ["4 Model B", "400", "Compute Module 4"] {
+            assert_eq!(expected("0x4000000", "0x3c000000"), by_model[model]);
+        }
+
+        // [pi4]のgpu_mem_1024は、後に書かれた[all]のgpu_memより優先する
+        let rpiconfig =
+            RPiConfig::load_from_str("[pi4]\ngpu_mem_1024=128\n[all]\ngpu_mem=64\n").unwrap();
+        let by_model = rpiconfig.uboot_commands_by_model().unwrap();
+        for model in ["4 Model B", "400", "Compute Module 4"] {
+            assert_eq!(expected("0x8000000", "0x38000000"), by_model[model]);
+        }
+        assert_eq!(
+            vec!["[pi4] gpu_mem_1024=128 takes precedence over [all] gpu_mem=64"],
+            rpiconfig.validate().warnings
+        );
+    }
+
+    #[test]
+    fn test_gpu_mem_precedence_without_lines() {
+        // 行番号の無い設定でも、同じ種類の中では後に書かれたgpu_memを適用する
+        let (_, configs) =
+            parse("gpu_mem=64\ngpu_mem=128\n[pi4]\ngpu_mem_1024=256\ngpu_mem_1024=192\n").unwrap();
+        let split = RPiConfig::from(configs).memory_split();
+        assert_eq!((896 << 20, 128 << 20), split["3 Model B"]);
+        assert_eq!((832 << 20, 192 << 20), split["4 Model B"]);
+
+        // mergeした設定は、後から追加したものが後に書かれたものになる
+        let (_, configs) = parse("[pi4]\ngpu_mem=128\n").unwrap();
+        let mut rpiconfig = RPiConfig::from(configs);
+        let (_, configs) = parse("gpu_mem=64\n").unwrap();
+        rpiconfig.merge(RPiConfig::from(configs));
+        assert_eq!((960 << 20, 64 << 20), rpiconfig.memory_split()["4 Model B"]);
+    }
+
+    #[test]
+    fn test_gpu_mem_precedence_warning_in_model_filter() {
+        // モデルのfilterの中で重なっている場合も、振り分けた後のモデルごとに調べて知らせる
+        let rpiconfig = RPiConfig::load_from_str("[pi4]\ngpu_mem_1024=256\ngpu_mem=128\n").unwrap();
+        assert_eq!(
+            vec!["[pi4] gpu_mem_1024=256 takes precedence over gpu_mem=128"],
+            rpiconfig.validate().warnings
+        );
+
+        let rpiconfig =
+            RPiConfig::load_from_str("[all]\ngpu_mem_1024=256\n[pi4]\ngpu_mem=128\n").unwrap();
+        assert_eq!(
+            vec!["[all] gpu_mem_1024=256 takes precedence over [pi4] gpu_mem=128"],
+            rpiconfig.validate().warnings
+        );
+
+        // 搭載メモリ量が異なるモデルには当てはまらないので、知らせない
+        let rpiconfig = RPiConfig::load_from_str("[pi0]\ngpu_mem_1024=256\ngpu_mem=128\n").unwrap();
+        assert!(rpiconfig.validate().warnings.is_empty());
+    }
+
+    #[test]
+    fn test_convert_to_fw_setenv_script() {
+        let (_, configs) = parse("dtoverlay=dwc2\ndtparam=audio=on\n").unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+
+        let script = rpiconfig
+            .convert_to_fw_setenv_script("bootconfig")
+            .unwrap()
+            .unwrap();
+        // ${devtype}などが展開されないように、;で繋げたコマンド全体をシングルクォートで囲む
+        let config = rpiconfig
+            .convert_to_uboot_config("bootconfig")
+            .unwrap()
+            .unwrap();
+        let commands = config.strip_prefix("bootconfig=").unwrap();
+        assert!(commands.contains("${devtype}"));
+        assert_eq!(
+            format!("#!/bin/sh\nfw_setenv bootconfig '{}'\n", commands),
+            script
+        );
+
+        let options = UbootOptions {
+            crlf: true,
+            ..Default::default()
+        };
+        let script = rpiconfig
+            .convert_to_fw_setenv_script_with_options("bootconfig", &options)
+            .unwrap()
+            .unwrap();
+        // crlfはuEnv.txtにだけ使い、スクリプトはLFのままにする
+        let uenv = rpiconfig
+            .convert_to_uboot_config_with_options("bootconfig", &options)
+            .unwrap()
+            .unwrap();
+        assert_eq!(format!("bootconfig={}\r\n", commands), uenv);
+        assert_eq!(
+            format!("bootconfig={}", commands),
+            rpiconfig
+                .convert_to_uboot_config("bootconfig")
+                .unwrap()
+                .unwrap()
+        );
+        assert_eq!(
+            format!("#!/bin/sh\nfw_setenv bootconfig '{}'\n", commands),
+            script
+        );
+        assert!(!script.contains('\r'));
+
+        assert_eq!("''", shell_quote(""));
+        assert_eq!(r"'echo '\''a b'\'''", shell_quote("echo 'a b'"));
+
+        let (_, configs) = parse("# comment\n").unwrap();
+        assert!(RPiConfig::from(configs)
+            .convert_to_fw_setenv_script("bootconfig")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_visit_mut() {
+        let text =
+            "dtparam=i2c_arm=on,i2c_arm_baudrate=100000\n[pi4]\ndtparam=i2c_arm_baudrate=50000\n";
+        let (_, configs) = parse(text).unwrap();
+        let mut rpiconfig = RPiConfig::from(configs);
+
+        let mut visited = Vec::new();
+        rpiconfig.visit_mut(|filter, entry| {
+            visited.push(filter.to_string());
+            if let ConfigEntry::DTparam(dtparam) = entry {
+                for config in dtparam.configs.iter_mut() {
+                    if config.key == "i2c_arm_baudrate" {
+                        config.value = "400000".to_string();
+                    }
+                }
+            }
+        });
+        assert_eq!(vec!["all", "pi4"], visited);
+
+        let by_model = rpiconfig.uboot_commands_by_model().unwrap();
+        assert_eq!(
+            vec![
+                "fdt set i2c_arm status okay".to_string(),
+                "fdt set i2c clock-frequency < 0x61a80 >".to_string()
+            ],
+            by_model["all"]
+        );
+        assert_eq!(
+            vec!["fdt set i2c clock-frequency < 0x61a80 >".to_string()],
+            by_model["4 Model B"]
+        );
+    }
+
+    #[test]
+    fn test_dtparam_krnbt() {
+        let entry = parse_line("dtparam=krnbt=on").unwrap();
+        assert_eq!(
+            vec!["fdt set bluetooth status okay".to_string()],
+            entry.to_uboot_commands(None).unwrap()
+        );
+        let entry = parse_line("dtparam=krnbt=off").unwrap();
+        assert_eq!(
+            vec!["fdt set bluetooth status disabled".to_string()],
+            entry.to_uboot_commands(None).unwrap()
+        );
+        let entry = parse_line("dtparam=krnbt=yes").unwrap();
+        assert_eq!(
+            "Unsupported dtparam option: krnbt=yes",
+            entry.to_uboot_commands(None).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_empty_config() {
+        for text in ["", "\n  \n", "# comment only\n\n#dtoverlay=dwc2\n"] {
+            let rpiconfig = RPiConfig::load_from_bytes(text.as_bytes()).unwrap();
+            assert_eq!(None, rpiconfig.convert_to_uboot_config("bootcmd").unwrap());
+
+            let rpiconfig = RPiConfig::load_from_reader(text.as_bytes()).unwrap();
+            assert_eq!(None, rpiconfig.convert_to_uboot_config("bootcmd").unwrap());
+
+            let (_, configs) = parse(text).unwrap();
+            let rpiconfig = RPiConfig::from(configs);
+            assert_eq!(None, rpiconfig.convert_to_uboot_config("bootcmd").unwrap());
+        }
+    }
+
+    #[test]
+    fn test_dtoverlay_bluetooth() {
+        let load = |line: &str, options: &UbootOptions| {
+            parse_line(line)
+                .unwrap()
+                .generate_uboot_config(options)
+                .unwrap()
+        };
+        let commands = |name: &str| {
+            vec![
+                format!("load ${{devtype}} ${{devnum}}:${{devpart}} ${{fdt_ovaddr}} ${{fdtdir}}/overlays/{}.dtbo", name),
+                "fdt apply ${fdt_ovaddr}".to_string(),
+            ]
+        };
+
+        let default = UbootOptions::default();
+        let rename = UbootOptions {
+            rename_legacy_overlays: true,
+            ..Default::default()
+        };
+        for name in ["disable-wifi", "disable-bt", "miniuart-bt"] {
+            let line = format!("dtoverlay={}", name);
+            assert_eq!(commands(name), load(&line, &default));
+            assert_eq!(commands(name), load(&line, &rename));
+
+            // 古い名前はそのまま読み込むか、現在の名前に読み替える
+            let legacy = format!("pi3-{}", name);
+            let line = format!("dtoverlay={}", legacy);
+            assert_eq!(commands(&legacy), load(&line, &default));
+            assert_eq!(commands(name), load(&line, &rename));
+            assert_eq!(commands(name), load(&format!("{}.dtbo", line), &rename));
+        }
+        assert_eq!(commands("act-led"), load("dtoverlay=pi3-act-led", &rename));
+        // 名前が変わっていないoverlayはそのまま
+        assert_eq!(commands("pi3-dwc2"), load("dtoverlay=pi3-dwc2", &rename));
+    }
+
+    #[test]
+    fn test_dtoverlay_name() {
+        let load = |line: &str| {
+            parse_line(line)
+                .unwrap()
+                .to_uboot_commands(None)
+                .map(|commands| commands[0].clone())
+        };
+        assert_eq!(
+            "load ${devtype} ${devnum}:${devpart} ${fdt_ovaddr} ${fdtdir}/overlays/vc4-kms-v3d-pi4.dtbo",
+            load("dtoverlay=vc4-kms-v3d-pi4").unwrap()
+        );
+        assert_eq!(
+            "load ${devtype} ${devnum}:${devpart} ${fdt_ovaddr} ${fdtdir}/overlays/rpi-display.v2.dtbo",
+            load("dtoverlay=rpi-display.v2").unwrap()
+        );
+        assert_eq!(
+            "load ${devtype} ${devnum}:${devpart} ${fdt_ovaddr} ${fdtdir}/overlays/disable-bt.dtbo",
+            load("dtoverlay=disable-bt.dtbo").unwrap()
+        );
+        assert_eq!(
+            "Invalid dtoverlay name: \"../evil\"",
+            load("dtoverlay=../evil").unwrap_err().to_string()
+        );
+        assert_eq!(
+            "Invalid dtoverlay name: \"a b\"",
+            load("dtoverlay=a b").unwrap_err().to_string()
+        );
+    }
 
-        for tmp in expected {
-            let dtparam = tmp.0;
-            let expected = tmp.1;
+    #[test]
+    fn test_fdt_resize_estimate() {
+        let resize = |text: &str, options: &UbootOptions| {
+            let (_, configs) = parse(text).unwrap();
+            let output = RPiConfig::from(configs)
+                .convert_to_uboot_config_with_options("bootcmd", options)
+                .unwrap()
+                .unwrap();
+            output
+                .split(';')
+                .find_map(|command| command.strip_prefix("fdt resize "))
+                .unwrap()
+                .to_string()
+        };
+        let options = UbootOptions::default();
 
-            let result = dtparam.generate_uboot_config().unwrap();
-            assert_eq!(expected, result);
-        }
+        assert_eq!("0x2000", resize("dtparam=audio=on\n", &options));
+        let overlays = "dtoverlay=dwc2\ndtoverlay=disable-bt\ndtoverlay=i2c-rtc\ndtoverlay=spi1-1cs\ndtoverlay=uart2\ndtoverlay=pwm\n";
+        // 6 * 0x800 + 2 * 0x40 を切り上げる
+        assert_eq!("0x4000", resize(overlays, &options));
+
+        // 分岐したブロックは最も大きいものだけ数える
+        let guarded = format!("[pi4]\n{}[pi3]\ndtoverlay=dwc2\n", overlays);
+        assert_eq!("0x4000", resize(&guarded, &options));
+
+        let options = UbootOptions {
+            fdt_resize_floor: 0x8000,
+            ..Default::default()
+        };
+        assert_eq!("0x8000", resize(overlays, &options));
     }
 
-    // RPiConfig
     #[test]
-    fn test_convert_to_uboot_config() {
-        let rpiconfig = RPiConfig {
-            configs: HashMap::from([
-                (
-                    "all".to_string(),
-                    vec![ConfigEntry::DTparam(DTparam {
-                        configs: vec![Config {
-                            key: "audio".to_string(),
-                            value: "on".to_string(),
-                        }],
-                    })],
+    fn test_dtparam_translations() {
+        let mut options = UbootOptions::default();
+        options.dtparam_translations.insert(
+            "my_led".to_string(),
+            vec!["fdt set /leds/my linux,default-trigger {value}".to_string()],
+        );
+        // 組み込みの変換を上書きする
+        options.dtparam_translations.insert(
+            "audio".to_string(),
+            vec![
+                "fdt set /soc/audio status okay".to_string(),
+                "fdt set /soc/audio brcm,disable-headphones < 1 >".to_string(),
+            ],
+        );
+
+        let entry = parse_line("dtparam=my_led=heartbeat,audio,i2c_arm=on").unwrap();
+        assert_eq!(
+            vec![
+                "fdt set /leds/my linux,default-trigger heartbeat",
+                "fdt set /soc/audio status okay",
+                "fdt set /soc/audio brcm,disable-headphones < 1 >",
+                "fdt set i2c_arm status okay",
+            ],
+            entry.generate_uboot_config(&options).unwrap()
+        );
+        // 変換表が無ければ未対応のkeyとして扱う
+        assert_eq!(
+            "Unsupported dtparam key: my_led",
+            entry.to_uboot_commands(None).unwrap_err().to_string()
+        );
+
+        // 検証や、変換されない設定の列挙でも変換表を使う
+        let rpiconfig = RPiConfig::load_from_str("dtparam=my_led=heartbeat\n").unwrap();
+        assert!(rpiconfig
+            .unknown_dtparam_keys_with_options(&options)
+            .is_empty());
+        assert_eq!(
+            vec![("all".to_string(), Some(1), "my_led".to_string())],
+            rpiconfig.unknown_dtparam_keys()
+        );
+        let validation = ValidationOptions {
+            strict_unknown_keys: true,
+            dtparam_translations: options.dtparam_translations.clone(),
+            ..Default::default()
+        };
+        assert_eq!(
+            ValidationReport::default(),
+            rpiconfig.validate_with_options(&validation)
+        );
+        assert!(rpiconfig
+            .unsupported_entries_with_options(&options)
+            .is_empty());
+        assert!(rpiconfig.has_uboot_effect_with_options(&options));
+        assert_eq!(
+            "dtparam=my_led=heartbeat\n",
+            rpiconfig.to_config_txt_annotated_with_options(&options)
+        );
+        assert!(rpiconfig
+            .to_config_txt_annotated()
+            .starts_with("# [unsupported by pibootcfg] dtparam=my_led=heartbeat"));
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn test_parse_dtparam_translations() {
+        let translations = parse_dtparam_translations(
+            r#"
+[dtparam]
+my_led = "fdt set /leds/my linux,default-trigger {value}"
+spi = ["fdt set spi0 status okay", "fdt set spi1 status okay"]
+"#,
+        )
+        .unwrap();
+        assert_eq!(2, translations.len());
+        assert_eq!(
+            vec!["fdt set /leds/my linux,default-trigger {value}"],
+            translations["my_led"]
+        );
+        assert_eq!(
+            vec!["fdt set spi0 status okay", "fdt set spi1 status okay"],
+            translations["spi"]
+        );
+
+        assert!(parse_dtparam_translations("").unwrap().is_empty());
+        assert_eq!(
+            "Invalid dtparam translation for spi: 1",
+            parse_dtparam_translations("[dtparam]\nspi = 1\n")
+                .unwrap_err()
+                .to_string()
+        );
+        assert!(parse_dtparam_translations("[dtparam\n").is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_convert_to_uboot_json() {
+        let (_, configs) = parse("dtparam=audio=on\ndtoverlay=dwc2\n").unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+
+        let json = rpiconfig.convert_to_uboot_json("bootcfg").unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!("bootcfg", value["env"]);
+        let commands = value["commands"].as_array().unwrap();
+        // prologue(3) + audio(1) + dwc2(2) + revision(2)
+        assert_eq!(8, commands.len());
+        assert_eq!(
+            rpiconfig
+                .convert_to_uboot_config("bootcfg")
+                .unwrap()
+                .unwrap(),
+            format!(
+                "bootcfg={}",
+                commands
+                    .iter()
+                    .map(|x| x.as_str().unwrap())
+                    .collect::<Vec<_>>()
+                    .join(";")
+            )
+        );
+
+        let json = RPiConfig::new().convert_to_uboot_json("bootcfg").unwrap();
+        assert_eq!(r#"{"env":"bootcfg","commands":[]}"#, json);
+    }
+
+    #[test]
+    fn test_dtparam_rtc() {
+        let guard = "if test \"${board_name}\" = \"5 Model B\" || test \"${board_name}\" = \"500\" || test \"${board_name}\" = \"Compute Module 5\"";
+        let entry = parse_line("dtparam=rtc=on,rtc_bbat_vchg=3000000").unwrap();
+        let expected: Vec<String> = [
+            guard,
+            "then",
+            "fdt set /soc@107c000000/rpi_rtc status okay",
+            "fi",
+            guard,
+            "then",
+            "fdt set /soc@107c000000/rpi_rtc trickle-charge-microvolt < 0x2dc6c0 >",
+            "fi",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+        assert_eq!(expected, entry.to_uboot_commands(None).unwrap());
+
+        // 0で充電を止める
+        let entry = parse_line("dtparam=rtc_bbat_vchg=0").unwrap();
+        assert_eq!(
+            vec![
+                guard,
+                "then",
+                "fdt set /soc@107c000000/rpi_rtc trickle-charge-microvolt < 0x0 >",
+                "fi"
+            ],
+            entry.to_uboot_commands(None).unwrap()
+        );
+
+        // [all]に書かれていても、RTCのノードが無いPi 5以外のボードでは実行しない
+        let rpiconfig = RPiConfig::load_from_str("[all]\ndtparam=rtc=on\n").unwrap();
+        let output = rpiconfig
+            .convert_to_uboot_config("bootconfig")
+            .unwrap()
+            .unwrap();
+        assert!(output.contains(&format!(
+            ";{};then;fdt set /soc@107c000000/rpi_rtc status okay;fi;",
+            guard
+        )));
+
+        let entry = parse_line("dtparam=rtc_bbat_vchg=5000000").unwrap();
+        assert_eq!(
+            "rtc_bbat_vchg out of range: 5000000 (expected 0..=4400000)",
+            entry.to_uboot_commands(None).unwrap_err().to_string()
+        );
+        let entry = parse_line("dtparam=rtc_bbat_vchg=3V").unwrap();
+        assert!(entry
+            .to_uboot_commands(None)
+            .unwrap_err()
+            .to_string()
+            .starts_with("Invalid rtc_bbat_vchg: \"3V\""));
+    }
+
+    #[test]
+    fn test_dtparam_poe_fan_temp() {
+        let entry = parse_line(
+            "dtparam=poe_fan_temp0=50000,poe_fan_temp0_hyst=2000,poe_fan_temp1=60000,poe_fan_temp1_hyst=5000",
+        )
+        .unwrap();
+        let expected: Vec<String> = [
+            "fdt set /thermal-zones/cpu-thermal/trips/trip0 temperature < 0xc350 >",
+            "fdt set /thermal-zones/cpu-thermal/trips/trip0 hysteresis < 0x7d0 >",
+            "fdt set /thermal-zones/cpu-thermal/trips/trip1 temperature < 0xea60 >",
+            "fdt set /thermal-zones/cpu-thermal/trips/trip1 hysteresis < 0x1388 >",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+        assert_eq!(expected, entry.to_uboot_commands(None).unwrap());
+
+        let entry = parse_line("dtparam=poe_fan_temp0=150000").unwrap();
+        assert_eq!(
+            "poe_fan_temp0 out of range: 150000 (expected 0..=100000)",
+            entry.to_uboot_commands(None).unwrap_err().to_string()
+        );
+        let entry = parse_line("dtparam=poe_fan_temp1_hyst=-1").unwrap();
+        assert!(entry
+            .to_uboot_commands(None)
+            .unwrap_err()
+            .to_string()
+            .starts_with("Invalid poe_fan_temp1_hyst: \"-1\""));
+        let entry = parse_line("dtparam=poe_fan_temp4=70000").unwrap();
+        assert_eq!(
+            "Unsupported dtparam key: poe_fan_temp4 (supported: poe_fan_temp0..3, poe_fan_temp0_hyst..3_hyst)",
+            entry.to_uboot_commands(None).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_to_config_txt_annotated() {
+        let (_, configs) = parse(
+            r"dtparam=audio=on
+# comment
+enable_uart=1
+dtparam=foo=on
+[pi4]
+dtoverlay=vc4-fkms-v3d
+hdmi_group=1
+",
+        )
+        .unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+
+        let expected = r"dtparam=audio=on
+# comment
+# [unsupported by pibootcfg] enable_uart=1 (enable_uart has no u-boot translation)
+# [unsupported by pibootcfg] dtparam=foo=on (Unsupported dtparam key: foo)
+[pi4]
+dtoverlay=vc4-fkms-v3d
+# [unsupported by pibootcfg] hdmi_group=1 (hdmi_group has no u-boot translation)
+";
+        let annotated = rpiconfig.to_config_txt_annotated();
+        assert_eq!(expected, annotated);
+
+        // コメントアウトした後のconfig.txtは、すべて変換できる
+        let (_, configs) = parse(&annotated).unwrap();
+        assert!(RPiConfig::from(configs).unsupported_entries().is_empty());
+    }
+
+    #[test]
+    fn test_usb_max_current_enable() {
+        let (_, configs) = parse("[pi5]\nusb_max_current_enable=1\n").unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+        assert!(!rpiconfig.has_uboot_effect());
+
+        let expected = vec![(
+            "pi5".to_string(),
+            parse_line("usb_max_current_enable=1").unwrap(),
+            "usb_max_current_enable raises the USB current limit in the firmware and has no device-tree equivalent"
+                .to_string(),
+        )];
+        assert_eq!(expected, rpiconfig.unsupported_entries());
+
+        // firmwareが解釈するkeyとして扱う
+        let options = ValidationOptions {
+            strict_unknown_keys: true,
+            ..Default::default()
+        };
+        let report = rpiconfig.validate_with_options(&options);
+        assert!(report.errors.is_empty());
+        assert_eq!(
+            vec!["[pi5] usb_max_current_enable is not translated to u-boot"],
+            report.warnings
+        );
+    }
+
+    #[test]
+    fn test_dtdebug() {
+        let (_, configs) = parse("dtdebug=1\ndtparam=audio=on\n").unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+
+        let output = rpiconfig
+            .convert_to_uboot_config("bootconfig")
+            .unwrap()
+            .unwrap();
+        assert!(!output.contains("dtdebug"));
+
+        let expected = vec![(
+            "all".to_string(),
+            parse_line("dtdebug=1").unwrap(),
+            "dtdebug only enables the firmware device-tree debug log (see vcdbg log msg); it does not apply to u-boot fdt commands"
+                .to_string(),
+        )];
+        assert_eq!(expected, rpiconfig.unsupported_entries());
+    }
+
+    #[test]
+    fn test_gpio_command() {
+        // gpio=はfirmwareが設定するので、u-bootでは何もしない
+        let rpiconfig = RPiConfig::load_from_str("gpio=17=op,dh\ngpio=4=ip,pu\n").unwrap();
+        assert!(!rpiconfig.has_uboot_effect());
+        assert_eq!(
+            vec![
+                "gpio is applied to the pins by the firmware before u-boot starts and has no device-tree equivalent";
+                2
+            ],
+            rpiconfig
+                .unsupported_entries()
+                .into_iter()
+                .map(|(_, _, reason)| reason)
+                .collect::<Vec<_>>()
+        );
+        // dtparam=gpioはfirmwareの書式ではない
+        let entry = parse_line("dtparam=gpio=17=up").unwrap();
+        assert_eq!(
+            "Unsupported dtparam key: gpio",
+            entry.to_uboot_commands(None).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_dtparam_audio_channels() {
+        // チャンネル数を読むドライバが無いので、変換しない
+        let entry = parse_line("dtparam=audio_channels=2").unwrap();
+        assert_eq!(
+            "Unsupported dtparam key: audio_channels has no device-tree equivalent; neither the bcm2835 audio nor the i2s driver reads a channel count",
+            entry.to_uboot_commands(None).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_dtparam_audremap_keys() {
+        // audremap overlayのパラメータは、dtparamとしては変換しない
+        let rpiconfig = RPiConfig::load_from_str("dtparam=audio=on,audio_pins=12_13\n").unwrap();
+        assert_eq!(
+            "line 1: Unsupported dtparam key: audio_pins is a parameter of the audremap overlay; use dtoverlay=audremap,pins_<a>_<b> instead",
+            rpiconfig
+                .convert_to_uboot_config("bootconfig")
+                .unwrap_err()
+                .to_string()
+        );
+        for (key, parameter) in [
+            ("audio_swap_lr", "swap_lr"),
+            ("audio_enable_jack", "enable_jack"),
+        ] {
+            let entry = parse_line(&format!("dtparam={}=on", key)).unwrap();
+            assert_eq!(
+                format!(
+                    "Unsupported dtparam key: {} is a parameter of the audremap overlay; use dtoverlay=audremap,{} instead",
+                    key, parameter
                 ),
-                (
-                    "pi4".to_string(),
-                    vec![ConfigEntry::DTOverlay(DTOverlay {
-                        overlay: "vc4-fkms-v3d".to_string(),
-                        configs: vec![],
-                    })],
+                entry.to_uboot_commands(None).unwrap_err().to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn test_framebuffer_geometry() {
+        let (_, configs) = parse(
+            r"framebuffer_width=1280
+framebuffer_height=720
+dtparam=audio=on
+",
+        )
+        .unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+
+        let output = rpiconfig
+            .convert_to_uboot_config("bootconfig")
+            .unwrap()
+            .unwrap();
+        assert!(!output.contains("framebuffer"));
+
+        let unsupported = rpiconfig.unsupported_entries();
+        assert_eq!(2, unsupported.len());
+        for (key, (_, entry, reason)) in ["framebuffer_width", "framebuffer_height"]
+            .iter()
+            .zip(unsupported)
+        {
+            assert_eq!(key.to_string(), entry.diff_key());
+            assert_eq!(
+                format!(
+                    "{} sets the firmware framebuffer geometry and has no device-tree equivalent; use video= in cmdline.txt instead",
+                    key
                 ),
-            ]),
+                reason
+            );
+        }
+    }
+
+    #[test]
+    fn test_exact_model_filter() {
+        let (_, configs) = parse(
+            r"[4 Model B]
+gpu_mem=256
+dtparam=audio=on
+",
+        )
+        .unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+
+        let by_model = rpiconfig.uboot_commands_by_model().unwrap();
+        let expected: Vec<String> = [
+            "fdt set / memreserve < 0x30000000 0x10000000 >",
+            "fdt set /memory@0 reg < 0x00 0x00 0x30000000 0x00 0x40000000 0xbc000000 >",
+            "fdt set /soc/audio status okay",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+        assert_eq!(expected, by_model["4 Model B"]);
+        assert!(!by_model.contains_key("400"));
+        assert!(rpiconfig.unsupported_entries().is_empty());
+    }
+
+    #[test]
+    fn test_disabled_entry() {
+        let (_, configs) = parse(
+            r"dtparam=audio=on
+dtoverlay=dwc2
+",
+        )
+        .unwrap();
+        let mut rpiconfig = RPiConfig::from(configs);
+        rpiconfig.visit_mut(|_, entry| {
+            if matches!(entry, ConfigEntry::DTparam(_)) {
+                entry.set_enabled(false);
+            }
+        });
+
+        let output = rpiconfig
+            .convert_to_uboot_config("bootconfig")
+            .unwrap()
+            .unwrap();
+        assert!(!output.contains("audio"));
+        assert!(output.contains("dwc2"));
+        assert!(rpiconfig.unsupported_entries().is_empty());
+
+        let entries: Vec<String> = rpiconfig.configs["all"]
+            .iter()
+            .map(|x| x.config.to_string())
+            .collect();
+        assert_eq!(vec!["#dtparam=audio=on", "dtoverlay=dwc2"], entries);
+
+        // 有効に戻すと元の設定になる
+        let mut entry = rpiconfig.configs["all"][0].config.clone();
+        assert!(!entry.is_enabled());
+        entry.set_enabled(true);
+        assert_eq!(parse_line("dtparam=audio=on").unwrap(), entry);
+
+        // コメントは無効にできない
+        let mut comment = ConfigEntry::Comment(" comment".to_string());
+        comment.set_enabled(false);
+        assert_eq!(ConfigEntry::Comment(" comment".to_string()), comment);
+    }
+
+    #[test]
+    fn test_display_implicit_all() {
+        let text = r"dtparam=audio=on
+dtoverlay=dwc2
+[pi4]
+dtoverlay=vc4-fkms-v3d
+";
+        let rpiconfig = RPiConfig::load_from_bytes(text.as_bytes()).unwrap();
+        assert_eq!(text, rpiconfig.to_string());
+        let rpiconfig = RPiConfig::load_from_reader(text.as_bytes()).unwrap();
+        assert_eq!(text, rpiconfig.to_string());
+
+        let text = r"[all]
+dtparam=audio=on
+[pi4]
+dtoverlay=vc4-fkms-v3d
+";
+        let rpiconfig = RPiConfig::load_from_bytes(text.as_bytes()).unwrap();
+        assert_eq!(text, rpiconfig.to_string());
+
+        // filterの無い先頭部分が無い場合も、allを出力しない
+        let text = "[pi4]\ndtoverlay=vc4-fkms-v3d\n";
+        let rpiconfig = RPiConfig::load_from_bytes(text.as_bytes()).unwrap();
+        assert_eq!(text, rpiconfig.to_string());
+    }
+
+    #[test]
+    fn test_pi_model_board_name() {
+        for model in PiModel::ALL {
+            assert_eq!(Some(model), PiModel::from_board_name(model.as_board_name()));
+        }
+        assert_eq!(None, PiModel::from_board_name("all"));
+        assert_eq!(None, PiModel::from_board_name("4 model b"));
+    }
+
+    #[test]
+    fn test_reject_semicolon_in_command() {
+        let text = "dtparam=audio=on\ndtoverlay=dwc2,dr_mode=host;reset\n";
+        let rpiconfig = RPiConfig::load_from_bytes(text.as_bytes()).unwrap();
+        assert_eq!(
+            "line 2: Generated u-boot command contains ';': \"fdt set usb dr_mode host;reset\"",
+            rpiconfig
+                .convert_to_uboot_config("bootconfig")
+                .unwrap_err()
+                .to_string()
+        );
+        assert!(rpiconfig.uboot_commands_by_model().is_err());
+
+        let (_, configs) = parse("dtoverlay=dwc2,dr_mode=host\n").unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+        let output = rpiconfig
+            .convert_to_uboot_config("bootconfig")
+            .unwrap()
+            .unwrap();
+        assert!(output
+            .split(';')
+            .any(|command| command == "fdt set usb dr_mode host"));
+    }
+
+    #[test]
+    fn test_has_uboot_effect() {
+        let text = r"# comment only
+disable_splash=1
+enable_uart=1
+[pi4]
+max_framebuffers=2
+";
+        let rpiconfig = RPiConfig::load_from_bytes(text.as_bytes()).unwrap();
+        assert!(!rpiconfig.has_uboot_effect());
+        // 変換はできるが、prologueとrevisionノードしか出力されない
+        assert!(rpiconfig
+            .convert_to_uboot_config("bootconfig")
+            .unwrap()
+            .is_some());
+        assert!(!RPiConfig::new().has_uboot_effect());
+
+        // u-boot自身を起動するためのkernelとcmdlineは、u-bootでは何もしない
+        let text = "kernel=u-boot.bin\ncmdline=cmdline.txt\n";
+        let rpiconfig = RPiConfig::load_from_bytes(text.as_bytes()).unwrap();
+        assert!(!rpiconfig.has_uboot_effect());
+        // bootcode_delayはu-bootの環境変数に引き継ぐ
+        let rpiconfig = RPiConfig::load_from_bytes(b"bootcode_delay=1\n").unwrap();
+        assert!(rpiconfig.has_uboot_effect());
+
+        let text = "disable_splash=1\n[pi4]\ndtparam=audio=on\n";
+        let rpiconfig = RPiConfig::load_from_bytes(text.as_bytes()).unwrap();
+        assert!(rpiconfig.has_uboot_effect());
+
+        let text = "[0x12345678]\ndtoverlay=dwc2\n";
+        let rpiconfig = RPiConfig::load_from_bytes(text.as_bytes()).unwrap();
+        assert!(rpiconfig.has_uboot_effect());
+
+        // 変換できない設定は、エラーを確認できるように変換対象とみなす
+        let rpiconfig = RPiConfig::load_from_bytes(b"dtparam=unknown=on\n").unwrap();
+        assert!(rpiconfig.has_uboot_effect());
+    }
+
+    #[test]
+    fn test_merge_configs() {
+        let dir = std::env::temp_dir().join(format!("pibootcfg-merge-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("config.txt");
+        let vendor = dir.join("vendor.txt");
+        fs::write(
+            &config,
+            "boot_delay=1\ngpu_mem_1024=128\ndtoverlay=dwc2\n[pi4]\ndtparam=audio=on\n",
+        )
+        .unwrap();
+        fs::write(
+            &vendor,
+            "boot_delay=3\ngpu_mem_1024=256\n[pi4]\ndtoverlay=vc4-fkms-v3d\n",
+        )
+        .unwrap();
+
+        let rpiconfig = RPiConfig::load_from_configs(&[&config, &vendor]).unwrap();
+        let (_, expected) = parse(
+            r"dtoverlay=dwc2
+boot_delay=3
+gpu_mem_1024=256
+[pi4]
+dtparam=audio=on
+dtoverlay=vc4-fkms-v3d
+",
+        )
+        .unwrap();
+        assert_eq!(RPiConfig::from(expected), rpiconfig);
+        // 1つだけ読み込んだ場合のみ行番号が残る
+        let has_lines = |rpiconfig: &RPiConfig| {
+            rpiconfig
+                .configs
+                .values()
+                .flatten()
+                .any(|x| x.line.is_some())
         };
-        let expected = vec!["setexpr fdt_ovaddr ${fdt_addr} + 0x40000",
-        "fdt addr ${fdt_addr}",
-        "fdt resize 0x2000",
-        "fdt set /soc/audio status okay",
-        "if test \"${board_name}\" = \"4 Model B\"",
-        "then",
-        "load ${devtype} ${devnum}:${devpart} ${fdt_ovaddr} ${fdtdir}/overlays/vc4-fkms-v3d.dtbo",
-        "fdt apply ${fdt_ovaddr}",
-        "fi",
-        "if test \"${board_name}\" = \"400\"",
-        "then",
-        "load ${devtype} ${devnum}:${devpart} ${fdt_ovaddr} ${fdtdir}/overlays/vc4-fkms-v3d.dtbo",
-        "fdt apply ${fdt_ovaddr}",
-        "fi",
-        "if test \"${board_name}\" = \"Compute Module 4\"",
-        "then",
-        "load ${devtype} ${devnum}:${devpart} ${fdt_ovaddr} ${fdtdir}/overlays/vc4-fkms-v3d.dtbo",
-        "fdt apply ${fdt_ovaddr}",
-        "fi",
-        "fdt mknode / system",
-        "fdt set /system linux,revision < ${board_revision} >"];
-        let expected = format!("bootconfig={}", expected.join(";"));
+        assert!(!has_lines(&rpiconfig));
+        assert!(has_lines(
+            &RPiConfig::load_from_configs(&[&config]).unwrap()
+        ));
 
         let output = rpiconfig
             .convert_to_uboot_config("bootconfig")
             .unwrap()
             .unwrap();
-        assert_eq!(expected, output);
+        let commands: Vec<&str> = output.split(';').collect();
+        assert!(commands.contains(&"setenv bootdelay 3"));
+        assert!(!commands.contains(&"setenv bootdelay 1"));
+        assert!(commands.contains(&"fdt set / memreserve < 0x30000000 0x10000000 >"));
+        assert!(output.contains("overlays/dwc2.dtbo"));
+        assert!(output.contains("overlays/vc4-fkms-v3d.dtbo"));
 
-        // TODO: gpu_memの設定を入れる
+        assert!(RPiConfig::load_from_configs(&[&config, &dir.join("missing.txt")]).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
-        let rpiconfig = RPiConfig {
-            configs: HashMap::from([(
-                "all".to_string(),
-                vec![ConfigEntry::GpuMem(GpuMem {
-                    total_ramsize: Some(1024),
-                    gpu_ramsize: 128,
-                    model: None,
-                })],
-            )]),
+    #[test]
+    fn test_merge_configs_gpu_mem_order() {
+        let dir =
+            std::env::temp_dir().join(format!("pibootcfg-merge-gpu-mem-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("config.txt");
+        let vendor = dir.join("vendor.txt");
+        fs::write(&config, "gpu_mem=64\n").unwrap();
+        fs::write(&vendor, "[pi4]\ngpu_mem=128\n").unwrap();
+
+        // 行番号が無くなっても、firmwareと同じく後のファイルに書かれたgpu_memを適用する
+        let rpiconfig = RPiConfig::load_from_configs(&[&config, &vendor]).unwrap();
+        let split = rpiconfig.memory_split();
+        assert_eq!((896 << 20, 128 << 20), split["4 Model B"]);
+        assert_eq!((448 << 20, 64 << 20), split["Zero"]);
+
+        // 逆の順番で読み込んだ場合は、後に書かれた[all]のgpu_memを適用する
+        let rpiconfig = RPiConfig::load_from_configs(&[&vendor, &config]).unwrap();
+        assert_eq!((960 << 20, 64 << 20), rpiconfig.memory_split()["4 Model B"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_default_gpu_mem() {
+        let options = UbootOptions {
+            default_gpu_mem: Some(64),
+            ..Default::default()
         };
-        let expected = vec![
+        let (_, configs) = parse("dtparam=audio=on\n").unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+        let by_model = rpiconfig
+            .uboot_commands_by_model_with_options(&options)
+            .unwrap();
+        assert_eq!(
+            vec!["fdt set /soc/audio status okay".to_string()],
+            by_model["all"]
+        );
+        assert_eq!(
+            vec![
+                "fdt set / memreserve < 0x3c000000 0x4000000 >".to_string(),
+                "fdt set /memory@0 reg < 0x00 0x00 0x3c000000 0x00 0x40000000 0xbc000000 >"
+                    .to_string(),
+            ],
+            by_model["4 Model B"]
+        );
+        assert_eq!(
+            vec![
+                "fdt set / memreserve < 0x1c000000 0x4000000 >".to_string(),
+                "fdt set /memory@0 reg < 0x00 0x1c000000 >".to_string(),
+            ],
+            by_model["Zero"]
+        );
+        // 搭載メモリ量がわからないモデルには追加しない
+        assert!(!by_model.contains_key("5 Model B"));
+        // 指定しない場合は追加しない
+        assert_eq!(1, rpiconfig.uboot_commands_by_model().unwrap().len());
+
+        // config.txtのgpu_memを優先する
+        let (_, configs) = parse("gpu_mem_1024=128\n").unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+        let by_model = rpiconfig
+            .uboot_commands_by_model_with_options(&options)
+            .unwrap();
+        assert_eq!(
+            "fdt set / memreserve < 0x38000000 0x8000000 >",
+            by_model["4 Model B"][0]
+        );
+        assert_eq!(
+            "fdt set / memreserve < 0x1c000000 0x4000000 >",
+            by_model["Zero"][0]
+        );
+    }
+
+    #[test]
+    fn test_convert_to_dotenv() {
+        let (_, configs) = parse("dtparam=audio=on\n").unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+        let options = UbootOptions {
+            emit_revision_node: false,
+            epilogue: vec!["echo \"a\\b\" $x".to_string()],
+            ..Default::default()
+        };
+
+        // "で囲んだ値では、\\、\"、\$がそれぞれ\、"、$として読まれる
+        // $をエスケープしないと、${fdt_addr}が.envを読み込む側で展開されてしまう
+        let expected = r#"bootconfig="setexpr fdt_ovaddr \${fdt_addr} + 0x40000;fdt addr \${fdt_addr};fdt resize 0x2000;fdt set /soc/audio status okay;echo \"a\\b\" \$x"
+"#;
+        assert_eq!(
+            expected,
+            rpiconfig
+                .convert_to_dotenv_with_options("bootconfig", &options)
+                .unwrap()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_prologue_epilogue() {
+        let (_, configs) = parse("dtparam=audio=on\n").unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+        let options = UbootOptions {
+            emit_revision_node: false,
+            prologue: vec!["setenv pibootcfg 1".to_string()],
+            epilogue: vec![
+                "fdt set /chosen custom < 0x1 >".to_string(),
+                "echo done".to_string(),
+            ],
+            ..Default::default()
+        };
+
+        let output = rpiconfig
+            .convert_to_uboot_config_with_options("bootconfig", &options)
+            .unwrap()
+            .unwrap();
+        let expected = [
             "setexpr fdt_ovaddr ${fdt_addr} + 0x40000",
             "fdt addr ${fdt_addr}",
             "fdt resize 0x2000",
-            "if test \"${board_name}\" = \"3 Model B\"",
-            "then",
-            "fdt set / memreserve < 0x38000000 0x8000000 >",
-            "fdt set /memory@0 reg < 0x00 0x38000000 >",
-            "fi",
-            "if test \"${board_name}\" = \"3 Model B+\"",
-            "then",
-            "fdt set / memreserve < 0x38000000 0x8000000 >",
-            "fdt set /memory@0 reg < 0x00 0x38000000 >",
-            "fi",
-            "if test \"${board_name}\" = \"Compute Module 3\"",
-            "then",
-            "fdt set / memreserve < 0x38000000 0x8000000 >",
-            "fdt set /memory@0 reg < 0x00 0x38000000 >",
-            "fi",
-            "if test \"${board_name}\" = \"Compute Module 3+\"",
-            "then",
-            "fdt set / memreserve < 0x38000000 0x8000000 >",
-            "fdt set /memory@0 reg < 0x00 0x38000000 >",
-            "fi",
-            "if test \"${board_name}\" = \"4 Model B\"",
-            "then",
-            "fdt set / memreserve < 0x38000000 0x8000000 >",
-            "fdt set /memory@0 reg < 0x00 0x00 0x38000000 0x00 0x40000000 0xbc000000 >",
-            "fi",
-            "if test \"${board_name}\" = \"400\"",
-            "then",
-            "fdt set / memreserve < 0x38000000 0x8000000 >",
-            "fdt set /memory@0 reg < 0x00 0x00 0x38000000 0x00 0x40000000 0xbc000000 >",
-            "fi",
-            "if test \"${board_name}\" = \"Compute Module 4\"",
-            "then",
-            "fdt set / memreserve < 0x38000000 0x8000000 >",
-            "fdt set /memory@0 reg < 0x00 0x00 0x38000000 0x00 0x40000000 0xbc000000 >",
-            "fi",
-            "fdt mknode / system",
-            "fdt set /system linux,revision < ${board_revision} >",
+            "setenv pibootcfg 1",
+            "fdt set /soc/audio status okay",
+            "fdt set /chosen custom < 0x1 >",
+            "echo done",
         ];
+        assert_eq!(format!("bootconfig={}", expected.join(";")), output);
+    }
 
-        let expected = format!("bootconfig={}", expected.join(";"));
+    #[test]
+    fn test_prologue_epilogue_separator() {
+        let (_, configs) = parse("dtparam=audio=on\n").unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+
+        // ;を含むと、u-bootで別のコマンドとして実行されてしまう
+        let options = UbootOptions {
+            prologue: vec!["setenv a 1;reset".to_string()],
+            ..Default::default()
+        };
+        let err = rpiconfig
+            .convert_to_uboot_config_with_options("bootconfig", &options)
+            .unwrap_err();
+        assert_eq!(
+            "prologue command contains ';' or a line break: \"setenv a 1;reset\"",
+            err.to_string()
+        );
+
+        // 改行を含むと、env import -tで別の変数として読まれてしまう
+        let options = UbootOptions {
+            epilogue: vec!["echo x\ny=z".to_string()],
+            ..Default::default()
+        };
+        let err = rpiconfig
+            .convert_to_uboot_config_with_options("bootconfig", &options)
+            .unwrap_err();
+        assert_eq!(
+            "epilogue command contains ';' or a line break: \"echo x\\ny=z\"",
+            err.to_string()
+        );
+
+        let options = UbootOptions {
+            epilogue: vec!["echo x\r".to_string()],
+            ..Default::default()
+        };
+        assert!(rpiconfig
+            .convert_to_uboot_config_with_options("bootconfig", &options)
+            .is_err());
+    }
+
+    #[test]
+    fn test_idempotent() {
+        let (_, configs) = parse("dtoverlay=gpio,gpio=17,func=high\n").unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+        let options = UbootOptions {
+            idempotent: true,
+            ..Default::default()
+        };
+
+        let output = rpiconfig
+            .convert_to_uboot_config_with_options("bootconfig", &options)
+            .unwrap()
+            .unwrap();
+        let commands: Vec<&str> = output
+            .strip_prefix("bootconfig=")
+            .unwrap()
+            .split(';')
+            .collect();
+        assert!(commands
+            .contains(&"fdt get size fdt_node_size gpio/gpio17-hog || fdt mknode gpio gpio17-hog"));
+        assert!(commands.contains(&"fdt get size fdt_node_size /system || fdt mknode / system"));
+        assert!(!commands.iter().any(|x| x.starts_with("fdt mknode ")));
 
+        // 無効の場合はそのまま作る
         let output = rpiconfig
             .convert_to_uboot_config("bootconfig")
             .unwrap()
             .unwrap();
-        assert_eq!(expected, output);
+        assert!(!output.contains("||"));
+        assert!(output.contains(";fdt mknode / system;"));
+    }
+
+    #[test]
+    fn test_node_path_style() {
+        let (_, configs) = parse("dtparam=i2c_arm=on,i2c_arm_baudrate=400000\n").unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+
+        let by_model = rpiconfig.uboot_commands_by_model().unwrap();
+        assert_eq!(
+            vec![
+                "fdt set i2c_arm status okay".to_string(),
+                "fdt set i2c clock-frequency < 0x61a80 >".to_string(),
+            ],
+            by_model["all"]
+        );
+
+        let options = UbootOptions {
+            node_path_style: NodePathStyle::FullPath,
+            ..Default::default()
+        };
+        let by_model = rpiconfig
+            .uboot_commands_by_model_with_options(&options)
+            .unwrap();
+        assert_eq!(
+            vec![
+                "fdt set /soc/i2c@7e804000 status okay".to_string(),
+                "fdt set /soc/i2c@7e804000 clock-frequency < 0x61a80 >".to_string(),
+            ],
+            by_model["all"]
+        );
+
+        // エイリアスから始まるパスや、フルパスのままのコマンド
+        let style = NodePathStyle::FullPath;
+        assert_eq!(
+            "fdt set /soc/gpio@7e200000/gpio17-hog gpio-hog",
+            apply_node_path_style("fdt set gpio/gpio17-hog gpio-hog".to_string(), style)
+        );
+        assert_eq!(
+            "fdt mknode /soc/gpio@7e200000 gpio17-hog",
+            apply_node_path_style("fdt mknode gpio gpio17-hog".to_string(), style)
+        );
+        for command in [
+            "fdt set /soc/audio status okay",
+            "fdt set mmc0 brcm,force-pio",
+            "fdt apply ${fdt_ovaddr}",
+        ] {
+            assert_eq!(command, apply_node_path_style(command.to_string(), style));
+        }
+    }
+
+    #[test]
+    fn test_memory_split() {
+        let (_, configs) = parse("gpu_mem_4096=256\ngpu_mem_1024=128\n").unwrap();
+        let rpiconfig = RPiConfig::from(configs);
+
+        let mut options = UbootOptions::default();
+        options.model_ramsize.insert("4 Model B".to_string(), 4096);
+        let split = rpiconfig.memory_split_with_options(&options);
+        assert_eq!(
+            Some(&(3840 * 1024 * 1024, 256 * 1024 * 1024)),
+            split.get("4 Model B")
+        );
+        assert_eq!(
+            Some(&(896 * 1024 * 1024, 128 * 1024 * 1024)),
+            split.get("Compute Module 4")
+        );
+        // 512MBのモデルにはgpu_memが無い
+        assert!(!split.contains_key("Zero"));
+
+        // 既定では4 Model Bは1GBとして扱う
+        let split = rpiconfig.memory_split();
+        assert_eq!(
+            Some(&(896 * 1024 * 1024, 128 * 1024 * 1024)),
+            split.get("4 Model B")
+        );
     }
 }
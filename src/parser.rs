@@ -1,24 +1,23 @@
-///! config.txt parser
-use std::collections::HashMap;
+//! config.txt parser
+use std::{collections::HashMap, fmt};
 
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_until, take_while},
-    character::complete::{digit1, multispace0, newline},
-    combinator::{map_res, opt, recognize},
-    multi::{many1, separated_list0, separated_list1},
-    sequence::{delimited, preceded, separated_pair},
-    AsChar, IResult,
+    bytes::complete::{tag, tag_no_case, take_until, take_while, take_while1},
+    character::complete::{digit1, hex_digit1, multispace0, space1},
+    combinator::{all_consuming, map, map_res, opt, recognize, verify},
+    error::{Error, ErrorKind},
+    multi::{many0, separated_list0, separated_list1},
+    sequence::{delimited, preceded, separated_pair, terminated},
+    IResult,
 };
 
-use crate::{Config, ConfigEntry, DTOverlay, DTparam, GpuMem};
+use crate::{Config, ConfigEntry, DTOverlay, DTparam, Entry, FilterGuard, GpuMem, HdmiCvt};
 
 fn comment(i: &str) -> IResult<&str, ConfigEntry> {
     // TODO: spaceを捨てる
-    let (rest, comment) = preceded(
-        tag("#"),
-        take_while(|c: char| c.is_ascii() && !c.is_ascii_control()),
-    )(i)?;
+    // config.txtはASCIIが前提だが、コメントには日本語などのUTF-8が書かれることがある
+    let (rest, comment) = preceded(tag("#"), take_while(|c: char| !c.is_control()))(i)?;
     let (rest, _) = take_while(|c: char| c.is_ascii_control())(rest)?;
 
     Ok((rest, ConfigEntry::Comment(comment.to_string())))
@@ -43,12 +42,41 @@ fn config(i: &str) -> IResult<&str, Config> {
     ))
 }
 
+/// =を含まないフラグ形式のパラメータ (e.g. dtoverlay=audremap,pins_18_19 の pins_18_19)
+fn flag(i: &str) -> IResult<&str, Config> {
+    let (rest, key) = take_while1(|c: char| c != '=' && !c.is_ascii_control())(i)?;
+
+    Ok((
+        rest,
+        Config {
+            key: key.to_string(),
+            value: String::new(),
+        },
+    ))
+}
+
 fn command(i: &str) -> IResult<&str, ConfigEntry> {
     let (rest, config) = config(i)?;
 
     Ok((rest, ConfigEntry::Command(config)))
 }
 
+/// ,で区切られたパラメータを1つ取り出す
+/// 値の中に=が含まれていてもよく、"で囲まれた部分には,を含めることができる
+/// 閉じられていない"はfirmwareと同様に値の一部として扱う
+/// e.g. label="a,b"
+fn param_segment(i: &str) -> IResult<&str, &str> {
+    recognize(many0(alt((
+        recognize(delimited(
+            tag("\""),
+            take_while(|c: char| c != '"' && !c.is_control()),
+            tag("\""),
+        )),
+        take_while1(|c: char| c.is_ascii() && c != ',' && c != '"' && !c.is_ascii_control()),
+        tag("\""),
+    ))))(i)
+}
+
 /// e.g. dtoverlay=spi0-1cs,cs0_pin=7,cs1_spidev=disabled
 fn dtoverlay(i: &str) -> IResult<&str, ConfigEntry> {
     // 先頭がdtoverlay=なら改行が来るまで読み込む
@@ -56,16 +84,14 @@ fn dtoverlay(i: &str) -> IResult<&str, ConfigEntry> {
     // 最初を除いて=で分割してvecに入れる
     let (rest, mut dtoverlays_str): (&str, Vec<&str>) = delimited(
         tag("dtoverlay="),
-        separated_list0(
-            tag(","),
-            take_while(|c: char| c.is_ascii() && c != ',' && !c.is_ascii_control()),
-        ),
+        separated_list0(tag(","), param_segment),
         multispace0,
     )(i)?;
-    let overlay = dtoverlays_str.remove(0).to_string();
+    // 行末の空白はoverlayの名前に含めない
+    let overlay = dtoverlays_str.remove(0).trim_end().to_string();
     let mut configs: Vec<Config> = Vec::new();
     for c in dtoverlays_str {
-        let config = config(c)?;
+        let config = alt((config, flag))(c)?;
         configs.push(config.1);
     }
 
@@ -76,53 +102,98 @@ fn dtoverlay(i: &str) -> IResult<&str, ConfigEntry> {
 fn dtparam(i: &str) -> IResult<&str, ConfigEntry> {
     let (rest, dtparams_str) = delimited(
         tag("dtparam="),
-        separated_list1(
-            tag(","),
-            take_while(|c: char| c.is_ascii() && c != ',' && !c.is_ascii_control()),
-        ),
+        separated_list1(tag(","), param_segment),
         multispace0,
     )(i)?;
     let mut configs: Vec<Config> = Vec::new();
     for c in dtparams_str {
-        let config = config(c)?;
+        // dtparam=ant2 のように値を省略したものも受け付ける
+        let config = alt((config, flag))(c)?;
         configs.push(config.1);
     }
 
     Ok((rest, ConfigEntry::DTparam(DTparam { configs })))
 }
 
+/// gpu_mem系で受け付けるメモリ量(MB)の上限
+const MAX_MEMSIZE: usize = 64 * 1024;
+
+/// メモリ量(MB)を読み込む
+/// 桁あふれや上限を超える値は他のパーサーに回さずエラーにする
+fn memsize(i: &str) -> IResult<&str, usize> {
+    let (rest, digits) = digit1(i)?;
+    match digits.parse::<usize>() {
+        Ok(size) if size <= MAX_MEMSIZE => Ok((rest, size)),
+        _ => Err(nom::Err::Failure(nom::error::Error::new(
+            i,
+            ErrorKind::TooLarge,
+        ))),
+    }
+}
+
+/// gpu_memの値を読み込む
+/// gpu_mem=256M のように単位(M, MB)が付いていても、MBとして読み込む
+fn gpu_memsize(i: &str) -> IResult<&str, usize> {
+    terminated(memsize, opt(alt((tag_no_case("MB"), tag_no_case("M")))))(i)
+}
+
 fn gpumem(i: &str) -> IResult<&str, ConfigEntry> {
-    let (rest, gpumem_str) = delimited(tag("gpu_mem="), digit1, multispace0)(i)?;
-    let memsize: (&str, usize) = map_res(recognize(digit1), str::parse)(gpumem_str)?;
+    let (rest, memsize) = delimited(tag("gpu_mem="), gpu_memsize, multispace0)(i)?;
     let gpumem = ConfigEntry::GpuMem(GpuMem {
         total_ramsize: None,
-        gpu_ramsize: memsize.1,
+        gpu_ramsize: memsize,
         model: None,
     });
     Ok((rest, gpumem))
 }
 
 fn gpumem_condition(i: &str) -> IResult<&str, ConfigEntry> {
-    let (rest, gpumem_str) = delimited(
+    let (rest, (total_memsize, gpu_memsize)) = delimited(
         tag("gpu_mem_"),
-        separated_list1(
-            tag("="),
-            take_while(|c: char| c.is_dec_digit() && c != '=' && !c.is_ascii_control()),
-        ),
+        separated_pair(memsize, tag("="), gpu_memsize),
         multispace0,
     )(i)?;
 
-    let total_memsize: (&str, usize) = map_res(recognize(digit1), str::parse)(gpumem_str[0])?;
-    let gpu_memsize: (&str, usize) = map_res(recognize(digit1), str::parse)(gpumem_str[1])?;
-
     let gpumem = ConfigEntry::GpuMem(GpuMem {
-        total_ramsize: Some(total_memsize.1),
-        gpu_ramsize: gpu_memsize.1,
+        total_ramsize: Some(total_memsize),
+        gpu_ramsize: gpu_memsize,
         model: None,
     });
     Ok((rest, gpumem))
 }
 
+fn hdmi_timings(i: &str) -> IResult<&str, ConfigEntry> {
+    let (rest, timings) = delimited(
+        tag("hdmi_timings="),
+        separated_list1(space1, map_res(digit1, str::parse)),
+        multispace0,
+    )(i)?;
+    Ok((rest, ConfigEntry::HdmiTimings(timings)))
+}
+
+/// hdmi_cvtは幅、高さ、フレームレートの3つが必須で、残りの4つは後ろから省略できる
+fn hdmi_cvt(i: &str) -> IResult<&str, ConfigEntry> {
+    let (rest, values) = delimited(
+        tag("hdmi_cvt="),
+        verify(
+            separated_list1(space1, map_res(digit1, str::parse::<u32>)),
+            |values: &Vec<u32>| (3..=7).contains(&values.len()),
+        ),
+        multispace0,
+    )(i)?;
+    let optional = |index: usize| values.get(index).copied();
+    let cvt = HdmiCvt {
+        width: values[0],
+        height: values[1],
+        framerate: values[2],
+        aspect: optional(3),
+        margins: optional(4),
+        interlace: optional(5),
+        reduced_blanking: optional(6),
+    };
+    Ok((rest, ConfigEntry::HdmiCvt(cvt)))
+}
+
 fn condition_filter(i: &str) -> IResult<&str, ConfigEntry> {
     let (rest, filter) = delimited(tag("["), take_until("]"), tag("]"))(i)?;
     Ok((rest, ConfigEntry::ConditionFilter(filter.to_string())))
@@ -136,40 +207,234 @@ fn config_entry(i: &str) -> IResult<&str, ConfigEntry> {
         dtparam,
         gpumem,
         gpumem_condition,
+        hdmi_timings,
+        hdmi_cvt,
         command,
     ))(i)?;
     Ok((rest, entry))
 }
 
+/// 空のconfig.txtも正しいので、設定が1つも無くてもよい
+/// parse_reportと同じく1行ずつ読み込み、行の途中までしか読めなかった場合もエラーにする
+/// エラーの入力は、読み込めなかった行の先頭からになる
 fn config_list(i: &str) -> IResult<&str, Vec<ConfigEntry>> {
-    many1(preceded(opt(newline), config_entry))(i)
+    match parse_lines(i) {
+        (entries, None) => Ok(("", entries.into_iter().map(|(_, entry)| entry).collect())),
+        (_, Some((offset, _))) => Err(nom::Err::Error(Error::new(&i[offset..], ErrorKind::Verify))),
+    }
 }
 
-/// parse the text in config.txt
-pub fn parse(i: &str) -> IResult<&str, HashMap<String, Vec<ConfigEntry>>> {
-    let (rest, configs) = config_list(i)?;
+/// 0xなどの接頭辞の無い16進数を読み込む
+fn hex_digits(i: &str) -> IResult<&str, u64> {
+    map_res(hex_digit1, |digits| u64::from_str_radix(digits, 16))(i)
+}
 
-    // filterでまとめる
-    let mut key = "all".to_string();
-    let mut result: HashMap<String, Vec<ConfigEntry>> = HashMap::from([(key.clone(), vec![])]);
+/// 0xから始まる場合は16進数、それ以外は10進数として読み込む
+fn number(i: &str) -> IResult<&str, u64> {
+    alt((
+        preceded(alt((tag("0x"), tag("0X"))), hex_digits),
+        map_res(digit1, str::parse),
+    ))(i)
+}
 
-    for config in configs {
-        match config {
+/// filterのうち、u-bootの環境変数で判定するものを読み込む
+/// e.g. [0x12345678], [board-type=0x11]
+pub(crate) fn filter_guard(filter: &str) -> Option<FilterGuard> {
+    let serial = map(preceded(tag("0x"), hex_digits), FilterGuard::Serial);
+    let board_type = map(
+        preceded(tag("board-type="), map_res(number, u32::try_from)),
+        FilterGuard::BoardType,
+    );
+    let (_, guard) = all_consuming(alt((serial, board_type)))(filter).ok()?;
+    Some(guard)
+}
+
+/// 読み込んだ設定をfilterでまとめる
+pub(crate) struct FilterGroups {
+    key: String,
+    result: HashMap<String, Vec<Entry>>,
+    /// [all]が明示的に書かれていたか
+    explicit_all: bool,
+    /// 次に追加する設定の順番
+    order: usize,
+}
+
+impl FilterGroups {
+    pub(crate) fn new() -> Self {
+        let key = "all".to_string();
+        let result = HashMap::from([(key.clone(), vec![])]);
+        FilterGroups {
+            key,
+            result,
+            explicit_all: false,
+            order: 0,
+        }
+    }
+
+    /// 行番号つきで設定を追加する
+    pub(crate) fn push_at(&mut self, config: ConfigEntry, line: usize) {
+        self.push_entry(Entry::new(config, Some(line), self.order));
+    }
+
+    pub(crate) fn push(&mut self, config: ConfigEntry) {
+        self.push_entry(Entry::new(config, None, self.order));
+    }
+
+    fn push_entry(&mut self, entry: Entry) {
+        self.order += 1;
+        match entry.config {
             ConfigEntry::ConditionFilter(c) => {
-                key = c;
-                if !result.contains_key(&key) {
-                    result.insert(key.clone(), vec![]);
+                self.explicit_all |= c == "all";
+                self.key = c;
+                if !self.result.contains_key(&self.key) {
+                    self.result.insert(self.key.clone(), vec![]);
                 }
             }
             _ => {
-                if let Some(c) = result.get_mut(&key) {
-                    c.push(config)
+                if let Some(c) = self.result.get_mut(&self.key) {
+                    c.push(entry)
                 }
             }
         }
     }
 
-    Ok((rest, result))
+    pub(crate) fn into_inner(self) -> HashMap<String, Vec<ConfigEntry>> {
+        self.result
+            .into_iter()
+            .map(|(filter, entries)| {
+                let configs = entries.into_iter().map(|entry| entry.config).collect();
+                (filter, configs)
+            })
+            .collect()
+    }
+
+    /// 先頭のfilterが無い部分だけでなく、[all]が明示的に書かれていたか
+    pub(crate) fn explicit_all(&self) -> bool {
+        self.explicit_all
+    }
+
+    /// push_atで記録した行番号つきの設定を取り出す
+    pub(crate) fn into_entries(self) -> HashMap<String, Vec<Entry>> {
+        self.result
+    }
+}
+
+/// config.txtの1行を読み込む
+/// 空行の場合はNoneを返す
+pub(crate) fn parse_line_entry(line: &str) -> IResult<&str, Option<ConfigEntry>> {
+    if line.trim().is_empty() {
+        return Ok(("", None));
+    }
+    let (rest, entry) = config_entry(line)?;
+    Ok((rest, Some(entry)))
+}
+
+/// config.txtの読み込みに失敗した行
+#[derive(Debug, PartialEq, Clone)]
+pub struct ParseError {
+    /// 1から始まる行番号
+    pub line: usize,
+    /// 読み込めなかった行
+    pub text: String,
+    pub reason: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}: {:?}", self.line, self.reason, self.text)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// 行番号がline_numberの1行を読み込む
+/// 行の途中までしか読めなかった場合もエラーにする
+pub(crate) fn parse_numbered_line(
+    line_number: usize,
+    line: &str,
+) -> Result<Option<ConfigEntry>, ParseError> {
+    let error = |reason: String| ParseError {
+        line: line_number,
+        text: line.trim_end().to_string(),
+        reason,
+    };
+    match parse_line_entry(line) {
+        Ok((rest, entry)) if rest.trim().is_empty() => Ok(entry),
+        Ok((rest, _)) => Err(error(format!("unexpected {:?}", rest.trim_end()))),
+        Err(nom::Err::Failure(err)) if err.code == ErrorKind::TooLarge => {
+            Err(error("value is too large".to_string()))
+        }
+        Err(_) => Err(error("not a valid config.txt entry".to_string())),
+    }
+}
+
+/// parse the text in config.txt, reporting the line which could not be parsed
+pub fn parse_report(i: &str) -> Result<HashMap<String, Vec<ConfigEntry>>, ParseError> {
+    Ok(parse_groups(i)?.into_inner())
+}
+
+/// 読み込めなかった行の先頭のバイト位置と、そのエラー
+type LineError = (usize, ParseError);
+
+/// config.txtを1行ずつparse_numbered_lineで読み込み、読み込めた設定を (行番号, 設定) の組で返す
+/// 読み込めない行があった場合はそこで止め、その行のLineErrorも返す
+fn parse_lines(i: &str) -> (Vec<(usize, ConfigEntry)>, Option<LineError>) {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    for (index, line) in i.split_inclusive('\n').enumerate() {
+        match parse_numbered_line(index + 1, line) {
+            Ok(entry) => entries.extend(entry.map(|entry| (index + 1, entry))),
+            Err(err) => return (entries, Some((offset, err))),
+        }
+        offset += line.len();
+    }
+    (entries, None)
+}
+
+/// parse_reportと同様に読み込み、各設定の行番号を記録したFilterGroupsを返す
+pub(crate) fn parse_groups(i: &str) -> Result<FilterGroups, ParseError> {
+    let (entries, err) = parse_lines(i);
+    if let Some((_, err)) = err {
+        return Err(err);
+    }
+    let mut groups = FilterGroups::new();
+    for (line, entry) in entries {
+        groups.push_at(entry, line);
+    }
+    Ok(groups)
+}
+
+/// 読み込めるところまでconfig.txtを読み込み、読み込めた設定と残りの入力を返す
+/// filterも1つの設定として、書かれている順に返す
+/// 最後まで読み込めた場合、残りの入力は空になる
+pub fn parse_partial(i: &str) -> (Vec<ConfigEntry>, &str) {
+    let (entries, err) = parse_lines(i);
+    let rest = match err {
+        Some((offset, _)) => &i[offset..],
+        None => "",
+    };
+    (entries.into_iter().map(|(_, entry)| entry).collect(), rest)
+}
+
+/// 最初に読み込めなかった行の先頭のバイト位置と、読み込めなかった理由を返す
+/// エディタで読み込めない行に印を付けるために使う
+/// 最後まで読み込める場合はNoneを返す
+pub fn parse_first_error(i: &str) -> Option<(usize, String)> {
+    let (_, err) = parse_lines(i);
+    err.map(|(offset, err)| (offset, err.reason))
+}
+
+/// parse the text in config.txt
+pub fn parse(i: &str) -> IResult<&str, HashMap<String, Vec<ConfigEntry>>> {
+    let (rest, configs) = config_list(i)?;
+
+    // filterでまとめる
+    let mut groups = FilterGroups::new();
+    for config in configs {
+        groups.push(config);
+    }
+
+    Ok((rest, groups.into_inner()))
 }
 
 #[cfg(test)]
@@ -189,6 +454,32 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_comment_utf8() {
+        assert_eq!(
+            comment("# UARTを有効にする\nenable_uart=1"),
+            Ok((
+                "enable_uart=1",
+                ConfigEntry::Comment(" UARTを有効にする".to_string())
+            ))
+        );
+
+        let text = "# オーディオ\ndtparam=audio=on\n";
+        let expected = HashMap::from([(
+            "all".to_string(),
+            vec![
+                ConfigEntry::Comment(" オーディオ".to_string()),
+                ConfigEntry::DTparam(DTparam {
+                    configs: vec![Config {
+                        key: "audio".to_string(),
+                        value: "on".to_string(),
+                    }],
+                }),
+            ],
+        )]);
+        assert_eq!(parse(text), Ok(("", expected)));
+    }
+
     #[test]
     fn test_command() {
         assert_eq!(
@@ -258,6 +549,111 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_dtoverlay_flag() {
+        assert_eq!(
+            dtoverlay("dtoverlay=audremap,pins_18_19"),
+            Ok((
+                "",
+                ConfigEntry::DTOverlay(DTOverlay {
+                    overlay: "audremap".to_string(),
+                    configs: vec![Config {
+                        key: "pins_18_19".to_string(),
+                        value: "".to_string()
+                    }]
+                })
+            ))
+        );
+        assert_eq!(
+            dtoverlay("dtoverlay=audremap,pins_18_19,enable_jack=on"),
+            Ok((
+                "",
+                ConfigEntry::DTOverlay(DTOverlay {
+                    overlay: "audremap".to_string(),
+                    configs: vec![
+                        Config {
+                            key: "pins_18_19".to_string(),
+                            value: "".to_string()
+                        },
+                        Config {
+                            key: "enable_jack".to_string(),
+                            value: "on".to_string()
+                        }
+                    ]
+                })
+            ))
+        );
+    }
+
+    #[test]
+    fn test_dtoverlay_special_values() {
+        assert_eq!(
+            dtoverlay("dtoverlay=spi0-1cs,cs0_spidev=okay"),
+            Ok((
+                "",
+                ConfigEntry::DTOverlay(DTOverlay {
+                    overlay: "spi0-1cs".to_string(),
+                    configs: vec![Config {
+                        key: "cs0_spidev".to_string(),
+                        value: "okay".to_string()
+                    }]
+                })
+            ))
+        );
+        assert_eq!(
+            dtoverlay("dtoverlay=foo,expr=a=b,label=\"50%, a=b\",speed=100\ndtparam=spi=on"),
+            Ok((
+                "dtparam=spi=on",
+                ConfigEntry::DTOverlay(DTOverlay {
+                    overlay: "foo".to_string(),
+                    configs: vec![
+                        Config {
+                            key: "expr".to_string(),
+                            value: "a=b".to_string()
+                        },
+                        Config {
+                            key: "label".to_string(),
+                            value: "\"50%, a=b\"".to_string()
+                        },
+                        Config {
+                            key: "speed".to_string(),
+                            value: "100".to_string()
+                        }
+                    ]
+                })
+            ))
+        );
+        // 閉じられていない"は値の一部になり、ファイル全体の読み込みは失敗しない
+        assert_eq!(
+            dtoverlay("dtoverlay=foo,label=a\"b,speed=100\ndtparam=spi=on"),
+            Ok((
+                "dtparam=spi=on",
+                ConfigEntry::DTOverlay(DTOverlay {
+                    overlay: "foo".to_string(),
+                    configs: vec![
+                        Config {
+                            key: "label".to_string(),
+                            value: "a\"b".to_string()
+                        },
+                        Config {
+                            key: "speed".to_string(),
+                            value: "100".to_string()
+                        }
+                    ]
+                })
+            ))
+        );
+        let (rest, configs) = parse("dtparam=foo=\"bar\ndtoverlay=dwc2\n").unwrap();
+        assert_eq!("", rest);
+        assert_eq!(
+            vec!["dtparam=foo=\"bar", "dtoverlay=dwc2"],
+            configs["all"]
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+        );
+    }
+
     #[test]
     fn test_dtparam() {
         assert_eq!(
@@ -548,4 +944,315 @@ gpu_mem_1024=512
             ))
         );
     }
+
+    #[test]
+    fn test_gpumem_overflow() {
+        assert!(gpumem("gpu_mem=99999999999999999999999999").is_err());
+        assert!(gpumem("gpu_mem=65537").is_err());
+        assert!(gpumem_condition("gpu_mem_99999999999999999999999999=256").is_err());
+        assert!(gpumem_condition("gpu_mem_1024=99999999999999999999999999").is_err());
+        // commandとして読み替えられずにファイル全体がエラーになる
+        assert!(parse("gpu_mem=99999999999999999999999999\n").is_err());
+    }
+
+    #[test]
+    fn test_gpumem_unit() {
+        let expected = ConfigEntry::GpuMem(GpuMem {
+            total_ramsize: None,
+            gpu_ramsize: 256,
+            model: None,
+        });
+        for text in [
+            "gpu_mem=256",
+            "gpu_mem=256M",
+            "gpu_mem=256m",
+            "gpu_mem=256MB",
+            "gpu_mem=256Mb",
+        ] {
+            assert_eq!(gpumem(text), Ok(("", expected.clone())));
+        }
+        assert_eq!(
+            gpumem_condition("gpu_mem_1024=128M"),
+            Ok((
+                "",
+                ConfigEntry::GpuMem(GpuMem {
+                    total_ramsize: Some(1024),
+                    gpu_ramsize: 128,
+                    model: None
+                }),
+            ))
+        );
+        assert_eq!(
+            parse("gpu_mem=256M\ndtparam=audio=on\n").unwrap().1["all"],
+            vec![
+                expected,
+                ConfigEntry::DTparam(DTparam {
+                    configs: vec![Config {
+                        key: "audio".to_string(),
+                        value: "on".to_string(),
+                    }],
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_partial() {
+        let text = "dtparam=audio=on\n\n[pi4]\ndtoverlay=dwc2\n[broken\ndtparam=spi=on\n";
+        let (entries, rest) = parse_partial(text);
+        assert_eq!(
+            vec![
+                ConfigEntry::DTparam(DTparam {
+                    configs: vec![Config {
+                        key: "audio".to_string(),
+                        value: "on".to_string(),
+                    }],
+                }),
+                ConfigEntry::ConditionFilter("pi4".to_string()),
+                ConfigEntry::DTOverlay(DTOverlay {
+                    overlay: "dwc2".to_string(),
+                    configs: vec![],
+                }),
+            ],
+            entries
+        );
+        assert_eq!("[broken\ndtparam=spi=on\n", rest);
+
+        let (entries, rest) = parse_partial("dtparam=audio=on");
+        assert_eq!(1, entries.len());
+        assert_eq!("", rest);
+    }
+
+    #[test]
+    fn test_parse_crlf() {
+        let text = "dtparam=audio=on,i2c_arm\r\n[pi4]\r\ndtoverlay=dwc2,dr_mode=host\r\ndtoverlay=vc4-fkms-v3d \r\n";
+        let expected = HashMap::from([
+            (
+                "all".to_string(),
+                vec![ConfigEntry::DTparam(DTparam {
+                    configs: vec![
+                        Config {
+                            key: "audio".to_string(),
+                            value: "on".to_string(),
+                        },
+                        Config {
+                            key: "i2c_arm".to_string(),
+                            value: "".to_string(),
+                        },
+                    ],
+                })],
+            ),
+            (
+                "pi4".to_string(),
+                vec![
+                    ConfigEntry::DTOverlay(DTOverlay {
+                        overlay: "dwc2".to_string(),
+                        configs: vec![Config {
+                            key: "dr_mode".to_string(),
+                            value: "host".to_string(),
+                        }],
+                    }),
+                    ConfigEntry::DTOverlay(DTOverlay {
+                        overlay: "vc4-fkms-v3d".to_string(),
+                        configs: vec![],
+                    }),
+                ],
+            ),
+        ]);
+        // 行末の\rが値に含まれないこと
+        assert_eq!(Ok(("", expected.clone())), parse(text));
+        assert_eq!(Ok(expected.clone()), parse_report(text));
+        let (entries, rest) = parse_partial(text);
+        assert_eq!(4, entries.len());
+        assert_eq!("", rest);
+        assert_eq!(None, parse_first_error(text));
+
+        // 最後の行に\nが無くても同じ
+        let text = "dtparam=spi=on\r";
+        assert_eq!(
+            Ok(vec![ConfigEntry::DTparam(DTparam {
+                configs: vec![Config {
+                    key: "spi".to_string(),
+                    value: "on".to_string(),
+                }],
+            })]),
+            parse_report(text).map(|mut configs| configs.remove("all").unwrap())
+        );
+    }
+
+    #[test]
+    fn test_parse_trailing_input() {
+        // 行末に読み込めない部分が残る場合は、どの読み込み方でも同じ行でエラーにする
+        for (text, line) in [
+            ("dtparam=audio=on\ngpu_mem=128 # c\n", "gpu_mem=128 # c\n"),
+            (
+                "[all] # c\ndtparam=audio=on\n",
+                "[all] # c\ndtparam=audio=on\n",
+            ),
+        ] {
+            match parse(text) {
+                Err(nom::Err::Error(err)) => assert_eq!(line, err.input),
+                result => panic!("unexpected result: {:?}", result),
+            }
+            assert!(parse_report(text).is_err());
+            let (offset, _) = parse_first_error(text).unwrap();
+            assert_eq!(text.len() - line.len(), offset);
+            assert_eq!(line, parse_partial(text).1);
+        }
+    }
+
+    #[test]
+    fn test_parse_first_error() {
+        let text = "dtparam=audio=on\n\n[pi4]\ndtoverlay=dwc2\n[broken\ndtparam=spi=on\n";
+        let (offset, message) = parse_first_error(text).unwrap();
+        assert_eq!(text.find("[broken").unwrap(), offset);
+        assert!(text[offset..].starts_with("[broken\n"));
+        assert!(!message.is_empty());
+
+        assert_eq!(None, parse_first_error("dtparam=audio=on\n[pi4]\n"));
+        assert_eq!(None, parse_first_error(""));
+    }
+
+    #[test]
+    fn test_hdmi_timings() {
+        assert_eq!(
+            hdmi_timings("hdmi_timings=1 2 3 \n"),
+            Ok(("", ConfigEntry::HdmiTimings(vec![1, 2, 3])))
+        );
+        assert!(hdmi_timings("hdmi_timings=a b").is_err());
+    }
+
+    #[test]
+    fn test_hdmi_cvt() {
+        assert_eq!(
+            hdmi_cvt("hdmi_cvt=1024 600 60 6 0 0 1\n"),
+            Ok((
+                "",
+                ConfigEntry::HdmiCvt(HdmiCvt {
+                    width: 1024,
+                    height: 600,
+                    framerate: 60,
+                    aspect: Some(6),
+                    margins: Some(0),
+                    interlace: Some(0),
+                    reduced_blanking: Some(1),
+                })
+            ))
+        );
+        // 省略できる値は省略したまま読み込む
+        assert_eq!(
+            hdmi_cvt("hdmi_cvt=640 480 60"),
+            Ok((
+                "",
+                ConfigEntry::HdmiCvt(HdmiCvt {
+                    width: 640,
+                    height: 480,
+                    framerate: 60,
+                    aspect: None,
+                    margins: None,
+                    interlace: None,
+                    reduced_blanking: None,
+                })
+            ))
+        );
+        assert!(hdmi_cvt("hdmi_cvt=640 480").is_err());
+        assert!(hdmi_cvt("hdmi_cvt=640 480 60 6 0 0 0 0").is_err());
+    }
+
+    #[test]
+    fn test_parse_report() {
+        let text = "dtparam=audio=on\n\n[pi4]\n[pi4] # comment\ndtoverlay=dwc2\n";
+        assert_eq!(
+            parse_report(text),
+            Err(ParseError {
+                line: 4,
+                text: "[pi4] # comment".to_string(),
+                reason: "unexpected \" # comment\"".to_string(),
+            })
+        );
+
+        let err = parse_report("# comment\ngpu_mem=99999999999\n").unwrap_err();
+        assert_eq!(2, err.line);
+        assert_eq!(
+            "line 2: value is too large: \"gpu_mem=99999999999\"",
+            err.to_string()
+        );
+
+        let text = "dtparam=audio=on\n[pi4]\ndtoverlay=dwc2\n";
+        assert_eq!(parse_report(text).unwrap(), parse(text).unwrap().1);
+    }
+
+    #[test]
+    fn test_filter_guard() {
+        assert_eq!(
+            filter_guard("0x12345678"),
+            Some(FilterGuard::Serial(0x12345678))
+        );
+        assert_eq!(
+            filter_guard("board-type=0x11"),
+            Some(FilterGuard::BoardType(0x11))
+        );
+        assert_eq!(
+            filter_guard("board-type=17"),
+            Some(FilterGuard::BoardType(17))
+        );
+        assert_eq!(filter_guard("board-type=1f"), None);
+        assert_eq!(filter_guard("pi4"), None);
+        assert_eq!(filter_guard("0x"), None);
+        assert_eq!(filter_guard("0x0x1234abcd"), None);
+        assert_eq!(filter_guard("0x0X1234abcd"), None);
+        assert_eq!(filter_guard("board-type=0x1ffffffff"), None);
+    }
+
+    #[test]
+    fn test_dtparam_flag() {
+        assert_eq!(
+            dtparam("dtparam=ant2,audio=on"),
+            Ok((
+                "",
+                ConfigEntry::DTparam(DTparam {
+                    configs: vec![
+                        Config {
+                            key: "ant2".to_string(),
+                            value: "".to_string(),
+                        },
+                        Config {
+                            key: "audio".to_string(),
+                            value: "on".to_string(),
+                        },
+                    ],
+                })
+            ))
+        );
+    }
+
+    #[test]
+    fn test_dtparam_bare_flag() {
+        assert_eq!(
+            parse_line_entry("dtparam=i2c_arm\n"),
+            Ok((
+                "",
+                Some(ConfigEntry::DTparam(DTparam {
+                    configs: vec![Config {
+                        key: "i2c_arm".to_string(),
+                        value: "".to_string(),
+                    }],
+                }))
+            ))
+        );
+    }
+
+    #[test]
+    fn test_dtoverlay_trailing_space() {
+        assert_eq!(
+            dtoverlay("dtoverlay=vc4-kms-v3d-pi4  \n"),
+            Ok((
+                "",
+                ConfigEntry::DTOverlay(DTOverlay {
+                    overlay: "vc4-kms-v3d-pi4".to_string(),
+                    configs: vec![],
+                })
+            ))
+        );
+    }
 }
@@ -1,39 +1,91 @@
 use anyhow::{Context, Result};
-use pibootcfg::RPiConfig;
-use std::{env, fs, path::PathBuf};
+use pibootcfg::{write_atomically, RPiConfig, UbootOptions};
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
 
 fn usage() {
     println!("usage:");
-    println!("\tpibconfig2uboot SRC DEST");
+    println!("\tpibconfig2uboot [--crlf] SRC [SRC...] DEST");
+    println!("\tpibconfig2uboot --summary SRC [SRC...]");
+    println!("options:");
+    println!("\t--crlf\tend the output with CRLF (import it with `env import -t -r` in u-boot)");
+    println!("\t--summary\tprint the number of settings instead of converting");
     println!("example:");
     println!("\tpibconfig2uboot /boot/config.txt /boot/uEnv.txt");
+    println!("\tpibconfig2uboot /boot/config.txt /boot/vendor.txt /boot/uEnv.txt");
 }
 
-fn main() -> Result<()> {
+fn main() {
     // config.txtを読み込んでuEnvにするコマンド
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        eprintln!("error: not enough arguments");
-        usage();
+    let mut args: Vec<String> = env::args().collect();
+    let crlf = args.iter().skip(1).any(|arg| arg == "--crlf");
+    let summary = args.iter().skip(1).any(|arg| arg == "--summary");
+    args.retain(|arg| arg != "--crlf" && arg != "--summary");
+    match args.get(1).map(String::as_str) {
+        Some("?" | "h" | "help") => {
+            usage();
+            return;
+        }
+        None if summary => {
+            eprintln!("error: missing SRC");
+            usage();
+            std::process::exit(1);
+        }
+        Some(_) if summary => {
+            if let Err(err) = print_summary(&args[1..]) {
+                eprintln!("error: {:#}", err);
+                std::process::exit(1);
+            }
+            return;
+        }
+        None => {
+            eprintln!("error: missing SRC and DEST");
+            usage();
+            std::process::exit(1);
+        }
+        Some(_) if args.len() < 3 => {
+            eprintln!("error: missing DEST");
+            usage();
+            std::process::exit(1);
+        }
+        Some(_) => (),
+    }
+
+    if let Err(err) = run(&args[1..args.len() - 1], &args[args.len() - 1], crlf) {
+        // 原因まで含めて1行で表示する
+        eprintln!("error: {:#}", err);
         std::process::exit(1);
     }
+}
 
-    let src = args.get(1).unwrap();
-    match &**src {
-        "?" | "h" | "help" => usage(),
-        _ => (),
-    };
-    let dest = args.get(2).unwrap();
-    let src = PathBuf::from(src);
+/// srcsを順に読み込んでまとめ、設定の数を表示する
+fn print_summary(srcs: &[String]) -> Result<()> {
+    let srcs: Vec<&Path> = srcs.iter().map(Path::new).collect();
+    let piconfig = RPiConfig::load_from_configs(&srcs)?;
+    println!("{}", piconfig.summary());
+    Ok(())
+}
+
+/// srcsを順に読み込んでまとめ、destにuEnvとして書き込む
+/// crlfの場合は、Windowsで編集されるboot partition向けに行末をCRLFにする
+/// u-bootでは`env import -t -r`で\rを取り除いて読み込む
+fn run(srcs: &[String], dest: &str, crlf: bool) -> Result<()> {
+    let srcs: Vec<&Path> = srcs.iter().map(Path::new).collect();
     let dest = PathBuf::from(dest);
 
-    let piconfig = RPiConfig::load_from_config(&src)?;
+    let piconfig = RPiConfig::load_from_configs(&srcs)?;
 
+    let options = UbootOptions {
+        crlf,
+        ..Default::default()
+    };
     let uenv = piconfig
-        .convert_to_uboot_config("bootcfg")?
-        .unwrap_or(format!("bootcfg=\"echo nothing to do\""));
+        .convert_to_uboot_config_with_options("bootcfg", &options)?
+        .unwrap_or_else(|| format!("bootcfg=\"echo nothing to do\"{}", options.line_ending()));
 
-    fs::write(&dest, uenv.as_bytes())
+    write_atomically(&dest, uenv.as_bytes())
         .with_context(|| format!("failed to write u-boot config to {}", dest.display()))?;
     Ok(())
 }
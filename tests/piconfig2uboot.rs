@@ -0,0 +1,118 @@
+use std::{
+    env, fs,
+    path::PathBuf,
+    process::{Command, Output},
+};
+
+fn piconfig2uboot(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_piconfig2uboot"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("pibootcfg-cli-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_missing_args() {
+    let output = piconfig2uboot(&[]);
+    assert_eq!(Some(1), output.status.code());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("error: missing SRC and DEST"));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("usage:"));
+
+    // DESTが無い場合はpanicせずにusageを表示する
+    let output = piconfig2uboot(&["examples/config.txt"]);
+    assert_eq!(Some(1), output.status.code());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("error: missing DEST"));
+    assert!(!stderr.contains("panicked"));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("usage:"));
+
+    let output = piconfig2uboot(&["help"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("usage:"));
+}
+
+#[test]
+fn test_missing_src_file() {
+    let dir = temp_dir("missing");
+    let src = dir.join("config.txt");
+    let dest = dir.join("uEnv.txt");
+
+    let output = piconfig2uboot(&[src.to_str().unwrap(), dest.to_str().unwrap()]);
+    assert_eq!(Some(1), output.status.code());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.starts_with(&format!(
+        "error: Failed to read config.txt from {}: ",
+        src.display()
+    )));
+    assert!(!stderr.contains("panicked"));
+    assert!(!dest.exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_convert() {
+    let dir = temp_dir("convert");
+    let src = dir.join("config.txt");
+    let dest = dir.join("uEnv.txt");
+    fs::write(&src, "dtparam=audio=on\n").unwrap();
+
+    let output = piconfig2uboot(&[src.to_str().unwrap(), dest.to_str().unwrap()]);
+    assert!(output.status.success());
+    let uenv = fs::read_to_string(&dest).unwrap();
+    assert!(uenv.starts_with("bootcfg="));
+    assert!(uenv.contains("fdt set /soc/audio status okay"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_convert_crlf() {
+    let dir = temp_dir("crlf");
+    let src = dir.join("config.txt");
+    let dest = dir.join("uEnv.txt");
+    fs::write(&src, "dtparam=audio=on\n").unwrap();
+
+    let output = piconfig2uboot(&["--crlf", src.to_str().unwrap(), dest.to_str().unwrap()]);
+    assert!(output.status.success());
+    let uenv = fs::read_to_string(&dest).unwrap();
+    assert!(uenv.starts_with("bootcfg="));
+    assert!(uenv.ends_with("\r\n"));
+
+    // 指定しない場合は改行を付けない
+    let output = piconfig2uboot(&[src.to_str().unwrap(), dest.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert!(!fs::read_to_string(&dest).unwrap().ends_with('\n'));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_summary() {
+    let dir = temp_dir("summary");
+    let src = dir.join("config.txt");
+    fs::write(
+        &src,
+        "dtparam=audio=on\ngpu_mem=64\ndtoverlay=dwc2\n[pi4]\ndtoverlay=disable-bt\n",
+    )
+    .unwrap();
+
+    let output = piconfig2uboot(&["--summary", src.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert_eq!(
+        "2 overlays, 1 dtparam, 1 gpu_mem setting across 2 sections.\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+
+    let output = piconfig2uboot(&["--summary"]);
+    assert_eq!(Some(1), output.status.code());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("error: missing SRC"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
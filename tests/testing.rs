@@ -0,0 +1,33 @@
+//! testing featureで公開している内部の変換処理のテスト
+//! Cargo.tomlのrequired-featuresで、testing featureが有効な場合のみビルドする
+
+use pibootcfg::{
+    parse,
+    testing::{arrange_for_uboot, generate_uboot_config},
+    ConfigEntry, UbootOptions,
+};
+
+#[test]
+fn test_arrange_for_uboot_gpu_mem() {
+    let (_, configs) = parse("gpu_mem_512=64\ndtparam=audio=on\n").unwrap();
+    let options = UbootOptions::default();
+    let arranged = arrange_for_uboot(&configs, &options.model_ramsize);
+
+    // gpu_mem_512はallから搭載メモリが512MBのモデルに振り分けられる
+    assert!(!arranged["all"]
+        .iter()
+        .any(|entry| matches!(entry, ConfigEntry::GpuMem(_))));
+    for model in ["Zero", "Zero W", "Zero 2 W", "3 Model A+"] {
+        assert_eq!(1, arranged[model].len());
+        let entry = &arranged[model][0];
+        assert_eq!("gpu_mem_512=64", entry.to_string());
+        assert_eq!(
+            vec![
+                "fdt set / memreserve < 0x1c000000 0x4000000 >".to_string(),
+                "fdt set /memory@0 reg < 0x00 0x1c000000 >".to_string(),
+            ],
+            generate_uboot_config(entry, &options).unwrap()
+        );
+    }
+    assert!(!arranged.contains_key("4 Model B"));
+}